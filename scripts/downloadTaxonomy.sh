@@ -35,8 +35,12 @@ while [ ! -f  ../Taxonomy/rankedlineage.dmp ]; do
 	tar -zxvf ../Taxonomy/new_taxdump.tar.gz rankedlineage.dmp
 	tar -zxvf ../Taxonomy/new_taxdump.tar.gz nodes.dmp
 	tar -zxvf ../Taxonomy/new_taxdump.tar.gz names.dmp
+	tar -zxvf ../Taxonomy/new_taxdump.tar.gz merged.dmp
+	tar -zxvf ../Taxonomy/new_taxdump.tar.gz delnodes.dmp
 	mv rankedlineage.dmp ../Taxonomy/
 	mv nodes.dmp ../Taxonomy/
 	mv names.dmp ../Taxonomy/
+	mv merged.dmp ../Taxonomy/
+	mv delnodes.dmp ../Taxonomy/
 	rm ../Taxonomy/new_taxdump.tar.gz
 done
@@ -1,16 +1,25 @@
 use std::collections::{HashMap, HashSet, VecDeque};
-use std::fs::File;
-use std::io::Write;
+use std::io::{BufRead, BufReader};
 use rand_distr::WeightedAliasIndex;
 
 use rayon::slice::ParallelSliceMut;
 
 use crate::cedar::Cedar;
+use crate::config::MoraConfig;
+use crate::error::{create_file, open_file, write_to, MoraError, Result};
 use crate::cedar::readers::{Query, Mapping};
+use crate::summary::{AssignmentSummary, TaxonomySummary};
 use rand::prelude::*;
 
-mod get_taxonomy;
-use get_taxonomy::tax_main;
+pub mod get_taxonomy;
+use get_taxonomy::{tax_main, write_taxonomy_outputs, LoadedTaxonomy, TaxonomyOptions, TaxonomyReports, TaxonomySource};
+
+// maximum number of incumbents examined on each reference of an ejection chain. This is a bound on the search and not
+// a model setting: a left over read examines at most CHAIN_BREADTH^max_chain_depth incumbents, the cheapest ones first
+const CHAIN_BREADTH: usize = 64;
+
+// seed of the random assignment of the left over reads, so that runs and re-estimation rounds can be reproduced
+const RANDOM_ASSIGNMENT_SEED: u64 = 42;
 
 /*
     Candidate move for an incumbent to make room on a reference
@@ -24,67 +33,202 @@ struct CandidateMove {
 }
 
 /*
-    abundance: <ref_id, estimated abundance from cedar>
-    current_abundance: <ref_id, current abundance>
+    Final outcome of the assignment stages for a query
+
+    Assigned: the query was assigned to the reference with the given ref_id
+    Unmapped: the query had no alignment in the SAM file
+    Unassigned: the query was left over and could not be placed on any reference
+    ReferenceFiltered: every reference the query mapped to was invalidated by the EM
+    Discordant: both mates of the fragment are mapped, but never to the same reference
+ */
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum AssignmentStatus {
+    Assigned(usize),
+    Unmapped,
+    Unassigned,
+    ReferenceFiltered,
+    Discordant,
+}
+
+impl AssignmentStatus {
+    // label written in the output in place of a reference name
+    pub fn label(&self) -> &'static str {
+        match self {
+            AssignmentStatus::Assigned(_) => "ASSIGNED",
+            AssignmentStatus::Unmapped => "UNMAPPED",
+            AssignmentStatus::Unassigned => "UNASSIGNED",
+            AssignmentStatus::ReferenceFiltered => "REFERENCE_FILTERED",
+            AssignmentStatus::Discordant => "DISCORDANT",
+        }
+    }
+
+    // the status of a query without a reference from its label in the output, none for reference names
+    pub fn from_label(name: &str) -> Option<AssignmentStatus> {
+        [AssignmentStatus::Unmapped, AssignmentStatus::Unassigned, AssignmentStatus::ReferenceFiltered, AssignmentStatus::Discordant]
+            .into_iter().find(|status| status.label() == name)
+    }
+
+    // if a name in the output is the label of a query without a reference
+    pub(crate) fn is_unassigned_label(name: &str) -> bool {
+        AssignmentStatus::from_label(name).is_some()
+    }
+}
+
+/*
+    Final assignment of a query
+
+    reference: name of the reference the query was assigned to, none if it was not assigned
+*/
+#[derive(Clone, Debug)]
+pub struct AssignmentRecord {
+    pub query: String,
+    pub reference: Option<String>,
+    pub status: AssignmentStatus,
+}
+
+impl AssignmentRecord {
+    // the reference name, or the label of the status for queries without a reference
+    pub fn get_name(&self) -> &str {
+        match &self.reference {
+            Some(reference) => reference,
+            None => self.status.label(),
+        }
+    }
+}
+
+// <query name, reference name or assignment status> as written by write_output
+pub fn records_to_output(records: &[AssignmentRecord]) -> HashMap<String, String> {
+    records.iter().map(|record| (record.query.to_string(), record.get_name().to_string())).collect()
+}
+
+/*
+    How many reads a reference may hold on top of the number of reads expected from its abundance
+
+    Absolute: a fixed number of reads
+    Relative: a fraction of the expected number of reads
+    Binomial: a multiple of the standard deviation sqrt(n * p * (1 - p)) of the read count of the reference when the
+        n reads are drawn with its abundance p. This is the sampling noise of the read count, not an uncertainty
+        (e.g. a bootstrap interval) of the EM estimate itself
+ */
+#[derive(Clone, Copy, Debug)]
+pub enum SlackModel {
+    Absolute(f32),
+    Relative(f32),
+    Binomial(f32),
+}
+
+impl SlackModel {
+    // the error names the model if it is not absolute, relative or binomial
+    pub fn new(model: &str, value: f32) -> Result<SlackModel> {
+        match model {
+            "absolute" => Ok(SlackModel::Absolute(value)),
+            "relative" => Ok(SlackModel::Relative(value)),
+            "binomial" => Ok(SlackModel::Binomial(value)),
+            _ => Err(MoraError::invalid_value("the slack model", model, "expected absolute, relative or binomial"))
+        }
+    }
+
+    // name of the model as given to new
+    pub fn get_model(&self) -> &'static str {
+        match self {
+            SlackModel::Absolute(_) => "absolute",
+            SlackModel::Relative(_) => "relative",
+            SlackModel::Binomial(_) => "binomial",
+        }
+    }
+
+    pub fn get_value(&self) -> f32 {
+        match self {
+            SlackModel::Absolute(v) | SlackModel::Relative(v) | SlackModel::Binomial(v) => *v,
+        }
+    }
+
+    // maximum number of reads that can be assigned to a reference with the given abundance
+    fn capacity(&self, abundance: f32, query_size: usize) -> usize {
+        let expected = abundance as f64 * query_size as f64;
+        let slack = match self {
+            SlackModel::Absolute(v) => *v as f64,
+            SlackModel::Relative(v) => *v as f64 * expected,
+            SlackModel::Binomial(v) => *v as f64 * (expected * (1.0 - abundance as f64)).max(0.0).sqrt(),
+        };
+        (expected + slack).ceil() as usize
+    }
+}
+
+/*
+    strain_valid: <ref_id, if the reference is still valid after the EM>
+    capacities: <ref_id, number of reads the reference can hold based on its abundance and the slack model>
+    assigned_counts: <ref_id, number of reads currently assigned>
     assignments: <ref_id, <score, query_name>>
-    output_assignments: <query_name, ref_id>
+    output_assignments: <query_name, assignment status>
+    random_assignments: the queries that were assigned randomly based on their mapping scores
     query_size: the total number of queries (not counting unmapped queries)
-    max_diff: maximum allowed difference between abundance and current abundance
+    summary: the number of queries placed by each assignment stage
 */
 struct AssignmentMachine {
-    abundance: HashMap<usize, f32>,
-    current_abundance: HashMap<usize, f32>,
+    strain_valid: HashMap<usize, bool>,
+    capacities: HashMap<usize, usize>,
+    assigned_counts: HashMap<usize, usize>,
     assignments: HashMap<usize, HashMap<usize, HashSet<usize>>>,
-    output_assignments: HashMap<usize, usize>,
+    output_assignments: HashMap<usize, AssignmentStatus>,
+    random_assignments: HashSet<usize>,
     query_size: usize,
-    max_diff: f32,
+    summary: AssignmentSummary,
 }
 
 impl AssignmentMachine {
-    fn new(abundance: HashMap<usize, f32>, query_size: usize) -> AssignmentMachine {
-        let mut current_abundance = HashMap::new();
-        for (key, _) in &abundance {
-            current_abundance.insert(*key, 0.0);
+    fn new(abundance: HashMap<usize, f32>, strain_valid: HashMap<usize, bool>, query_size: usize, slack: SlackModel) -> AssignmentMachine {
+        let mut capacities = HashMap::new();
+        let mut assigned_counts = HashMap::new();
+        for (key, value) in &abundance {
+            capacities.insert(*key, slack.capacity(*value, query_size));
+            assigned_counts.insert(*key, 0);
         }
-        AssignmentMachine { abundance, current_abundance, assignments: HashMap::new(), output_assignments: HashMap::new(), query_size, max_diff: 1.0/(query_size as f32)}
+        AssignmentMachine { strain_valid, capacities, assigned_counts, assignments: HashMap::new(), output_assignments: HashMap::new(), random_assignments: HashSet::new(), query_size, summary: AssignmentSummary::default() }
     }
 
     // assign a query to a reference with a score
     fn add_assignment(&mut self, query_id: usize, ref_id: usize, score: usize) {
-        if ref_id != usize::MAX - 1 {
-            let entry = self.current_abundance.entry(ref_id).or_insert(0.0);
-            *entry += 1.0 / self.query_size as f32;                             // update the new current abundance
-        }
-        self.output_assignments.insert(query_id, ref_id);
+        let entry = self.assigned_counts.entry(ref_id).or_insert(0);
+        *entry += 1;
+        self.output_assignments.insert(query_id, AssignmentStatus::Assigned(ref_id));
 
-        let entry = self.assignments.entry(ref_id).or_insert(HashMap::new());
-        let entry2 = entry.entry(score).or_insert(HashSet::new());
+        let entry = self.assignments.entry(ref_id).or_default();
+        let entry2 = entry.entry(score).or_default();
         entry2.insert(query_id);
     }
 
-    // check if a reference still has space to be assigned a query
+    // record a query that is not assigned to any reference
+    fn set_status(&mut self, query_id: usize, status: AssignmentStatus) {
+        self.output_assignments.insert(query_id, status);
+    }
+
+    // check if a reference still has space to be assigned a query, a reference without an abundance has no space
     fn has_space(&self, ref_id: &usize) -> bool {
-        if *ref_id == usize::MAX - 1 {
-            true
-        } else {
-            self.current_abundance[ref_id] < self.abundance[ref_id] + self.max_diff
-        }
+        self.assigned_counts.get(ref_id).copied().unwrap_or(0) < self.capacities.get(ref_id).copied().unwrap_or(0)
+    }
+
+    // check if a reference has been invalidated by the EM
+    fn is_filtered(&self, ref_id: &usize) -> bool {
+        !self.strain_valid.get(ref_id).copied().unwrap_or(true)
     }
 
     // remove an assignment for a previously assigned query
     fn remove_assignment(&mut self, ref_id: usize, query_id: usize, score: usize) {
-        let entry = self.current_abundance.entry(ref_id).or_insert(0.0);
-        *entry -= 1.0 / self.query_size as f32;
+        let entry = self.assigned_counts.entry(ref_id).or_insert(0);
+        *entry -= 1;
         
-        let entry = self.assignments.entry(ref_id).or_insert(HashMap::new());
-        let entry2 = entry.entry(score).or_insert(HashSet::new());
+        let entry = self.assignments.entry(ref_id).or_default();
+        let entry2 = entry.entry(score).or_default();
         entry2.remove(&query_id);
     }
 
-    // initial assignment of unique mapping queries or unmapped queries
+    // initial assignment of unique mapping queries, unmapped queries, discordant fragments and queries whose references were all filtered out
     fn initial_assignment(&mut self, mut queries: HashMap<usize, Query>) -> HashMap<usize, Query> {
         let mut unique_mapping_queries = 0;
         let mut unmapped_queries = 0;
+        let mut discordant_queries = 0;
+        let mut filtered_queries = 0;
         let mut to_remove = Vec::new();
         
         println!("total query_size = {}", self.query_size);
@@ -92,7 +236,18 @@ impl AssignmentMachine {
             if query.mappings.is_empty() {
                 unmapped_queries += 1;
 
-                self.add_assignment(*query_id, usize::MAX, 0);
+                self.set_status(*query_id, AssignmentStatus::Unmapped);
+                to_remove.push(*query_id)
+            } else if query.is_discordant() {
+                discordant_queries += 1;
+
+                self.set_status(*query_id, AssignmentStatus::Discordant);
+                to_remove.push(*query_id)
+            } else if query.mappings.iter().all(|mapping| self.is_filtered(&mapping.get_reference_id())) {
+                // checked before the unique mappings so that a read is not assigned to a reference the EM invalidated
+                filtered_queries += 1;
+
+                self.set_status(*query_id, AssignmentStatus::ReferenceFiltered);
                 to_remove.push(*query_id)
             } else if check_if_read_is_uniquely_mapping(&query.mappings) {
                 unique_mapping_queries += 1;
@@ -106,8 +261,10 @@ impl AssignmentMachine {
         for id in to_remove {
             queries.remove(&id);
         }
+        self.summary.unique = unique_mapping_queries;
 
-        println!("unique mapping queries: {}, unmapped queries: {}, current assigned length: {}", unique_mapping_queries, unmapped_queries, self.output_assignments.len());
+        println!("unique mapping queries: {}, unmapped queries: {}, discordant queries: {}, reference filtered queries: {}, current assigned length: {}", 
+            unique_mapping_queries, unmapped_queries, discordant_queries, filtered_queries, self.output_assignments.len());
 
         queries
     }
@@ -117,11 +274,11 @@ impl AssignmentMachine {
         let mut to_remove = Vec::new();
 
         for (query_id, query) in &queries {
-            let (ref_id, score) = assign_best(query, &self, score_max_diff);
-            if ref_id != usize::MAX - 1 {
+            if let Some((ref_id, score)) = assign_best(query, self, score_max_diff) {
                 to_remove.push(*query_id);
                 if self.has_space(&ref_id) {
                     self.add_assignment(*query_id, ref_id, score as usize);
+                    self.summary.secondary += 1;
                 }
             }
         }
@@ -198,8 +355,14 @@ impl AssignmentMachine {
         candidates
     }
 
-    fn place_leftovers(&mut self, leftovers: &HashMap<usize, Query>,
-        candidates: &mut HashMap<usize, VecDeque<CandidateMove>>)
+    /*
+        Place the leftovers by moving incumbents out of the way. A leftover is first placed with a single move
+        from the precomputed candidates. If there is none and max_chain_depth > 1, a chain of moves is searched
+        where each incumbent moves into the space freed by the next one.
+        chain_depths: number of leftovers rescued by a chain of each length (index = length of chain)
+     */
+    fn place_leftovers(&mut self, original_queries: &HashMap<usize, Query>, leftovers: &HashMap<usize, Query>,
+        candidates: &mut HashMap<usize, VecDeque<CandidateMove>>, max_chain_depth: usize, chain_depths: &mut [usize])
         -> HashMap<usize, Query>
     {
         let mut unplaced = HashMap::new();
@@ -220,16 +383,26 @@ impl AssignmentMachine {
 
                 let chosen = match candidates.get_mut(&target) {
                     Some(queue) => self.take_valid_move(queue, target, threshold),
+                    None => None
+                };
+
+                let chain = match chosen {
+                    Some(c) => vec![(target, c)],
+                    None if max_chain_depth > 1 => {
+                        let mut visited = HashSet::from([target]);
+                        match self.find_ejection_chain(original_queries, target, threshold, max_chain_depth, &mut visited) {
+                            Some(chain) => chain,
+                            None => continue
+                        }
+                    },
                     None => continue
                 };
 
-                if let Some(c) = chosen {
-                    self.remove_assignment(target, c.incumbent_id, c.incumbent_score);
-                    self.add_assignment(c.incumbent_id, c.alt_ref_id, c.alt_score);
-                    self.add_assignment(query_id, target, mapping.get_score() as usize);
-                    placed = true;
-                    break;
-                }
+                chain_depths[chain.len()] += 1;
+                self.apply_ejection_chain(chain);
+                self.add_assignment(query_id, target, mapping.get_score() as usize);
+                placed = true;
+                break;
             }
 
             if !placed {
@@ -240,6 +413,77 @@ impl AssignmentMachine {
         unplaced
     }
 
+    /*
+        Depth first search for a chain of at most max_depth moves that frees a spot on target.
+        The summed cost of the moves has to stay below budget, the score loss the leftover is allowed to cause.
+        visited holds the references on the current chain, so that no incumbent moves back onto one of them. A reference
+        is removed again when the search backtracks from it, so that a dead end does not block it for the other branches.
+        Output: the moves as (reference the incumbent leaves, move), ordered so that the first move goes to a reference with space
+     */
+    fn find_ejection_chain(&self, original_queries: &HashMap<usize, Query>, target: usize, budget: f32,
+        max_depth: usize, visited: &mut HashSet<usize>) -> Option<Vec<(usize, CandidateMove)>>
+    {
+        let bins = self.assignments.get(&target)?;
+        // incumbents with a low score are the cheapest to move
+        let mut scores: Vec<usize> = bins.keys().cloned().collect();
+        scores.sort_unstable();
+
+        let mut examined = 0;
+        for score in scores {
+            for inc_id in &bins[&score] {
+                if examined == CHAIN_BREADTH {
+                    return None;
+                }
+                examined += 1;
+
+                let inc = &original_queries[inc_id];
+                let total_score = inc.get_total_score();
+
+                for mapping in &inc.mappings {
+                    let alt_ref_id = mapping.get_reference_id();
+                    if visited.contains(&alt_ref_id) {
+                        continue;
+                    }
+
+                    let cost = 2.0 * (score as f32) / total_score - mapping.get_score() / total_score;
+                    if cost >= budget {
+                        continue;
+                    }
+
+                    let candidate = CandidateMove {
+                        cost,
+                        incumbent_id: *inc_id,
+                        incumbent_score: score,
+                        alt_ref_id,
+                        alt_score: mapping.get_score() as usize
+                    };
+
+                    if self.has_space(&alt_ref_id) {
+                        return Some(vec![(target, candidate)]);
+                    }
+                    if max_depth > 1 {
+                        visited.insert(alt_ref_id);
+                        if let Some(mut chain) = self.find_ejection_chain(original_queries, alt_ref_id, budget - cost, max_depth - 1, visited) {
+                            chain.push((target, candidate));
+                            return Some(chain);
+                        }
+                        visited.remove(&alt_ref_id);
+                    }
+                }
+            }
+        }
+
+        None
+    }
+
+    // move the incumbents of a chain, starting with the one moving to a reference with space
+    fn apply_ejection_chain(&mut self, chain: Vec<(usize, CandidateMove)>) {
+        for (from_ref_id, c) in chain {
+            self.remove_assignment(from_ref_id, c.incumbent_id, c.incumbent_score);
+            self.add_assignment(c.incumbent_id, c.alt_ref_id, c.alt_score);
+        }
+    }
+
     fn take_valid_move(&self, queue: &mut VecDeque<CandidateMove>, target: usize, threshold: f32)
         -> Option<CandidateMove>
     {
@@ -265,8 +509,9 @@ impl AssignmentMachine {
         None
     }
 
-    //assignment based on abundances
-    fn assign_based_on_abundance(&mut self, mut queries: HashMap<usize, Query>, original_queries: &HashMap<usize, Query>, method: String) {
+    //assignment based on abundances, fails with the ID of a left over query that cannot be assigned randomly
+    fn assign_based_on_abundance(&mut self, mut queries: HashMap<usize, Query>, original_queries: &HashMap<usize, Query>, method: String, max_chain_depth: usize)
+                                    -> std::result::Result<(), usize> {
         //create score bins
         let mut score_bins = HashMap::new();
         for (query_id, query) in &queries {
@@ -287,45 +532,93 @@ impl AssignmentMachine {
                 if queries.contains_key(query_id) && self.has_space(ref_id){
                     self.add_assignment(*query_id, *ref_id, key);
                     queries.remove(query_id);
+                    self.summary.abundance += 1;
                 }
             }
         }
         println!("queries have been mapped to the best possible reference. left overs: {}", queries.len());
 
         // assignment of the left overs by trying to open up space
+        let mut chain_depths = vec![0; max_chain_depth.max(1) + 1];
         let leftover_queries = if queries.is_empty() {
             HashMap::new()
         } else {
             let mut candidates = self.compute_candidate_moves(original_queries, &queries);
-            self.place_leftovers(&queries, &mut candidates)
+            self.place_leftovers(original_queries, &queries, &mut candidates, max_chain_depth, &mut chain_depths)
         };
         
+        for (depth, rescued) in chain_depths.iter().enumerate().skip(1) {
+            println!("left overs rescued by ejection chains of depth {}: {}", depth, rescued);
+        }
         println!("cannot move: {}", leftover_queries.len());
+        self.summary.moved = chain_depths.iter().sum();
+        self.summary.leftover = leftover_queries.len();
 
         //assign the queries that cannot be mapped based on what method was specified. Default mode is none
         if method == "none" {
             self.leave_left_overs(leftover_queries);
+            Ok(())
         } else {
-            self.assign_based_on_prob(leftover_queries);
+            self.assign_based_on_prob(leftover_queries)
         }
     }
 
-    //assignment based on probability with weights being the mapping scores
-    fn assign_based_on_prob(&mut self, queries: HashMap<usize, Query>) {
+    //assignment based on probability with weights being the mapping scores, fails with the ID of a query whose scores cannot be used as weights
+    fn assign_based_on_prob(&mut self, queries: HashMap<usize, Query>) -> std::result::Result<(), usize> {
+        // seeded and in order of the query IDs, so that the same left overs get the same references
+        let mut rng = StdRng::seed_from_u64(RANDOM_ASSIGNMENT_SEED);
+        let mut queries: Vec<(usize, Query)> = queries.into_iter().collect();
+        queries.sort_unstable_by_key(|(name, _)| *name);
         for (name, query) in queries {
-            let mappings:Vec<Mapping> = query.mappings.into_iter().collect();
-            let dist  = WeightedAliasIndex::new(mappings.iter().map(|mapping| mapping.get_score()).collect()).unwrap();
+            let mut mappings:Vec<Mapping> = query.mappings.into_iter().collect();
+            mappings.sort_unstable_by_key(|mapping| mapping.get_reference_id());
+            let dist  = WeightedAliasIndex::new(mappings.iter().map(|mapping| mapping.get_score()).collect()).map_err(|_| name)?;
             // assign randomly
-            let mut rng = thread_rng();
             let chosen = &mappings[dist.sample(&mut rng)];
             self.add_assignment(name, chosen.get_reference_id(), chosen.get_score() as usize);
+            self.random_assignments.insert(name);
         }
+        Ok(())
+    }
+
+    /*
+        Re-estimate the abundances from the current assignment. Assigned queries count for their reference and 
+        each unassigned query is split over its references proportionally to the current abundances.
+        The counts are divided by query_size, the number of reads the capacities are computed for, so that
+        the capacity of a reference is its re-estimated read count plus the slack.
+     */
+    fn reestimate_abundance(&self, original_queries: &HashMap<usize, Query>, abundance: &HashMap<usize, f32>) -> HashMap<usize, f32> {
+        let mut counts: HashMap<usize, f32> = abundance.keys().map(|ref_id| (*ref_id, 0.0)).collect();
+        for (query_id, status) in &self.output_assignments {
+            match status {
+                AssignmentStatus::Assigned(ref_id) => {
+                    *counts.entry(*ref_id).or_insert(0.0) += 1.0;
+                },
+                AssignmentStatus::Unassigned => {
+                    let mappings = &original_queries[query_id].mappings;
+                    let denom: f32 = mappings.iter().map(|mapping| abundance.get(&mapping.get_reference_id()).copied().unwrap_or(0.0)).sum();
+                    if denom <= 0.0 {
+                        continue;
+                    }
+                    for mapping in mappings {
+                        let ref_id = mapping.get_reference_id();
+                        *counts.entry(ref_id).or_insert(0.0) += abundance.get(&ref_id).copied().unwrap_or(0.0) / denom;
+                    }
+                },
+                _ => {}
+            }
+        }
+
+        if self.query_size > 0 {
+            counts.values_mut().for_each(|count| *count /= self.query_size as f32);
+        }
+        counts
     }
 
     // leave left over queries unassigned
     fn leave_left_overs(&mut self, queries: HashMap<usize, Query>) {
         for (name, _query) in queries {
-            self.add_assignment(name, usize::MAX - 1, 0);
+            self.set_status(name, AssignmentStatus::Unassigned);
         }
     }
 }
@@ -341,11 +634,18 @@ fn check_if_read_is_uniquely_mapping(mappings: &HashSet<Mapping>) -> bool {
     it.all(|m| m.get_reference_id() == first)
 }
 
-// assign each mapping to a unique reference based on their mapping scores and the predicted abundance levels
-pub(crate) fn assign_mappings(cedar: Cedar, score_max_diff: f32, method: String) -> HashMap<String, String> {
+// number of queries whose assignment differs between two rounds, the randomly assigned left overs of either round are not counted
+fn count_changed_assignments(previous: &AssignmentMachine, current: &AssignmentMachine) -> usize {
+    current.output_assignments.iter()
+        .filter(|(query_id, _)| !previous.random_assignments.contains(query_id) && !current.random_assignments.contains(query_id))
+        .filter(|(query_id, status)| previous.output_assignments.get(query_id) != Some(status))
+        .count()
+}
+
+// run all assignment stages for the queries of cedar against the given abundances
+fn run_assignment_stages(cedar: &Cedar, abundance: HashMap<usize, f32>, score_max_diff: f32, method: &str, max_chain_depth: usize, slack: SlackModel) -> Result<AssignmentMachine> {
     let mut queries = cedar.get_queries();
-    let references = cedar.get_references();
-    let mut machine = AssignmentMachine::new(cedar.get_strain_abundance(), queries.len() - cedar.get_unmapping_reads());
+    let mut machine = AssignmentMachine::new(abundance, cedar.get_strain_valid(), queries.len() - cedar.get_unmapping_reads(), slack);
 
     println!("performing assignment of queries\n");
 
@@ -360,54 +660,351 @@ pub(crate) fn assign_mappings(cedar: Cedar, score_max_diff: f32, method: String)
     println!("second assignment done. query length: {}", queries.len());
 
     //assignment of the rest of the queries based on abundancies
-    machine.assign_based_on_abundance(queries, &cedar.queries, method);
+    machine.assign_based_on_abundance(queries, &cedar.queries, method.to_string(), max_chain_depth)
+        .map_err(|query_id| MoraError::Assignment { query: cedar.query_id_2_name[&query_id].to_string(),
+                                                      message: "its mapping scores cannot be used as weights for a random assignment".to_string() })?;
 
     println!("final assignment done. output length: {}", machine.output_assignments.len());
+    Ok(machine)
+}
+
+/*
+    assign each mapping to a unique reference based on their mapping scores and the predicted abundance levels
+    reestimate_rounds: maximum number of rounds where the abundances are re-estimated from the assignment and the
+        assignment is redone, stops early once no query changes its assignment (the randomly assigned left overs
+        are not counted, they only follow the capacities)
+    OUTPUT: the assignment of every query and the summary of the final assignment
+*/
+pub fn assign_mappings(cedar: Cedar, config: &MoraConfig) -> Result<(Vec<AssignmentRecord>, AssignmentSummary)> {
+    let (score_max_diff, method, max_chain_depth, slack, reestimate_rounds) =
+        (config.min_score_diff, &config.final_method, config.max_chain_depth, config.slack, config.reestimate_rounds);
+    let references = cedar.get_references();
+    let mut abundance = cedar.get_strain_abundance();
+    let mut machine = run_assignment_stages(&cedar, abundance.clone(), score_max_diff, method, max_chain_depth, slack)?;
+    let mut rounds = 0;
+
+    for round in 1..=reestimate_rounds {
+        let new_abundance = machine.reestimate_abundance(&cedar.queries, &abundance);
+        let abundance_change: f32 = new_abundance.iter().map(|(ref_id, value)| (value - abundance.get(ref_id).copied().unwrap_or(0.0)).abs()).sum();
+
+        let new_machine = run_assignment_stages(&cedar, new_abundance.clone(), score_max_diff, method, max_chain_depth, slack)?;
+        let changed = count_changed_assignments(&machine, &new_machine);
+        println!("re-estimation round {}: abundance change (L1) = {}, queries with a changed assignment = {}", round, abundance_change, changed);
+
+        machine = new_machine;
+        abundance = new_abundance;
+        rounds = round;
+        if changed == 0 {
+            println!("assignment stopped changing after {} re-estimation rounds", round);
+            break;
+        }
+    }
 
     //write final assignments
-    let mut output = HashMap::new();
-    for (query_id, id) in machine.output_assignments {
-        let name = cedar.query_id_2_name[&query_id].to_string();
-        if id == usize::MAX - 1 {                                           // queries that could not be assigned to anything (if using method 1: leave left over reads un-assigned)
-            output.insert(name, "NOT ALIGNED".to_string());
-        } else if id == usize::MAX {                                        // queries that couldn't be mapped to any reference from the first aligner
-            output.insert(name, "NOT ALIGNED".to_string());  
-        } else {                                                            // queries that were assigned to something
-            output.insert(name, references[&id].ref_name.to_string());
+    let (mut assigned, mut unmapped, mut unassigned, mut filtered, mut discordant) = (0, 0, 0, 0, 0);
+    let mut output = Vec::with_capacity(machine.output_assignments.len());
+    for (query_id, status) in machine.output_assignments {
+        let query = cedar.query_id_2_name[&query_id].to_string();
+        let mut reference = None;
+        match status {
+            AssignmentStatus::Assigned(id) => {                             // queries that were assigned to something
+                assigned += 1;
+                reference = Some(references[&id].ref_name.to_string());
+            },
+            AssignmentStatus::Unmapped => unmapped += 1,                    // queries that couldn't be mapped to any reference from the first aligner
+            AssignmentStatus::Unassigned => unassigned += 1,                // queries that could not be assigned to anything (if using method 1: leave left over reads un-assigned)
+            AssignmentStatus::ReferenceFiltered => filtered += 1,           // queries whose references were all removed by the EM
+            AssignmentStatus::Discordant => discordant += 1,                // fragments whose mates are mapped to different references
         }
+        output.push(AssignmentRecord { query, reference, status });
     }
-    output
+    println!("Assignment summary:");
+    println!("# of assigned reads: {}", assigned);
+    println!("# of unmapped reads: {}", unmapped);
+    println!("# of unassigned reads: {}", unassigned);
+    println!("# of reference filtered reads: {}", filtered);
+    println!("# of discordant fragments: {}", discordant);
+
+    let summary = AssignmentSummary { assigned, unassigned, unmapped, reference_filtered: filtered, discordant, reestimation_rounds: rounds, ..machine.summary };
+    Ok((output, summary))
 }
 
 // find the mapping with the best score and if it is a lot bigger than the second best mapping, return it
-fn assign_best(query: &Query, machine: &AssignmentMachine, score_max_diff: f32) -> (usize, f32) {
+fn assign_best(query: &Query, machine: &AssignmentMachine, score_max_diff: f32) -> Option<(usize, f32)> {
     let (r1, s1, _r2, s2) = query.get_best_mappings();
-    if (s1 - s2)/s1 > score_max_diff {
+    if (s1 - s2)/s1 > score_max_diff && machine.has_space(&r1) {
     // difference is enough to make it get mapped to the best mapping
-        if  machine.has_space(&r1){
-            (r1, s1)
-        } else {
-            (usize::MAX - 1, 0.0)
-        }
+        Some((r1, s1))
     } else {
-        (usize::MAX - 1, 0.0)
+        None
     }
 }
 
 // write the output into a file in the following way: query_name    reference_name
-pub(crate) fn write_output(output_filename: String, output: HashMap<String, String>) {
-    let mut output_file = File::create(output_filename).unwrap();
+pub fn write_output(output_filename: String, output: HashMap<String, String>) -> Result<()> {
+    let mut output_file = create_file(&output_filename)?;
     for (q_name, r_name) in output {
         let mut data = q_name;
-        data.push_str("\t");
+        data.push('\t');
         data.push_str(&r_name);
-        data.push_str("\t"); 
-        data.push_str("\n");
-        output_file.write(data.as_bytes()).ok();
+        data.push('\t'); 
+        data.push('\n');
+        write_to(&mut output_file, &output_filename, data.as_bytes())?;
     }
+    Ok(())
+}
+
+// read the output of write_output (or the first two columns of the output with taxonomy): <query name, reference name or assignment status>
+pub fn read_output(output_filename: &str) -> Result<HashMap<String, String>> {
+    let f = open_file(output_filename)?;
+    let mut output = HashMap::new();
+    for line in BufReader::new(f).lines() {
+        let line = line.map_err(|e| MoraError::io(output_filename, e))?;
+        if line.starts_with("Query\tReference") {    // header of the output with taxonomy
+            continue;
+        }
+        let mut chunks = line.split('\t');
+        if let (Some(q_name), Some(r_name)) = (chunks.next(), chunks.next()) {
+            output.insert(q_name.to_string(), r_name.to_string());
+        }
+    }
+    Ok(output)
 }
 
 // write the output into a file in the following way: query_name    reference_name  reference_species   reference_genus     reference_family    ...     reference_superkingdom
-pub(crate) fn write_output_with_taxonomy(output_filename: String, output: HashMap<String, String>, at_file: String, nodes_file: String, names_file: String) {
-    tax_main(output, at_file, nodes_file, names_file, output_filename)
+// reference_abundance and reports are used for the taxonomic profiles
+pub fn write_output_with_taxonomy(output_filename: String, output: HashMap<String, String>, reference_abundance: HashMap<String, f32>, 
+                                            source: TaxonomySource, options: TaxonomyOptions, reports: TaxonomyReports) -> Result<TaxonomySummary> {
+    tax_main(output, reference_abundance, source, options, reports, output_filename)
+}
+
+// same as write_output_with_taxonomy, with the taxonomy already loaded for the references of a batch
+pub fn write_output_with_loaded_taxonomy(output_filename: String, output: HashMap<String, String>, reference_abundance: HashMap<String, f32>, 
+                                                    taxonomy: &LoadedTaxonomy, options: &TaxonomyOptions, reports: &TaxonomyReports) -> Result<TaxonomySummary> {
+    write_taxonomy_outputs(output, reference_abundance, taxonomy, options, reports, output_filename)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cedar::readers::{Mate, Reference};
+
+    // a query with a mapping of the same score to each reference, or a fragment whose mates are mapped to the references
+    fn query(query_id: usize, ref_ids: &[i32], mates: bool) -> Query {
+        let mappings: HashSet<Mapping> = ref_ids.iter().enumerate().map(|(i, ref_id)| {
+            let mate = if !mates { Mate::Single } else if i == 0 { Mate::First } else { Mate::Second };
+            Mapping::new(*ref_id, 100, 0, 100, 100, mates, mate)
+        }).collect();
+        let mut query = Query::new(query_id, mappings.len(), 100, mappings, mates);
+        if mates {
+            query.pair_mates();
+        }
+        query
+    }
+
+    #[test]
+    fn initial_assignment_classifies_the_queries() {
+        let abundance = HashMap::from([(0, 0.5), (1, 0.5), (2, 0.0), (3, 0.0)]);
+        let strain_valid = HashMap::from([(0, true), (1, true), (2, false), (3, false)]);
+        let mut machine = AssignmentMachine::new(abundance, strain_valid, 6, SlackModel::Absolute(1.0));
+        let queries: HashMap<usize, Query> = [
+            query(0, &[], false),           // unmapped
+            query(1, &[0], false),          // unique
+            query(2, &[2], false),          // unique on an invalidated reference
+            query(3, &[2, 3], false),       // all references invalidated
+            query(4, &[0, 1], true),        // mates on different references
+            query(5, &[0, 1], false),       // ambiguous, left for the later stages
+        ].into_iter().map(|query| (query.query_id, query)).collect();
+
+        let left = machine.initial_assignment(queries);
+        assert_eq!(machine.output_assignments[&0], AssignmentStatus::Unmapped);
+        assert_eq!(machine.output_assignments[&1], AssignmentStatus::Assigned(0));
+        assert_eq!(machine.output_assignments[&2], AssignmentStatus::ReferenceFiltered);
+        assert_eq!(machine.output_assignments[&3], AssignmentStatus::ReferenceFiltered);
+        assert_eq!(machine.output_assignments[&4], AssignmentStatus::Discordant);
+        assert!(!machine.output_assignments.contains_key(&5));
+        assert_eq!(left.keys().collect::<Vec<_>>(), vec![&5]);
+        assert_eq!(machine.summary.unique, 1);
+    }
+
+    // a query with the given (reference, score) mappings
+    fn scored_query(query_id: usize, mappings: &[(i32, usize)]) -> Query {
+        let mappings: HashSet<Mapping> = mappings.iter().map(|(ref_id, score)| Mapping::new(*ref_id, *score, 0, 100, 100, false, Mate::Single)).collect();
+        Query::new(query_id, mappings.len(), 100, mappings, false)
+    }
+
+    // a machine with the given <ref_id, capacity> and the incumbents (query_id, ref_id, score) assigned
+    fn machine_with(capacities: &[(usize, usize)], incumbents: &[(usize, usize, usize)]) -> AssignmentMachine {
+        let abundance = capacities.iter().map(|(ref_id, _)| (*ref_id, 0.0)).collect();
+        let mut machine = AssignmentMachine::new(abundance, HashMap::new(), 0, SlackModel::Absolute(0.0));
+        machine.capacities = capacities.iter().cloned().collect();
+        for (query_id, ref_id, score) in incumbents {
+            machine.add_assignment(*query_id, *ref_id, *score);
+        }
+        machine
+    }
+
+    // place the leftovers, returns the IDs of the unplaced leftovers and the chain_depths
+    fn place(machine: &mut AssignmentMachine, original_queries: &HashMap<usize, Query>, leftover_ids: &[usize], max_chain_depth: usize) -> (Vec<usize>, Vec<usize>) {
+        let leftovers = leftover_ids.iter().map(|id| (*id, original_queries[id].clone())).collect();
+        let mut candidates = machine.compute_candidate_moves(original_queries, &leftovers);
+        let mut chain_depths = vec![0; max_chain_depth.max(1) + 1];
+        let unplaced = machine.place_leftovers(original_queries, &leftovers, &mut candidates, max_chain_depth, &mut chain_depths);
+        (unplaced.into_keys().collect(), chain_depths)
+    }
+
+    // the leftover 0 only maps to the full reference 0, the incumbent 1 can move to the full reference 1,
+    // whose incumbent 2 can move to reference 2 with space. moving incumbent 2 costs nothing or its whole score
+    fn two_move_chain(incumbent_2_alt_score: usize) -> (AssignmentMachine, HashMap<usize, Query>) {
+        let queries = [
+            scored_query(0, &[(0, 100)]),
+            scored_query(1, &[(0, 50), (1, 100)]),
+            scored_query(2, &[(1, 50), (2, incumbent_2_alt_score)]),
+        ].into_iter().map(|query| (query.query_id, query)).collect();
+        (machine_with(&[(0, 1), (1, 1), (2, 1)], &[(1, 0, 50), (2, 1, 50)]), queries)
+    }
+
+    #[test]
+    fn ejection_chain_of_depth_two_rescues_a_leftover() {
+        let (mut machine, queries) = two_move_chain(100);
+        let (unplaced, chain_depths) = place(&mut machine, &queries, &[0], 1);
+        assert_eq!(unplaced, vec![0]);
+        assert_eq!(chain_depths, vec![0, 0]);
+
+        let (unplaced, chain_depths) = place(&mut machine, &queries, &[0], 2);
+        assert!(unplaced.is_empty());
+        assert_eq!(chain_depths, vec![0, 0, 1]);
+        assert_eq!(machine.output_assignments[&0], AssignmentStatus::Assigned(0));
+        assert_eq!(machine.output_assignments[&1], AssignmentStatus::Assigned(1));
+        assert_eq!(machine.output_assignments[&2], AssignmentStatus::Assigned(2));
+        assert_eq!(machine.assigned_counts, HashMap::from([(0, 1), (1, 1), (2, 1)]));
+    }
+
+    #[test]
+    fn ejection_chain_stops_at_the_score_loss_budget() {
+        // moving incumbent 2 costs 2 * 50 / 100 - 50 / 100 = 0.5 on top of 0.0 for incumbent 1, within the budget of 1.0
+        let (mut machine, queries) = two_move_chain(50);
+        let (unplaced, chain_depths) = place(&mut machine, &queries, &[0], 2);
+        assert!(unplaced.is_empty());
+        assert_eq!(chain_depths, vec![0, 0, 1]);
+
+        // the leftover only has half of its score on reference 0, which is the budget the moves have to stay below
+        let (mut machine, mut queries) = two_move_chain(50);
+        queries.insert(0, scored_query(0, &[(0, 100), (3, 100)]));
+        machine.capacities.insert(3, 0);
+        machine.assigned_counts.insert(3, 0);
+        let (unplaced, chain_depths) = place(&mut machine, &queries, &[0], 2);
+        assert_eq!(unplaced, vec![0]);
+        assert_eq!(chain_depths, vec![0, 0, 0]);
+        assert_eq!(machine.output_assignments[&1], AssignmentStatus::Assigned(0));
+        assert_eq!(machine.output_assignments[&2], AssignmentStatus::Assigned(1));
+    }
+
+    #[test]
+    fn ejection_chain_search_backtracks_out_of_dead_ends() {
+        // incumbent 1 is examined first and leads to the dead end 1 -> 2 -> 3 at depth 3,
+        // the chain through incumbent 2 needs reference 2 again: 2 -> 3 -> 4
+        let queries: HashMap<usize, Query> = [
+            scored_query(0, &[(0, 100)]),
+            scored_query(1, &[(0, 40), (1, 80)]),
+            scored_query(2, &[(0, 50), (2, 100)]),
+            scored_query(3, &[(1, 50), (2, 100)]),
+            scored_query(4, &[(2, 50), (3, 100)]),
+            scored_query(5, &[(3, 50), (4, 100)]),
+        ].into_iter().map(|query| (query.query_id, query)).collect();
+        let mut machine = machine_with(&[(0, 2), (1, 1), (2, 1), (3, 1), (4, 1)], &[(1, 0, 40), (2, 0, 50), (3, 1, 50), (4, 2, 50), (5, 3, 50)]);
+
+        let (unplaced, chain_depths) = place(&mut machine, &queries, &[0], 3);
+        assert!(unplaced.is_empty());
+        assert_eq!(chain_depths, vec![0, 0, 0, 1]);
+        assert_eq!(machine.output_assignments[&2], AssignmentStatus::Assigned(2));
+        assert_eq!(machine.output_assignments[&4], AssignmentStatus::Assigned(3));
+        assert_eq!(machine.output_assignments[&5], AssignmentStatus::Assigned(4));
+    }
+
+    #[test]
+    fn paired_mates_are_assigned_to_the_same_reference() {
+        // each mate maps to two references, but both mates only map to reference 1
+        let mappings: HashSet<Mapping> = [(0, Mate::First), (1, Mate::First), (1, Mate::Second), (2, Mate::Second)].into_iter()
+            .map(|(ref_id, mate)| Mapping::new(ref_id, 100, 0, 100, 100, true, mate)).collect();
+        let fragment = Query::new(0, mappings.len(), 100, mappings, true);
+        let abundance = HashMap::from([(0, 0.4), (1, 0.2), (2, 0.4)]);
+
+        let mut machine = AssignmentMachine::new(abundance.clone(), HashMap::new(), 1, SlackModel::Absolute(1.0));
+        let left = machine.initial_assignment(HashMap::from([(0, fragment.clone())]));
+        assert_eq!(left.len(), 1);
+
+        let mut paired = fragment;
+        paired.pair_mates();
+        let mut machine = AssignmentMachine::new(abundance, HashMap::new(), 1, SlackModel::Absolute(1.0));
+        let left = machine.initial_assignment(HashMap::from([(0, paired)]));
+        assert!(left.is_empty());
+        assert_eq!(machine.output_assignments[&0], AssignmentStatus::Assigned(1));
+    }
+
+    #[test]
+    fn slack_model_capacities() {
+        // 0.25 * 10 = 2.5 expected reads
+        assert_eq!(SlackModel::Absolute(0.0).capacity(0.25, 10), 3);
+        assert_eq!(SlackModel::Absolute(1.5).capacity(0.25, 10), 4);
+        assert_eq!(SlackModel::Absolute(0.0).capacity(0.25, 8), 2);
+        assert_eq!(SlackModel::Relative(0.5).capacity(0.25, 8), 3);
+        assert_eq!(SlackModel::Relative(0.5).capacity(0.0, 8), 0);
+        // 50 expected reads with a standard deviation of sqrt(100 * 0.5 * 0.5) = 5
+        assert_eq!(SlackModel::Binomial(2.0).capacity(0.5, 100), 60);
+        assert_eq!(SlackModel::Binomial(2.0).capacity(1.0, 100), 100);
+        assert_eq!(SlackModel::Binomial(2.0).capacity(0.0, 100), 0);
+    }
+
+    #[test]
+    fn references_without_an_abundance_have_no_space() {
+        let mut machine = machine_with(&[(0, 1)], &[]);
+        assert!(machine.has_space(&0));
+        assert!(!machine.has_space(&1));
+        machine.add_assignment(0, 0, 100);
+        assert!(!machine.has_space(&0));
+    }
+
+    #[test]
+    fn reestimation_rounds_stop_once_the_assignment_converges() {
+        // queries 1 and 2 map uniquely to reference 0, 3 and 4 to reference 1, 5 to 8 prefer reference 0
+        let references = (0..2).map(|ref_id| (ref_id, Reference { ref_len: 1000, ref_name: format!("ref{}", ref_id) })).collect();
+        let queries = [
+            scored_query(1, &[(0, 100)]), scored_query(2, &[(0, 100)]), scored_query(3, &[(1, 100)]), scored_query(4, &[(1, 100)]),
+            scored_query(5, &[(0, 100), (1, 90)]), scored_query(6, &[(0, 100), (1, 90)]),
+            scored_query(7, &[(0, 100), (1, 90)]), scored_query(8, &[(0, 100), (1, 90)]),
+        ].into_iter().map(|query| (query.query_id, query)).collect();
+        let cedar = Cedar::with_abundances(references, queries, HashMap::from([(0, 0.5), (1, 0.25)]));
+        let config = MoraConfig::builder().slack(SlackModel::Absolute(0.0)).reestimate_rounds(5).build();
+
+        // without re-estimation reference 0 only holds 4 reads and 2 queries are left over
+        let (_, summary) = assign_mappings(cedar.clone(), &MoraConfig::builder().slack(SlackModel::Absolute(0.0)).build()).unwrap();
+        assert_eq!((summary.unassigned, summary.reestimation_rounds), (2, 0));
+
+        // the first round gives reference 0 room for all of its queries, the second round changes nothing
+        let (records, summary) = assign_mappings(cedar, &config).unwrap();
+        assert_eq!((summary.assigned, summary.unassigned, summary.reestimation_rounds), (8, 0, 2));
+        let on_reference_0 = records.iter().filter(|record| record.reference.as_deref() == Some("ref0")).count();
+        assert_eq!(on_reference_0, 6);
+    }
+
+    #[test]
+    fn changed_assignments_exclude_random_placements() {
+        let mut previous = machine_with(&[(0, 4), (1, 4)], &[(1, 0, 100), (2, 0, 100), (3, 1, 100)]);
+        let mut current = machine_with(&[(0, 4), (1, 4)], &[(1, 0, 100), (2, 1, 100), (3, 0, 100)]);
+        assert_eq!(count_changed_assignments(&previous, &current), 2);
+
+        previous.random_assignments.insert(2);
+        current.random_assignments.insert(3);
+        assert_eq!(count_changed_assignments(&previous, &current), 0);
+    }
+
+    #[test]
+    fn status_labels_round_trip() {
+        for status in [AssignmentStatus::Unmapped, AssignmentStatus::Unassigned, AssignmentStatus::ReferenceFiltered, AssignmentStatus::Discordant] {
+            assert_eq!(AssignmentStatus::from_label(status.label()), Some(status));
+        }
+        assert_eq!(AssignmentStatus::from_label("NC_000913.3"), None);
+    }
 }
@@ -1,14 +1,143 @@
-use std::{collections::{HashMap, HashSet}, fs::File, io::{BufReader, BufRead, Write}};
+use std::{collections::{HashMap, HashSet}, fs::File, io::{BufReader, BufRead, ErrorKind}, path::Path};
+
+pub mod taxonomy_db;
+use taxonomy_db::TaxonomyDb;
+
+pub mod prepare;
+
+mod gtdb;
+use gtdb::{gtdb_accessions_2_lineage, gtdb_files};
+
+mod profile;
+pub use profile::ProfileSource;
+use profile::{write_kraken_report, write_metaphlan_profile, ProfileTree};
+
+mod cami;
+use cami::{write_cami_binning, write_cami_profile};
+
+mod reference_names;
+pub use reference_names::{ReferenceNames, DEFAULT_NAME_RULES};
+
+mod rank_counts;
+use rank_counts::{write_rank_counts, RankCounts};
+
+mod krona;
+use krona::write_krona_text;
+
+mod sunburst;
+use sunburst::write_sunburst_html;
+
+use crate::assignment::AssignmentStatus;
+use crate::summary::TaxonomySummary;
+use crate::error::{create_file, open_file, write_to, MoraError, Result};
+
+/*
+    Where the taxonomy information is read from
+
+    Dmp: the accessions 2 taxIDs file with the NCBI nodes, names, merged and delnodes dump files
+    Database: a binary taxonomy database compiled with `mora taxonomy build`
+    Gtdb: GTDB taxonomy files (bac120_taxonomy.tsv, ar53_taxonomy.tsv)
+*/
+pub enum TaxonomySource {
+    Dmp { at_file: String, nodes_file: String, names_file: String, merged_file: String, delnodes_file: String },
+    Database(String),
+    Gtdb(Vec<String>),
+}
+
+impl TaxonomySource {
+    // for NCBI, a directory is expected to contain accessionsTaxIDs.tab, nodes.dmp and names.dmp (and optionally merged.dmp 
+    // and delnodes.dmp), a file is a compiled database
+    // for GTDB, the path is a taxonomy file or a directory with the taxonomy files
+    pub fn from_path(path: &str, format: &str) -> Result<TaxonomySource> {
+        if format == "gtdb" {
+            Ok(TaxonomySource::Gtdb(gtdb_files(path)?))
+        } else if Path::new(path).is_file() {
+            Ok(TaxonomySource::Database(path.to_string()))
+        } else {
+            Ok(TaxonomySource::Dmp { 
+                at_file: path.to_string() + "/accessionsTaxIDs.tab", 
+                nodes_file: path.to_string() + "/nodes.dmp", 
+                names_file: path.to_string() + "/names.dmp",
+                merged_file: path.to_string() + "/merged.dmp",
+                delnodes_file: path.to_string() + "/delnodes.dmp"
+            })
+        }
+    }
+}
+
+/*
+    Options for the taxonomy output
+
+    ranks: the ranks reported for each reference, from the most specific to the least specific
+    include_tax_ids: if a column with the TaxID of the reference is written
+    reference_names: how the reference names are matched to the accessions of the taxonomy
+*/
+pub struct TaxonomyOptions {
+    pub(crate) ranks: Vec<String>,
+    pub(crate) include_tax_ids: bool,
+    pub(crate) reference_names: ReferenceNames,
+}
+
+impl TaxonomyOptions {
+    pub fn new(ranks: &str, include_tax_ids: bool, reference_names: ReferenceNames) -> TaxonomyOptions {
+        let ranks = ranks.split(',').map(|rank| rank.trim().to_lowercase()).filter(|rank| !rank.is_empty()).collect();
+        TaxonomyOptions { ranks, include_tax_ids, reference_names }
+    }
+}
+
+/*
+    Taxonomic profiles written next to the per-read lineages, each one is written if it has a path
+
+    sample_id: the name of the sample in the profiles
+    kraken_report: Kraken2-style report of the assigned reads
+    metaphlan_profile: MetaPhlAn-style profile of the estimated abundances
+    cami_profile: CAMI taxonomic profiling format of the estimated abundances
+    cami_binning: CAMI binning format of the assignments
+    krona_text: Krona text input
+    sunburst_html: self-contained HTML page with a sunburst chart drawn as SVG, not an interactive Krona chart
+    krona_source: if the Krona text input and the sunburst chart show the assigned reads or the estimated abundances
+    rank_counts: table of the reads counted per taxon at every rank
+    rank_counts_source: if the reads are counted from the per-read lineages or estimated from the abundances
+*/
+#[derive(Default)]
+pub struct TaxonomyReports {
+    pub sample_id: String,
+    pub kraken_report: Option<String>,
+    pub metaphlan_profile: Option<String>,
+    pub cami_profile: Option<String>,
+    pub cami_binning: Option<String>,
+    pub krona_text: Option<String>,
+    pub sunburst_html: Option<String>,
+    pub krona_source: ProfileSource,
+    pub rank_counts: Option<String>,
+    pub rank_counts_source: ProfileSource,
+}
+
+impl TaxonomyReports {
+    fn is_empty(&self) -> bool {
+        self.kraken_report.is_none() && self.metaphlan_profile.is_none() && self.cami_profile.is_none() && self.cami_binning.is_none()
+            && self.krona_text.is_none() && self.sunburst_html.is_none() && self.rank_counts.is_none()
+    }
+}
+
+pub const DEFAULT_RANKS: &str = "species,genus,family,order,class,phylum,superkingdom";
+
+// NCBI renamed superkingdom to domain, both are accepted for either name
+fn rank_matches(wanted: &str, rank: &str) -> bool {
+    let is_domain = |rank: &str| rank == "superkingdom" || rank == "domain";
+    wanted == rank || (is_domain(wanted) && is_domain(rank))
+}
 
 #[derive(Clone)]
 struct Lineage {
     rank: String,
     name: String,
+    tax_id: String,
 }
 
 impl Lineage {
-    fn new(rank: String, name: String) -> Lineage {
-        Lineage { rank, name }
+    fn new(rank: String, name: String, tax_id: String) -> Lineage {
+        Lineage { rank, name, tax_id }
     }
     fn get_rank(&self) -> String {
         self.rank.to_string()
@@ -16,13 +145,15 @@ impl Lineage {
     fn get_name(&self) -> String {
         self.name.to_string()
     }
+    fn get_tax_id(&self) -> String {
+        self.tax_id.to_string()
+    }
 }
 
 /* 
     Add NA to the missing taxonomy ranks
 */
-fn fix_empty_ranks(lineage: HashMap<String, Vec<Lineage>>) -> HashMap<String, Vec<Lineage>> {
-    let ranks = vec!["species".to_string(), "genus".to_string(), "family".to_string(), "order".to_string(), "class".to_string(), "phylum".to_string(), "superkingdom".to_string()];
+fn fix_empty_ranks(lineage: HashMap<String, Vec<Lineage>>, ranks: &[String]) -> HashMap<String, Vec<Lineage>> {
     let mut new_lineage = HashMap::new();
     for (key, mut val) in lineage {
         let mut new_val = Vec::new();
@@ -30,10 +161,10 @@ fn fix_empty_ranks(lineage: HashMap<String, Vec<Lineage>>) -> HashMap<String, Ve
         new_val.push(val[0].clone());
         val.remove(0);
         // fill the rest in terms of rank
-        for rank in 0..ranks.len(){
-            let mut new_element = Lineage::new(ranks[rank].to_string(), "NA".to_string());
+        for rank in ranks {
+            let mut new_element = Lineage::new(rank.to_string(), "NA".to_string(), "NA".to_string());
             for i in 0..val.len() {
-                if val[i].get_rank() == ranks[rank]{
+                if rank_matches(rank, &val[i].get_rank()) {
                     new_element = val[i].clone();
                     val.remove(i);
                     break;
@@ -46,109 +177,269 @@ fn fix_empty_ranks(lineage: HashMap<String, Vec<Lineage>>) -> HashMap<String, Ve
     new_lineage
 }
 
+// the accessions that the queries were assigned to
+fn assigned_accessions(assignments: &HashMap<String, String>) -> HashSet<String> {
+    let mut accessions = HashSet::new();
+    for reference in assignments.values() {
+        accessions.insert(reference.to_string());
+    }
+    accessions
+}
+
 /*
-    assignments: <query name, reference name>
+    accessions: the accessions to look up
     at_file: accessions 2 taxIDs file
-    OUTPUT: <TaxID,  vector of accession numbers that have the given TaxID>
+    reference_names: decides if the versions of the accessions are ignored
+    OUTPUT: <accession, TaxID> of the accessions found in the file
 */
-fn temp_accessions_2_tax_id(assignments: &HashMap<String, String>, at_file: String) -> (HashMap<String, Vec<String>>, HashMap<String, String>) {
-    let mut tax_id_accesions = HashMap::new();
+fn temp_accessions_2_tax_id(accessions: &HashSet<String>, at_file: String, reference_names: &ReferenceNames) -> Result<HashMap<String, String>> {
+    // streamed, only the accessions that are looked up are kept
     let mut accessions_2_tax = HashMap::new();
-    let mut accessions = HashSet::new();
-    for (_, reference) in assignments {
-        accessions.insert(reference.to_string());
+    for chunks in read_columns(open_file(&at_file)?, &at_file, '\t', 2) {
+        let chunks = chunks?;
+        reference_names.add_accession(accessions, &mut accessions_2_tax, &chunks[0], &chunks[1]);
     }
-    let f = File::open(at_file).unwrap();
-    let stream = BufReader::new(f);
-    let lines: Vec<_> = stream.lines().collect();
-    
-    for line in lines {
-        let line = line.unwrap();
-        let chunks:Vec<_> = line.split('\t').collect();
-        let entry = tax_id_accesions.entry(chunks[1].to_string()).or_insert(Vec::new());
-        entry.push(chunks[0].to_string());
-        accessions_2_tax.insert(chunks[0].to_string(), chunks[1].to_string());
+    Ok(accessions_2_tax)
+}
+
+// <TaxID,  vector of reference names that have the given TaxID>
+fn tax_id_references(reference_2_tax: &HashMap<String, String>) -> HashMap<String, Vec<String>> {
+    let mut tax_id_references = HashMap::new();
+    for (reference, tax_id) in reference_2_tax {
+        let entry = tax_id_references.entry(tax_id.to_string()).or_insert(Vec::new());
+        entry.push(reference.to_string());
     }
-    let accessions_found:HashSet<String> = tax_id_accesions.keys().cloned().collect();
-    let mut not_found = HashSet::new();
-    for element in accessions {
-        if !accessions_found.contains(&element) {
-            not_found.insert(element);
+    tax_id_references
+}
+
+// the lines of a taxonomy file split at the separator, a line with less than min_columns columns is an error
+fn read_columns(f: File, file_name: &str, separator: char, min_columns: usize) -> impl Iterator<Item = Result<Vec<String>>> + '_ {
+    BufReader::new(f).lines().enumerate().map(move |(i, line)| {
+        let line = line.map_err(|e| MoraError::io(file_name, e))?;
+        let chunks: Vec<String> = line.split(separator).map(|chunk| chunk.to_string()).collect();
+        if chunks.len() < min_columns {
+            return Err(MoraError::parse(file_name, i + 1, format!("expected at least {} columns separated by '{}'", min_columns, separator)));
         }
+        Ok(chunks)
+    })
+}
+
+// accessions: <accession, TaxID> of all the accessions of the accessions 2 taxIDs file
+pub(crate) fn read_accessions(at_file: String) -> Result<HashMap<String, String>> {
+    let mut accessions = HashMap::new();
+    for chunks in read_columns(open_file(&at_file)?, &at_file, '\t', 2) {
+        let chunks = chunks?;
+        accessions.insert(chunks[0].to_string(), chunks[1].to_string());
     }
-    println!("number of accessions not found: {}", not_found.len());
-    (tax_id_accesions, accessions_2_tax)
+    Ok(accessions)
 }
 
-/*
-    nodes_file: nodes file
-    names_file: names file
-    tax_id_accessions: <TaxID,  vector of accession numbers that have the given TaxID>
-    OUTPUT: 
-*/
-fn build_taxonomy(nodes_file: String, names_file: String, tax_id_accessions: &HashMap<String, Vec<String>>) -> HashMap<String, Vec<Lineage>>{
-    let (mut nodes, mut names, mut lineage) = (HashMap::new(), HashMap::new(), HashMap::new());
-    let ranks = vec!["species".to_string(), "genus".to_string(), "family".to_string(), "order".to_string(), "class".to_string(), "phylum".to_string(), "superkingdom".to_string()];
-    
-    let f = File::open(nodes_file).unwrap();
-    let stream = BufReader::new(f);
-    let lines: Vec<_> = stream.lines().collect();
-
-    // nodes: <TaxID, <Parent TaxID, rank of TaxID>>
-    // example: <10, <1706371, genus>>
-    for line in lines {
-        let line = line.unwrap();
-        let chunks:Vec<_> = line.split('|').collect();
+// nodes: <TaxID, <Parent TaxID, rank of TaxID>>
+// example: <10, <1706371, genus>>
+pub(crate) fn read_nodes(nodes_file: String) -> Result<HashMap<String, Vec<String>>> {
+    let mut nodes = HashMap::new();
+    for chunks in read_columns(open_file(&nodes_file)?, &nodes_file, '|', 3) {
+        let chunks = chunks?;
         nodes.insert(chunks[0].trim().to_string(), vec![chunks[1].trim().to_string(), chunks[2].trim().to_string()]);
     }
-    
-    // names: <TaxID, scientific name>
-    let f = File::open(names_file).unwrap();
-    let stream = BufReader::new(f);
-    let lines: Vec<_> = stream.lines().collect();
-
-    for line in lines {
-        let line = line.unwrap();
-        let chunks:Vec<_> = line.split('|').collect();
+    Ok(nodes)
+}
+
+// names: <TaxID, scientific name>
+pub(crate) fn read_names(names_file: String) -> Result<HashMap<String, String>> {
+    let mut names = HashMap::new();
+    for chunks in read_columns(open_file(&names_file)?, &names_file, '|', 4) {
+        let chunks = chunks?;
         if chunks[3].trim() == "scientific name" {
             names.insert(chunks[0].trim().to_string(), chunks[1].trim().to_string());
         }
     }
+    Ok(names)
+}
+
+// an optional taxonomy file, none if it does not exist
+fn open_optional(file_name: &str) -> Result<Option<File>> {
+    match File::open(file_name) {
+        Ok(f) => Ok(Some(f)),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(MoraError::io(file_name, e))
+    }
+}
+
+// merged: <deprecated TaxID, TaxID it was merged into>, empty if there is no merged file
+pub(crate) fn read_merged(merged_file: String) -> Result<HashMap<String, String>> {
+    let mut merged = HashMap::new();
+    if let Some(f) = open_optional(&merged_file)? {
+        for chunks in read_columns(f, &merged_file, '|', 2) {
+            let chunks = chunks?;
+            merged.insert(chunks[0].trim().to_string(), chunks[1].trim().to_string());
+        }
+    }
+    Ok(merged)
+}
+
+// deleted: the deleted TaxIDs, empty if there is no delnodes file
+pub(crate) fn read_deleted(delnodes_file: String) -> Result<HashSet<String>> {
+    let mut deleted = HashSet::new();
+    if let Some(f) = open_optional(&delnodes_file)? {
+        for chunks in read_columns(f, &delnodes_file, '|', 1) {
+            deleted.insert(chunks?[0].trim().to_string());
+        }
+    }
+    Ok(deleted)
+}
+
+/*
+    How the TaxID of an accession was resolved
+
+    Resolved: the lineage was followed up to the root
+    Merged: the TaxID is deprecated and the TaxID it was merged into was used
+    Incomplete: the walk to the root stopped at an ancestor missing from the nodes or at a cycle
+    Deleted: the TaxID was deleted from the taxonomy
+    Missing: the TaxID is not in the taxonomy
+*/
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum TaxIdStatus {
+    Resolved,
+    Merged,
+    Incomplete,
+    Deleted,
+    Missing,
+}
+
+/*
+    The NCBI taxonomy tree
+
+    nodes: <TaxID, <Parent TaxID, rank of TaxID>>
+    names: <TaxID, scientific name>
+    merged: <deprecated TaxID, TaxID it was merged into>
+    deleted: the deleted TaxIDs
+*/
+pub(crate) struct TaxonomyTree {
+    pub(crate) nodes: HashMap<String, Vec<String>>,
+    pub(crate) names: HashMap<String, String>,
+    pub(crate) merged: HashMap<String, String>,
+    pub(crate) deleted: HashSet<String>,
+}
+
+impl TaxonomyTree {
+    pub(crate) fn from_dmp(nodes_file: String, names_file: String, merged_file: String, delnodes_file: String) -> Result<TaxonomyTree> {
+        Ok(TaxonomyTree { 
+            nodes: read_nodes(nodes_file)?, 
+            names: read_names(names_file)?, 
+            merged: read_merged(merged_file)?, 
+            deleted: read_deleted(delnodes_file)? 
+        })
+    }
+
+    // the current TaxID of a possibly deprecated TaxID
+    pub(crate) fn resolve<'a>(&'a self, tax_id: &'a str) -> &'a str {
+        self.merged.get(tax_id).map(|tax_id| tax_id.as_str()).unwrap_or(tax_id)
+    }
+
+    /*
+        Walk from the TaxID up to the root (the node that is its own parent). The walk also stops at a TaxID 
+        that is not in the nodes or that was already visited, so that broken dumps cannot make it loop forever.
+        OUTPUT: (the TaxID and its ancestors from the TaxID to the root, how the TaxID was resolved)
+    */
+    fn walk_to_root(&self, tax_id: &str) -> (Vec<Lineage>, TaxIdStatus) {
+        let start = self.resolve(tax_id);
+        if !self.nodes.contains_key(start) {
+            let status = if self.deleted.contains(start) { TaxIdStatus::Deleted } else { TaxIdStatus::Missing };
+            return (Vec::new(), status);
+        }
+
+        let mut status = if start != tax_id { TaxIdStatus::Merged } else { TaxIdStatus::Resolved };
+        let mut lineage = Vec::new();
+        let mut visited = HashSet::new();
+        let mut current = start;
+        loop {
+            let node = match self.nodes.get(current) {
+                Some(node) => node,
+                None => { status = TaxIdStatus::Incomplete; break; }
+            };
+            if !visited.insert(current) {
+                status = TaxIdStatus::Incomplete;
+                break;
+            }
+            let name = self.names.get(current).map(|name| name.as_str()).unwrap_or("NA");
+            lineage.push(Lineage::new(node[1].to_string(), name.to_string(), current.to_string()));
+
+            let parent = self.resolve(&node[0]);
+            if parent == current {
+                break;
+            }
+            current = parent;
+        }
+        (lineage, status)
+    }
+}
+
+/*
+    tree: the taxonomy tree
+    tax_id_accessions: <TaxID,  vector of accession numbers that have the given TaxID>
+    OUTPUT: <TaxID, lineage of the TaxID>, TaxIDs that cannot be resolved have no lineage
+*/
+fn build_taxonomy(tree: &TaxonomyTree, tax_id_accessions: &HashMap<String, Vec<String>>, ranks: &[String]) -> HashMap<String, Vec<Lineage>>{
+    let mut lineage = HashMap::new();
+    let mut statuses = Vec::new();
     
     for key in tax_id_accessions.keys() {       //key is TaxID
-        let mut temp_lineage = Vec::new();  // rank| name of parent| parent TaxID
         let mut helpful_list = Vec::new();
-        let mut parent = key;
-        while parent != "131567" && parent != "10239" {
-            let val = Lineage::new(nodes[parent][1].to_string(), names[parent].to_string());
-            temp_lineage.push(val);
-            parent = &nodes[parent][0];
-        }
-        let mut rank_counter = 0;
-        for rank in 0..temp_lineage.len() {
+        let (temp_lineage, status) = tree.walk_to_root(key);  // rank| name of parent| parent TaxID
+        statuses.push((key, status));
+        if temp_lineage.is_empty() {
+            continue;
+        }
+        for (rank_counter, parent) in temp_lineage.iter().enumerate() {
             if rank_counter == 0 {
-                helpful_list.push(temp_lineage[rank].clone());
-            } else {
-                let parent_rank = temp_lineage[rank].get_rank();
-                if ranks.contains(&parent_rank) { // checks if the rank of parent is valid
-                    helpful_list.push(temp_lineage[rank].clone());
-                }
+                helpful_list.push(parent.clone());  // the TaxID itself, also reported at its own rank below
+            }
+            let parent_rank = parent.get_rank();
+            if ranks.iter().any(|rank| rank_matches(rank, &parent_rank)) { // checks if the rank of the TaxID or its parent is valid
+                helpful_list.push(parent.clone());
             }
-            rank_counter += 1;
         }
         lineage.insert(key.to_owned(), helpful_list); // lineage: <TaxID, list of TaxID and its ancestors in the form: " rank of key | name of key"
     }
-    lineage = fix_empty_ranks(lineage);
+    report_tax_id_statuses(&statuses, tax_id_accessions);
+    lineage = fix_empty_ranks(lineage, ranks);
     lineage
 }
 
+// print how the TaxIDs were resolved and which accessions have a TaxID that could not be resolved
+fn report_tax_id_statuses(statuses: &[(&String, TaxIdStatus)], tax_id_accessions: &HashMap<String, Vec<String>>) {
+    let count = |status: TaxIdStatus| statuses.iter().filter(|(_, s)| *s == status).count();
+    println!("TaxIDs resolved: {}, resolved through merged TaxIDs: {}, with an incomplete lineage: {}, deleted: {}, missing from the taxonomy: {}", 
+        count(TaxIdStatus::Resolved), count(TaxIdStatus::Merged), count(TaxIdStatus::Incomplete), count(TaxIdStatus::Deleted), count(TaxIdStatus::Missing));
+
+    let mut unresolved = Vec::new();
+    for (tax_id, status) in statuses {
+        if *status == TaxIdStatus::Deleted || *status == TaxIdStatus::Missing {
+            for accession in &tax_id_accessions[*tax_id] {
+                unresolved.push((accession, tax_id));
+            }
+        }
+    }
+    if !unresolved.is_empty() {
+        unresolved.sort();
+        println!("accessions with an unresolvable TaxID: {}", unresolved.len());
+        for (accession, tax_id) in unresolved.iter().take(10) {
+            println!("\t{}\t{}", accession, tax_id);
+        }
+        if unresolved.len() > 10 {
+            println!("\t...");
+        }
+    }
+}
+
 // find the lineages of the assignments
-fn assignments_2_lineage(assignments: HashMap<String, String>, lineage: HashMap<String, Vec<Lineage>>, accessions_2_tax: HashMap<String, String>) -> HashMap<String, (String, Vec<Lineage>)> {
+fn assignments_2_lineage(assignments: &HashMap<String, String>, lineage: &HashMap<String, Vec<Lineage>>, accessions_2_tax: &HashMap<String, String>, ranks: &[String]) -> HashMap<String, (String, Vec<Lineage>)> {
     let mut assignments2lineage = HashMap::new();
-    for (query, reference) in &assignments {
-        let mut assigned_lineage = create_na_lineage();
-        if accessions_2_tax.contains_key(reference) {
-            assigned_lineage = lineage[&accessions_2_tax[reference]].clone();
+    for (query, reference) in assignments {
+        let mut assigned_lineage = create_na_lineage(ranks.len());
+        if let Some(found) = accessions_2_tax.get(reference).and_then(|tax_id| lineage.get(tax_id)) {
+            assigned_lineage = found.clone();
         }    
         assignments2lineage.insert(query.to_string(),(reference.to_string(), assigned_lineage));
     }
@@ -156,50 +447,316 @@ fn assignments_2_lineage(assignments: HashMap<String, String>, lineage: HashMap<
 }
 
 
-fn create_na_lineage() -> Vec<Lineage> {
+// a lineage of NA for the key and each of the rank_cnt ranks
+fn create_na_lineage(rank_cnt: usize) -> Vec<Lineage> {
     let mut v = Vec::new();
-    for _ in 0..8{
-        v.push(Lineage::new("NA".to_string(), "NA".to_string()));
+    for _ in 0..rank_cnt + 1 {
+        v.push(Lineage::new("NA".to_string(), "NA".to_string(), "NA".to_string()));
     }
     v
 }
 
+// rank name with a capital first letter for the header
+fn rank_header(rank: &str) -> String {
+    let mut chars = rank.chars();
+    match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars).collect(),
+        None => String::new()
+    }
+}
+
 
 /*
 Write the results out in the following format:
-Query reads     Assigned Reference      Species     Genus       Family      Order       Class       Phylum      Superkingdom
+Query reads     Assigned Reference      (TaxID)     Species     Genus       Family      Order       Class       Phylum      Superkingdom
+where the rank columns follow the configured ranks
 
 Inputs: 
     out_dir: where to write the results
     assignment2lineage: the results
+    options: the ranks to write and if the TaxID is written
 */
 
-fn write_results(out_dir: String, assignment2lineage: HashMap<String, (String, Vec<Lineage>)>) {
+fn write_results(out_dir: String, assignment2lineage: HashMap<String, (String, Vec<Lineage>)>, options: &TaxonomyOptions) -> Result<()> {
     let mut results = Vec::new();
-    let mut output = File::create(out_dir).unwrap();
-    let data = ["Query", "Reference", "Species", "Genus", "Family", "Order", "Class", "Phylum", "Superkingdom"].join("\t");
-    output.write(data.as_bytes()).ok();
-    output.write("\n\n".as_bytes()).ok();
+    let mut output = create_file(&out_dir)?;
+    let mut header = vec!["Query".to_string(), "Reference".to_string()];
+    if options.include_tax_ids {
+        header.push("TaxID".to_string());
+    }
+    header.extend(options.ranks.iter().map(|rank| rank_header(rank)));
+    let data = header.join("\t");
+    write_to(&mut output, &out_dir, data.as_bytes())?;
+    write_to(&mut output, &out_dir, "\n\n".as_bytes())?;
     for (query, (reference, assigned)) in &assignment2lineage {
         let mut result = Vec::new();
         result.push(query.to_string());
         result.push(reference.to_string());
-        for i in 1..assigned.len() {
-            result.push(assigned[i].get_name());
+        if options.include_tax_ids {
+            result.push(assigned[0].get_tax_id());
+        }
+        for lineage in &assigned[1..] {
+            result.push(lineage.get_name());
         }
         results.push(result);
     }
     for result in results {
         let i = result.join("\t");
-        output.write(i.as_bytes()).ok();
-        output.write("\n".as_bytes()).ok();
+        write_to(&mut output, &out_dir, i.as_bytes())?;
+        write_to(&mut output, &out_dir, "\n".as_bytes())?;
     }
+    Ok(())
 }
 
 
-pub(crate) fn tax_main(assignments: HashMap<String, String>, at_file: String, nodes_file: String, names_file: String, out_dir: String) {
-    let (tax_id_accesions, accessions_2_tax) = temp_accessions_2_tax_id(&assignments, at_file); // map the accessions to the taxonomic IDs
-    let lineage = build_taxonomy(nodes_file, names_file, &tax_id_accesions); // create lineages of the references
-    let assignments2lineage = assignments_2_lineage(assignments, lineage, accessions_2_tax); //
-    write_results(out_dir, assignments2lineage);
+/*
+    Roll the assigned reads and the estimated abundances up the taxonomy and write the requested profiles
+
+    assignments: <query name, reference name>
+    reference_abundance: <reference name, estimated abundance>
+*/
+fn write_reports(assignments: &HashMap<String, String>, reference_abundance: &HashMap<String, f32>, lineage: &HashMap<String, Vec<Lineage>>, 
+                    accessions_2_tax: &HashMap<String, String>, ranks: &[String], reports: &TaxonomyReports) -> Result<()> {
+    let lineage_of = |reference: &String| accessions_2_tax.get(reference).and_then(|tax_id| lineage.get(tax_id));
+    let mut reads_per_reference: HashMap<&String, usize> = HashMap::new();
+    for reference in assignments.values() {
+        *reads_per_reference.entry(reference).or_insert(0) += 1;
+    }
+
+    let mut tree = ProfileTree::new();
+    for (reference, reads) in reads_per_reference {
+        tree.add_reads(lineage_of(reference), reads);
+    }
+    for (reference, abundance) in reference_abundance {
+        tree.add_abundance(lineage_of(reference), *abundance);
+    }
+
+    if let Some(kraken_report) = &reports.kraken_report {
+        println!("Writing Kraken-style report to {}", kraken_report);
+        write_kraken_report(&tree, kraken_report)?;
+    }
+    if let Some(metaphlan_profile) = &reports.metaphlan_profile {
+        println!("Writing MetaPhlAn-style profile to {}", metaphlan_profile);
+        write_metaphlan_profile(&tree, &reports.sample_id, metaphlan_profile)?;
+    }
+    if let Some(cami_profile) = &reports.cami_profile {
+        println!("Writing CAMI profile to {}", cami_profile);
+        write_cami_profile(&tree, ranks, &reports.sample_id, cami_profile)?;
+    }
+    if let Some(cami_binning) = &reports.cami_binning {
+        println!("Writing CAMI binning to {}", cami_binning);
+        write_cami_binning(assignments, lineage_of, &reports.sample_id, cami_binning)?;
+    }
+    if let Some(krona_text) = &reports.krona_text {
+        println!("Writing Krona text input to {}", krona_text);
+        write_krona_text(&tree, reports.krona_source, krona_text)?;
+    }
+    if let Some(sunburst_html) = &reports.sunburst_html {
+        println!("Writing sunburst chart to {}", sunburst_html);
+        write_sunburst_html(&tree, reports.krona_source, &reports.sample_id, sunburst_html)?;
+    }
+    Ok(())
+}
+
+
+/*
+    The TaxIDs and lineages of a set of references, loaded once and used for the outputs of one or more samples
+
+    accessions_2_tax: <reference name, TaxID>
+    lineage: <TaxID, lineage>
+*/
+pub struct LoadedTaxonomy {
+    accessions_2_tax: HashMap<String, String>,
+    lineage: HashMap<String, Vec<Lineage>>,
+}
+
+// the references that need a lineage: the assigned references and, for the reports, the references with an estimated abundance
+fn output_accessions(assignments: &HashMap<String, String>, reference_abundance: &HashMap<String, f32>, reports: &TaxonomyReports) -> HashSet<String> {
+    let mut accessions = assigned_accessions(assignments);
+    if !reports.is_empty() {
+        // references without assigned reads can still have an estimated abundance
+        accessions.extend(reference_abundance.iter().filter(|(_, abundance)| **abundance > 0.0).map(|(reference, _)| reference.to_string()));
+    }
+    accessions
+}
+
+// find the TaxIDs and lineages of the accessions
+pub fn load_taxonomy(accessions: &HashSet<String>, source: TaxonomySource, options: &TaxonomyOptions) -> Result<LoadedTaxonomy> {
+    let reference_names = &options.reference_names;
+    let keys = reference_names.lookup_keys(accessions);
+    let (accessions_2_tax, lineage) = match source {
+        TaxonomySource::Dmp { at_file, nodes_file, names_file, merged_file, delnodes_file } => {
+            let found = temp_accessions_2_tax_id(&keys, at_file, reference_names)?; // map the accessions to the taxonomic IDs
+            let accessions_2_tax = reference_names.resolve(accessions, &found, true);
+            let tree = TaxonomyTree::from_dmp(nodes_file, names_file, merged_file, delnodes_file)?;
+            let lineage = build_taxonomy(&tree, &tax_id_references(&accessions_2_tax), &options.ranks); // create lineages of the references
+            (accessions_2_tax, lineage)
+        },
+        TaxonomySource::Database(db_file) => {
+            let db = TaxonomyDb::open(db_file)?;
+            let found = db.accessions_2_tax_id(&keys, reference_names.ignores_versions());
+            let accessions_2_tax = reference_names.resolve(accessions, &found, true);
+            let tax_id_accesions = tax_id_references(&accessions_2_tax);
+            let tree = db.subtree(tax_id_accesions.keys());
+            let lineage = build_taxonomy(&tree, &tax_id_accesions, &options.ranks);
+            (accessions_2_tax, lineage)
+        },
+        TaxonomySource::Gtdb(files) => {
+            let (found, lineage) = gtdb_accessions_2_lineage(files, &keys, reference_names.ignores_versions())?;
+            let accessions_2_tax = reference_names.resolve(accessions, &found, false);
+            (accessions_2_tax, fix_empty_ranks(lineage, &options.ranks))
+        }
+    };
+    Ok(LoadedTaxonomy { accessions_2_tax, lineage })
+}
+
+/*
+    write the assignments with their lineages, the requested reports and rank counts of a sample
+    OUTPUT: the number of references looked up and the ones without a lineage
+*/
+pub(crate) fn write_taxonomy_outputs(assignments: HashMap<String, String>, reference_abundance: HashMap<String, f32>, taxonomy: &LoadedTaxonomy,
+                                        options: &TaxonomyOptions, reports: &TaxonomyReports, out_dir: String) -> Result<TaxonomySummary> {
+    let accessions = output_accessions(&assignments, &reference_abundance, reports);
+    let (accessions_2_tax, lineage) = (&taxonomy.accessions_2_tax, &taxonomy.lineage);
+    // references without a TaxID or a lineage, the assignment statuses are not references
+    let references: Vec<&String> = accessions.iter().filter(|accession| !AssignmentStatus::is_unassigned_label(accession)).collect();
+    let mut missing_accessions: Vec<String> = references.iter()
+        .filter(|accession| accessions_2_tax.get(**accession).and_then(|tax_id| lineage.get(tax_id)).is_none())
+        .map(|accession| accession.to_string()).collect();
+    missing_accessions.sort();
+
+    if !reports.is_empty() {
+        write_reports(&assignments, &reference_abundance, lineage, accessions_2_tax, &options.ranks, reports)?;
+    }
+    let assignments2lineage = assignments_2_lineage(&assignments, lineage, accessions_2_tax, &options.ranks); //
+    if let Some(rank_counts) = &reports.rank_counts {
+        println!("Writing read counts per rank to {}", rank_counts);
+        let counts = match reports.rank_counts_source {
+            ProfileSource::Reads => RankCounts::from_reads(&assignments2lineage),
+            ProfileSource::Abundance => {
+                let lineage_of = |reference: &String| accessions_2_tax.get(reference).and_then(|tax_id| lineage.get(tax_id));
+                RankCounts::from_abundance(&reference_abundance, lineage_of, &assignments)
+            }
+        };
+        write_rank_counts(&counts, reports.rank_counts_source, &options.ranks, rank_counts)?;
+    }
+    write_results(out_dir, assignments2lineage, options)?;
+    Ok(TaxonomySummary { accessions: references.len(), missing_accessions })
+}
+
+pub(crate) fn tax_main(assignments: HashMap<String, String>, reference_abundance: HashMap<String, f32>, source: TaxonomySource, 
+                        options: TaxonomyOptions, reports: TaxonomyReports, out_dir: String) -> Result<TaxonomySummary> {
+    let accessions = output_accessions(&assignments, &reference_abundance, &reports);
+    let taxonomy = load_taxonomy(&accessions, source, &options)?;
+    write_taxonomy_outputs(assignments, reference_abundance, &taxonomy, &options, &reports, out_dir)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a taxonomy tree from (TaxID, parent TaxID, rank, name) nodes, without merged or deleted TaxIDs
+    fn tree(nodes: &[(&str, &str, &str, &str)]) -> TaxonomyTree {
+        TaxonomyTree {
+            nodes: nodes.iter().map(|(tax_id, parent, rank, _)| (tax_id.to_string(), vec![parent.to_string(), rank.to_string()])).collect(),
+            names: nodes.iter().map(|(tax_id, _, _, name)| (tax_id.to_string(), name.to_string())).collect(),
+            merged: HashMap::new(),
+            deleted: HashSet::new(),
+        }
+    }
+
+    // the names of the lineage of a TaxID at the given ranks, built as for the output
+    fn names_at_ranks(tree: &TaxonomyTree, tax_id: &str, ranks: &str) -> Vec<String> {
+        let ranks = TaxonomyOptions::new(ranks, false, ReferenceNames::new(DEFAULT_NAME_RULES, None).unwrap()).ranks;
+        let tax_id_accessions = HashMap::from([(tax_id.to_string(), vec!["accession".to_string()])]);
+        let lineage = build_taxonomy(tree, &tax_id_accessions, &ranks);
+        lineage[tax_id][1..].iter().map(|entry| entry.get_name()).collect()
+    }
+
+    // E. coli and HIV-1 in a dump from before and after NCBI replaced superkingdom with domain and acellular root
+    fn old_dump() -> TaxonomyTree {
+        tree(&[("1", "1", "no rank", "root"), ("2", "1", "superkingdom", "Bacteria"), ("1224", "2", "phylum", "Pseudomonadota"),
+                ("562", "1224", "species", "Escherichia coli"), ("10239", "1", "superkingdom", "Viruses"),
+                ("2559587", "10239", "realm", "Riboviria"), ("11676", "2559587", "species", "Human immunodeficiency virus 1")])
+    }
+
+    fn new_dump() -> TaxonomyTree {
+        tree(&[("1", "1", "no rank", "root"), ("2", "1", "domain", "Bacteria"), ("1224", "2", "phylum", "Pseudomonadota"),
+                ("562", "1224", "species", "Escherichia coli"), ("10239", "1", "acellular root", "Viruses"),
+                ("2559587", "10239", "realm", "Riboviria"), ("11676", "2559587", "species", "Human immunodeficiency virus 1")])
+    }
+
+    #[test]
+    fn superkingdom_and_domain_match_each_other() {
+        assert!(rank_matches("superkingdom", "domain"));
+        assert!(rank_matches("domain", "superkingdom"));
+        assert!(rank_matches("realm", "realm"));
+        assert!(!rank_matches("superkingdom", "realm"));
+        assert!(!rank_matches("species", "strain"));
+    }
+
+    #[test]
+    fn domain_ranks_are_found_in_old_and_new_dumps() {
+        for dump in [old_dump(), new_dump()] {
+            assert_eq!(names_at_ranks(&dump, "562", DEFAULT_RANKS), vec!["Escherichia coli", "NA", "NA", "NA", "NA", "Pseudomonadota", "Bacteria"]);
+            assert_eq!(names_at_ranks(&dump, "562", "Species, Phylum, Domain, Realm"), vec!["Escherichia coli", "Pseudomonadota", "Bacteria", "NA"]);
+        }
+        assert_eq!(names_at_ranks(&old_dump(), "11676", "species,realm,superkingdom"), vec!["Human immunodeficiency virus 1", "Riboviria", "Viruses"]);
+        assert_eq!(names_at_ranks(&new_dump(), "11676", "species,realm,domain"), vec!["Human immunodeficiency virus 1", "Riboviria", "NA"]);
+    }
+
+    #[test]
+    fn the_rank_of_the_tax_id_itself_is_reported() {
+        let mut dump = new_dump();
+        dump.nodes.insert("511145".to_string(), vec!["562".to_string(), "strain".to_string()]);
+        dump.names.insert("511145".to_string(), "Escherichia coli str. K-12 substr. MG1655".to_string());
+        assert_eq!(names_at_ranks(&dump, "511145", "strain,species,domain"), vec!["Escherichia coli str. K-12 substr. MG1655", "Escherichia coli", "Bacteria"]);
+        assert_eq!(names_at_ranks(&dump, "562", "strain,species,domain"), vec!["NA", "Escherichia coli", "Bacteria"]);
+    }
+
+    // the TaxIDs of the walk to the root and how the TaxID was resolved
+    fn walk(tree: &TaxonomyTree, tax_id: &str) -> (Vec<String>, TaxIdStatus) {
+        let (lineage, status) = tree.walk_to_root(tax_id);
+        (lineage.iter().map(|entry| entry.get_tax_id()).collect(), status)
+    }
+
+    // a dump with non-cellular entries, a parent cycle, a missing parent, a merged and a deleted TaxID
+    fn broken_dump() -> TaxonomyTree {
+        let mut dump = tree(&[("1", "1", "no rank", "root"), ("2", "1", "domain", "Bacteria"), ("562", "2", "species", "Escherichia coli"),
+                            ("28384", "1", "no rank", "other sequences"), ("81077", "28384", "no rank", "artificial sequences"),
+                            ("32630", "81077", "species", "synthetic construct"), ("5", "6", "species", "cycle a"), ("6", "5", "genus", "cycle b"),
+                            ("7", "8", "species", "orphan")]);
+        dump.merged.insert("100".to_string(), "562".to_string());
+        dump.deleted.insert("999".to_string());
+        dump
+    }
+
+    #[test]
+    fn lineages_outside_the_cellular_organisms_reach_the_root() {
+        assert_eq!(walk(&broken_dump(), "32630"), (vec!["32630".to_string(), "81077".to_string(), "28384".to_string(), "1".to_string()], TaxIdStatus::Resolved));
+        assert_eq!(walk(&broken_dump(), "562"), (vec!["562".to_string(), "2".to_string(), "1".to_string()], TaxIdStatus::Resolved));
+    }
+
+    #[test]
+    fn broken_lineages_stop_the_walk() {
+        assert_eq!(walk(&broken_dump(), "5"), (vec!["5".to_string(), "6".to_string()], TaxIdStatus::Incomplete));
+        assert_eq!(walk(&broken_dump(), "7"), (vec!["7".to_string()], TaxIdStatus::Incomplete));
+    }
+
+    #[test]
+    fn merged_deleted_and_missing_tax_ids() {
+        assert_eq!(walk(&broken_dump(), "100"), (vec!["562".to_string(), "2".to_string(), "1".to_string()], TaxIdStatus::Merged));
+        assert_eq!(walk(&broken_dump(), "999"), (Vec::new(), TaxIdStatus::Deleted));
+        assert_eq!(walk(&broken_dump(), "12345"), (Vec::new(), TaxIdStatus::Missing));
+
+        // only the TaxIDs that could be resolved get a lineage
+        let tax_id_accessions: HashMap<String, Vec<String>> = ["100", "999", "12345", "5"].iter()
+            .map(|tax_id| (tax_id.to_string(), vec![format!("accession_{}", tax_id)])).collect();
+        let ranks = vec!["species".to_string(), "domain".to_string()];
+        let lineage = build_taxonomy(&broken_dump(), &tax_id_accessions, &ranks);
+        let mut tax_ids: Vec<&String> = lineage.keys().collect();
+        tax_ids.sort();
+        assert_eq!(tax_ids, vec!["100", "5"]);
+        assert_eq!(lineage["100"].iter().map(|entry| entry.get_name()).collect::<Vec<_>>(), vec!["Escherichia coli", "Escherichia coli", "Bacteria"]);
+    }
 }
@@ -0,0 +1,137 @@
+use std::collections::HashMap;
+
+use super::{rank_matches, Lineage};
+use super::profile::ProfileTree;
+use crate::assignment::AssignmentStatus;
+use crate::error::{create_file, write_to, Result};
+
+// the profiling and the binning format are versioned separately by bioboxes, each header has the version of its own format
+const CAMI_PROFILE_VERSION: &str = "0.9.1";
+const CAMI_BINNING_VERSION: &str = "0.9.0";
+
+// ranks of the CAMI formats, from the least specific to the most specific
+const CAMI_RANKS: [&str; 8] = ["superkingdom", "phylum", "class", "order", "family", "genus", "species", "strain"];
+
+// the CAMI ranks among the reported ranks, from the least specific to the most specific
+fn cami_ranks(ranks: &[String]) -> Vec<&'static str> {
+    CAMI_RANKS.iter().filter(|cami_rank| ranks.iter().any(|rank| rank_matches(cami_rank, rank))).copied().collect()
+}
+
+/*
+Write the CAMI taxonomic profiling format (bioboxes profiling 0.9.1) of the estimated abundances:
+@@TAXID     RANK        TAXPATH     TAXPATHSN       PERCENTAGE
+where TAXPATH and TAXPATHSN hold the TaxIDs and names of the CAMI ranks down to the rank of the taxon,
+left empty for ranks that are missing from the lineage
+*/
+pub(super) fn write_cami_profile(tree: &ProfileTree, ranks: &[String], sample_id: &str, output_filename: &str) -> Result<()> {
+    let ranks = cami_ranks(ranks);
+    let mut rows = Vec::new();
+    for (tax_id, node) in &tree.nodes {
+        let depth = match ranks.iter().position(|rank| rank_matches(rank, &node.rank)) {
+            Some(depth) => depth,
+            None => continue
+        };
+        if node.abundance <= 0.0 {
+            continue;
+        }
+        let path = tree.path(tax_id);
+        let mut tax_path = Vec::new();
+        let mut tax_path_names = Vec::new();
+        for rank in &ranks[..=depth] {
+            match path.iter().find(|t| rank_matches(rank, &tree.nodes[**t].rank)) {
+                Some(t) => {
+                    tax_path.push(t.to_string());
+                    tax_path_names.push(tree.nodes[*t].name.to_string());
+                },
+                None => {
+                    tax_path.push(String::new());
+                    tax_path_names.push(String::new());
+                }
+            }
+        }
+        rows.push((depth, node.abundance, tax_id, tax_path.join("|"), tax_path_names.join("|")));
+    }
+    rows.sort_by(|a, b| a.0.cmp(&b.0).then(b.1.total_cmp(&a.1)).then(a.2.cmp(b.2)));
+
+    let percentage = |abundance: f32| if tree.total_abundance > 0.0 { 100.0 * abundance / tree.total_abundance } else { 0.0 };
+    let mut output = create_file(output_filename)?;
+    let header = format!("@SampleID:{}\n@Version:{}\n@Ranks:{}\n\n@@TAXID\tRANK\tTAXPATH\tTAXPATHSN\tPERCENTAGE\n", sample_id, CAMI_PROFILE_VERSION, ranks.join("|"));
+    write_to(&mut output, output_filename, header.as_bytes())?;
+    for (depth, abundance, tax_id, tax_path, tax_path_names) in rows {
+        let data = format!("{}\t{}\t{}\t{}\t{:.5}\n", tax_id, ranks[depth], tax_path, tax_path_names, percentage(abundance));
+        write_to(&mut output, output_filename, data.as_bytes())?;
+    }
+    Ok(())
+}
+
+/*
+Write the CAMI binning format (bioboxes binning 0.9.0) of the assignments:
+@@SEQUENCEID        BINID       TAXID
+where the bin of a read is its assigned reference, reads without a reference are left out
+and the TaxID is NA for references without a lineage
+*/
+pub(super) fn write_cami_binning<'a>(assignments: &HashMap<String, String>, lineage_of: impl Fn(&String) -> Option<&'a Vec<Lineage>>,
+                                        sample_id: &str, output_filename: &str) -> Result<()> {
+    let mut output = create_file(output_filename)?;
+    let header = format!("@Version:{}\n@SampleID:{}\n\n@@SEQUENCEID\tBINID\tTAXID\n", CAMI_BINNING_VERSION, sample_id);
+    write_to(&mut output, output_filename, header.as_bytes())?;
+
+    let mut queries: Vec<&String> = assignments.keys().collect();
+    queries.sort();
+    for query in queries {
+        let reference = &assignments[query];
+        let tax_id = match lineage_of(reference) {
+            Some(lineage) => lineage[0].get_tax_id(),
+            None if AssignmentStatus::is_unassigned_label(reference) => continue,
+            None => "NA".to_string()
+        };
+        let data = format!("{}\t{}\t{}\n", query, reference, tax_id);
+        write_to(&mut output, output_filename, data.as_bytes())?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::profile::tests::{example_tree, lineage};
+    use crate::test_util::TestDir;
+
+    #[test]
+    fn cami_profile_rows() {
+        let dir = TestDir::new();
+        let profile = dir.path("profile.cami");
+        let ranks: Vec<String> = ["strain", "species", "genus", "phylum", "superkingdom"].iter().map(|rank| rank.to_string()).collect();
+        write_cami_profile(&example_tree(), &ranks, "sample1", &profile).unwrap();
+        assert_eq!(std::fs::read_to_string(&profile).unwrap(), "\
+            @SampleID:sample1\n\
+            @Version:0.9.1\n\
+            @Ranks:superkingdom|phylum|genus|species|strain\n\
+            \n\
+            @@TAXID\tRANK\tTAXPATH\tTAXPATHSN\tPERCENTAGE\n\
+            2\tsuperkingdom\t2\tBacteria\t100.00000\n\
+            561\tgenus\t2||561\tBacteria||Escherichia\t75.00000\n\
+            590\tgenus\t2||590\tBacteria||Salmonella\t25.00000\n\
+            562\tspecies\t2||561|562\tBacteria||Escherichia|Escherichia coli\t75.00000\n\
+            83333\tstrain\t2||561|562|83333\tBacteria||Escherichia|Escherichia coli|Escherichia coli K-12\t25.00000\n");
+    }
+
+    #[test]
+    fn cami_binning_rows() {
+        let dir = TestDir::new();
+        let binning = dir.path("binning.cami");
+        let k12 = lineage(&[("strain", "Escherichia coli K-12", "83333"), ("species", "Escherichia coli", "562")]);
+        let lineages = HashMap::from([("NC_000913.3".to_string(), k12)]);
+        let assignments = HashMap::from([("read1".to_string(), "NC_000913.3".to_string()), ("read2".to_string(), "unknown".to_string()),
+                                        ("read3".to_string(), AssignmentStatus::Unassigned.label().to_string()),
+                                        ("read4".to_string(), AssignmentStatus::Unmapped.label().to_string())]);
+        write_cami_binning(&assignments, |reference| lineages.get(reference), "sample1", &binning).unwrap();
+        assert_eq!(std::fs::read_to_string(&binning).unwrap(), "\
+            @Version:0.9.0\n\
+            @SampleID:sample1\n\
+            \n\
+            @@SEQUENCEID\tBINID\tTAXID\n\
+            read1\tNC_000913.3\t83333\n\
+            read2\tunknown\tNA\n");
+    }
+}
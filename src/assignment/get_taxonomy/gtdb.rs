@@ -0,0 +1,162 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+
+use super::Lineage;
+use super::reference_names::strip_version;
+use crate::error::{open_file, MoraError, Result};
+
+// GTDB rank prefixes and the NCBI rank names they are reported as
+const GTDB_RANKS: [(&str, &str); 7] = [("d__", "superkingdom"), ("p__", "phylum"), ("c__", "class"), ("o__", "order"), 
+    ("f__", "family"), ("g__", "genus"), ("s__", "species")];
+
+/*
+    the GTDB taxonomy files to read: the file itself, or the bac120/ar53 (ar122 in older releases) taxonomy files of a directory
+*/
+pub(crate) fn gtdb_files(path: &str) -> Result<Vec<String>> {
+    if Path::new(path).is_file() {
+        return Ok(vec![path.to_string()]);
+    }
+    let mut files: Vec<String> = fs::read_dir(path).map_err(|e| MoraError::io(path, e))?.filter_map(|entry| {
+        let file_name = entry.ok()?.file_name().into_string().ok()?;
+        let is_taxonomy = (file_name.starts_with("bac120_taxonomy") || file_name.starts_with("ar53_taxonomy") 
+            || file_name.starts_with("ar122_taxonomy")) && file_name.ends_with(".tsv");
+        if is_taxonomy { Some(path.to_string() + "/" + &file_name) } else { None }
+    }).collect();
+    files.sort();
+    if files.is_empty() {
+        return Err(MoraError::invalid_value("--tax", path, "no GTDB taxonomy files (bac120_taxonomy*.tsv, ar53_taxonomy*.tsv) in the directory"));
+    }
+    Ok(files)
+}
+
+// GTDB accessions are prefixed with the database they come from (RS_ for RefSeq and GB_ for GenBank)
+fn strip_database_prefix(accession: &str) -> &str {
+    accession.strip_prefix("RS_").or_else(|| accession.strip_prefix("GB_")).unwrap_or(accession)
+}
+
+/*
+    Turn a GTDB taxonomy string (d__Bacteria;p__Pseudomonadota;...;s__Escherichia coli) into a lineage.
+    The TaxID is the most specific named taxon (e.g. s__Escherichia coli), as GTDB has no numerical TaxIDs.
+    OUTPUT: (TaxID, lineage in the same form build_taxonomy creates before the ranks are fixed)
+*/
+fn parse_gtdb_lineage(taxonomy: &str) -> Option<(String, Vec<Lineage>)> {
+    let mut ranks = Vec::new();
+    for taxon in taxonomy.trim().split(';') {
+        let taxon = taxon.trim();
+        if let Some((prefix, rank)) = GTDB_RANKS.iter().find(|(prefix, _)| taxon.starts_with(prefix)) {
+            let name = &taxon[prefix.len()..];
+            if !name.is_empty() {
+                ranks.push((taxon.to_string(), Lineage::new(rank.to_string(), name.to_string(), taxon.to_string())));
+            }
+        }
+    }
+    let (tax_id, leaf) = ranks.last()?.clone();
+    let mut lineage = vec![leaf];
+    lineage.extend(ranks.into_iter().rev().map(|(_, lineage)| lineage));
+    Some((tax_id, lineage))
+}
+
+// (<accession, TaxID>, <TaxID, lineage>)
+type GtdbLineages = (HashMap<String, String>, HashMap<String, Vec<Lineage>>);
+
+/*
+    files: GTDB taxonomy files with lines of the form accession<TAB>taxonomy string
+    accessions: the accessions to look up, with or without the RS_/GB_ prefix
+    ignore_versions: if an accession without a version also matches the versioned accessions of the files
+    OUTPUT: (<accession, TaxID>, <TaxID, lineage>)
+*/
+pub(crate) fn gtdb_accessions_2_lineage(files: Vec<String>, accessions: &HashSet<String>, ignore_versions: bool) -> Result<GtdbLineages> {
+    let mut accessions_2_tax = HashMap::new();
+    let mut lineage = HashMap::new();
+    let wanted: HashMap<&str, &String> = accessions.iter().map(|accession| (strip_database_prefix(accession), accession)).collect();
+
+    for file in files {
+        let f = open_file(&file)?;
+        for line in BufReader::new(f).lines() {
+            let line = line.map_err(|e| MoraError::io(&file, e))?;
+            let (accession, taxonomy) = match line.split_once('\t') {
+                Some(chunks) => chunks,
+                None => continue
+            };
+            let accession = strip_database_prefix(accession);
+            let exact = wanted.get(accession);
+            let unversioned = if ignore_versions { wanted.get(strip_version(accession)) } else { None };
+            if exact.is_none() && unversioned.is_none() {
+                continue;
+            }
+            if let Some((tax_id, assigned_lineage)) = parse_gtdb_lineage(taxonomy) {
+                if let Some(accession) = exact {
+                    accessions_2_tax.insert(accession.to_string(), tax_id.to_string());
+                }
+                if let Some(accession) = unversioned {
+                    accessions_2_tax.entry(accession.to_string()).or_insert_with(|| tax_id.to_string());
+                }
+                lineage.insert(tax_id, assigned_lineage);
+            }
+        }
+    }
+    Ok((accessions_2_tax, lineage))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TestDir;
+
+    // (rank, name, TaxID) of each entry of a lineage
+    fn entries(lineage: &[Lineage]) -> Vec<(String, String, String)> {
+        lineage.iter().map(|entry| (entry.get_rank(), entry.get_name(), entry.get_tax_id())).collect()
+    }
+
+    #[test]
+    fn lineages_end_at_the_most_specific_named_taxon() {
+        let (tax_id, lineage) = parse_gtdb_lineage("d__Archaea;p__Thermoproteota;c__;o__;f__;g__;s__").unwrap();
+        assert_eq!(tax_id, "p__Thermoproteota");
+        assert_eq!(entries(&lineage), vec![
+            ("phylum".to_string(), "Thermoproteota".to_string(), "p__Thermoproteota".to_string()),
+            ("phylum".to_string(), "Thermoproteota".to_string(), "p__Thermoproteota".to_string()),
+            ("superkingdom".to_string(), "Archaea".to_string(), "d__Archaea".to_string()),
+        ]);
+        assert!(parse_gtdb_lineage("d__;p__").is_none());
+    }
+
+    #[test]
+    fn accessions_are_matched_without_the_database_prefix() {
+        let dir = TestDir::new();
+        let file = dir.path("bac120_taxonomy.tsv");
+        fs::write(&file, "RS_GCF_000005845.2\td__Bacteria;p__Pseudomonadota;c__Gammaproteobacteria;o__Enterobacterales;f__Enterobacteriaceae;g__Escherichia;s__Escherichia coli\n\
+                          GB_GCA_000010.1\td__Bacteria;p__Bacillota;c__Bacilli;o__Lactobacillales;f__Streptococcaceae;g__Streptococcus;s__\n\
+                          RS_GCF_000020.3\td__Bacteria;p__Bacillota;c__Bacilli;o__Bacillales;f__Bacillaceae;g__Bacillus;s__Bacillus subtilis\n\
+                          RS_GCF_000030.1\td__Bacteria;p__Bacillota\n").unwrap();
+        let accessions = HashSet::from(["GCF_000005845.2".to_string(), "GB_GCA_000010.1".to_string(), "GCF_000020".to_string()]);
+
+        let (accessions_2_tax, lineage) = gtdb_accessions_2_lineage(vec![file.to_string()], &accessions, false).unwrap();
+        assert_eq!(accessions_2_tax, HashMap::from([("GCF_000005845.2".to_string(), "s__Escherichia coli".to_string()),
+                                                    ("GB_GCA_000010.1".to_string(), "g__Streptococcus".to_string())]));
+        let species = entries(&lineage["s__Escherichia coli"]);
+        assert_eq!(species.len(), 8);
+        assert_eq!(species[1], ("species".to_string(), "Escherichia coli".to_string(), "s__Escherichia coli".to_string()));
+        assert_eq!(species[7], ("superkingdom".to_string(), "Bacteria".to_string(), "d__Bacteria".to_string()));
+        assert!(!lineage.contains_key("p__Bacillota"));
+
+        let (accessions_2_tax, _) = gtdb_accessions_2_lineage(vec![file], &accessions, true).unwrap();
+        assert_eq!(accessions_2_tax["GCF_000020"], "s__Bacillus subtilis");
+    }
+
+    #[test]
+    fn directories_are_searched_for_the_taxonomy_files() {
+        let dir = TestDir::new();
+        for name in ["bac120_taxonomy_r220.tsv", "ar53_taxonomy_r220.tsv", "bac120_metadata_r220.tsv", "notes.txt"] {
+            fs::write(dir.path(name), "").unwrap();
+        }
+        let root = &dir.get_path();
+        assert_eq!(gtdb_files(root).unwrap(), vec![format!("{}/ar53_taxonomy_r220.tsv", root), format!("{}/bac120_taxonomy_r220.tsv", root)]);
+        assert_eq!(gtdb_files(&dir.path("notes.txt")).unwrap(), vec![dir.path("notes.txt")]);
+
+        fs::remove_file(dir.path("bac120_taxonomy_r220.tsv")).unwrap();
+        fs::remove_file(dir.path("ar53_taxonomy_r220.tsv")).unwrap();
+        assert!(matches!(gtdb_files(root), Err(MoraError::InvalidValue { .. })));
+    }
+}
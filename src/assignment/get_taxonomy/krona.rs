@@ -0,0 +1,80 @@
+use super::profile::{ProfileNode, ProfileSource, ProfileTree};
+use crate::error::{create_file, write_to, Result};
+
+const ROOT: &str = "1";
+
+// the Krona text input and the sunburst chart show the reads without a lineage as Unclassified and the abundances in percent
+impl ProfileSource {
+    // value of a taxon and its descendants
+    pub(super) fn clade_value(&self, tree: &ProfileTree, node: &ProfileNode) -> f32 {
+        match self {
+            ProfileSource::Reads => node.clade_reads as f32,
+            ProfileSource::Abundance => if tree.total_abundance > 0.0 { 100.0 * node.abundance / tree.total_abundance } else { 0.0 }
+        }
+    }
+
+    // value of a taxon itself, without its descendants
+    fn direct_value(&self, tree: &ProfileTree, node: &ProfileNode) -> f32 {
+        let children: f32 = node.children.iter().map(|child| self.clade_value(tree, &tree.nodes[child])).sum();
+        (self.clade_value(tree, node) - children).max(0.0)
+    }
+
+    pub(super) fn unclassified_value(&self, tree: &ProfileTree) -> f32 {
+        match self {
+            ProfileSource::Reads => tree.unclassified_reads as f32,
+            ProfileSource::Abundance => 0.0
+        }
+    }
+}
+
+/*
+Write the Krona text input, one line per taxon with a value of its own:
+value       name of the least specific taxon        ...     name of the taxon
+*/
+pub(super) fn write_krona_text(tree: &ProfileTree, source: ProfileSource, output_filename: &str) -> Result<()> {
+    let mut lines = Vec::new();
+    for (tax_id, node) in &tree.nodes {
+        let value = source.direct_value(tree, node);
+        if value <= 0.0 || tax_id == ROOT {
+            continue;
+        }
+        let names: Vec<&str> = tree.path(tax_id).iter().map(|t| tree.nodes[*t].name.as_str()).collect();
+        lines.push((value, names.join("\t")));
+    }
+    let unclassified = source.unclassified_value(tree);
+    if unclassified > 0.0 {
+        lines.push((unclassified, "Unclassified".to_string()));
+    }
+    lines.sort_by(|a, b| a.1.cmp(&b.1));
+
+    let mut output = create_file(output_filename)?;
+    for (value, lineage) in lines {
+        // rounded so that sums of abundances do not show float noise
+        write_to(&mut output, output_filename, format!("{}\t{}\n", (value * 10000.0).round() / 10000.0, lineage).as_bytes())?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::profile::tests::example_tree;
+    use crate::test_util::TestDir;
+
+    #[test]
+    fn krona_text_lines() {
+        let dir = TestDir::new();
+        let (reads, abundance) = (dir.path("reads.krona"), dir.path("abundance.krona"));
+        write_krona_text(&example_tree(), ProfileSource::Reads, &reads).unwrap();
+        write_krona_text(&example_tree(), ProfileSource::Abundance, &abundance).unwrap();
+        assert_eq!(std::fs::read_to_string(&reads).unwrap(), "\
+            4\tBacteria\tEscherichia\tEscherichia coli\n\
+            2\tBacteria\tEscherichia\tEscherichia coli\tEscherichia coli K-12\n\
+            2\tBacteria\tSalmonella\n\
+            2\tUnclassified\n");
+        assert_eq!(std::fs::read_to_string(&abundance).unwrap(), "\
+            50\tBacteria\tEscherichia\tEscherichia coli\n\
+            25\tBacteria\tEscherichia\tEscherichia coli\tEscherichia coli K-12\n\
+            25\tBacteria\tSalmonella\n");
+    }
+}
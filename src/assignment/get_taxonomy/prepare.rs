@@ -0,0 +1,198 @@
+use std::collections::{hash_map::Entry, HashMap, HashSet};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use flate2::read::MultiGzDecoder;
+use rayon::prelude::*;
+use rust_htslib::bam::{Reader, Read};
+
+use super::TaxonomyTree;
+use super::taxonomy_db::TaxonomyDb;
+use crate::error::{create_file, open_file, write_to, MoraError, Result};
+
+// number of lines of an accession2taxid file matched in parallel at a time
+const BATCH_LINES: usize = 100_000;
+
+// plain or gzipped text file
+fn open_text(file_name: &str) -> Result<Box<dyn BufRead + Send>> {
+    let f = open_file(file_name)?;
+    if file_name.ends_with(".gz") {
+        Ok(Box::new(BufReader::new(MultiGzDecoder::new(f))))
+    } else {
+        Ok(Box::new(BufReader::new(f)))
+    }
+}
+
+/*
+    The accessions of the references: the target names of a SAM/BAM/CRAM header or
+    the first token of the headers of a (gzipped) FASTA file
+*/
+fn reference_accessions(references_file: &str) -> Result<HashSet<String>> {
+    let mut accessions = HashSet::new();
+    if [".bam", ".sam", ".cram"].iter().any(|extension| references_file.ends_with(extension)) {
+        let bam = Reader::from_path(references_file).map_err(|e| MoraError::Alignment { path: references_file.to_string(),
+                                                                                         record: "file".to_string(), message: e.to_string() })?;
+        for name in bam.header().target_names() {
+            accessions.insert(String::from_utf8_lossy(name).to_string());
+        }
+    } else {
+        for line in open_text(references_file)?.lines() {
+            let line = line.map_err(|e| MoraError::io(references_file, e))?;
+            if let Some(header) = line.strip_prefix('>') {
+                if let Some(accession) = header.split_whitespace().next() {
+                    accessions.insert(accession.to_string());
+                }
+            }
+        }
+    }
+    Ok(accessions)
+}
+
+/*
+    A line of an NCBI accession2taxid file: accession    accession.version   taxid   gi
+    OUTPUT: the wanted accessions (with and without version) of the line with their TaxID
+*/
+fn match_line(line: &str, accessions: &HashSet<String>) -> Vec<(String, String)> {
+    let chunks: Vec<&str> = line.split('\t').collect();
+    if chunks.len() < 3 || chunks[2].is_empty() || !chunks[2].chars().all(|c| c.is_ascii_digit()) {
+        return Vec::new(); // header or malformed line
+    }
+    chunks[..2].iter().filter(|accession| accessions.contains(**accession))
+                .map(|accession| (accession.to_string(), chunks[2].to_string())).collect()
+}
+
+/*
+    Scan the accession2taxid files in parallel, each file in batches of batch_lines lines that are matched in parallel.
+    The scan stops as soon as every accession has been found.
+    OUTPUT: <accession, TaxID>
+*/
+fn find_tax_ids(accessions: &HashSet<String>, accession2taxid_files: &[String], batch_lines: usize) -> Result<HashMap<String, String>> {
+    let found = Mutex::new(HashMap::new());
+    let remaining = AtomicUsize::new(accessions.len());
+
+    accession2taxid_files.par_iter().try_for_each(|file_name| {
+        let mut lines = open_text(file_name)?.lines();
+        let mut scanned = 0;
+        while remaining.load(Ordering::Relaxed) > 0 {
+            let batch = lines.by_ref().take(batch_lines).collect::<std::io::Result<Vec<String>>>().map_err(|e| MoraError::io(file_name, e))?;
+            if batch.is_empty() {
+                break;
+            }
+            scanned += batch.len();
+            let hits: Vec<(String, String)> = batch.par_iter().flat_map_iter(|line| match_line(line, accessions)).collect();
+            if !hits.is_empty() {
+                let mut found = found.lock().unwrap();
+                for (accession, tax_id) in hits {
+                    if let Entry::Vacant(entry) = found.entry(accession) {
+                        entry.insert(tax_id);
+                        remaining.fetch_sub(1, Ordering::Relaxed);
+                    }
+                }
+            }
+        }
+        println!("scanned {} lines of {}", scanned, file_name);
+        Ok(())
+    })?;
+    Ok(found.into_inner().unwrap())
+}
+
+/*
+    Find the TaxIDs of the references and write them as accessionsTaxIDs.tab or, with a tax directory
+    containing the NCBI dump files, directly as a binary taxonomy database
+
+    references_file: reference FASTA file or SAM/BAM file whose header has the references
+    accession2taxid_files: NCBI accession2taxid files, plain or gzipped
+    output_filename: path for accessionsTaxIDs.tab or the taxonomy database
+    tax_dir: directory with nodes.dmp, names.dmp (and optionally merged.dmp and delnodes.dmp) for the database
+*/
+pub fn prepare(references_file: String, accession2taxid_files: Vec<String>, output_filename: String, tax_dir: Option<String>) -> Result<()> {
+    let accessions = reference_accessions(&references_file)?;
+    println!("number of references in {}: {}", references_file, accessions.len());
+
+    let found = find_tax_ids(&accessions, &accession2taxid_files, BATCH_LINES)?;
+    println!("number of accessions found: {}, not found: {}", found.len(), accessions.len() - found.len());
+
+    match tax_dir {
+        Some(tax_dir) => {
+            let tree = TaxonomyTree::from_dmp(tax_dir.to_string() + "/nodes.dmp", tax_dir.to_string() + "/names.dmp",
+                                                tax_dir.to_string() + "/merged.dmp", tax_dir.to_string() + "/delnodes.dmp")?;
+            TaxonomyDb::write(&tree, &found, output_filename)
+        },
+        None => {
+            let mut sorted: Vec<(&String, &String)> = found.iter().collect();
+            sorted.sort();
+            let mut output = BufWriter::new(create_file(&output_filename)?);
+            for (accession, tax_id) in sorted {
+                write_to(&mut output, &output_filename, format!("{}\t{}\n", accession, tax_id).as_bytes())?;
+            }
+            output.flush().map_err(|e| MoraError::io(&output_filename, e))?;
+            println!("accessions and their TaxIDs written to {}", output_filename);
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::{write::GzEncoder, Compression};
+    use crate::test_util::TestDir;
+
+    const ACCESSION2TAXID: &str = "accession\taccession.version\ttaxid\tgi\n\
+                                   NC_000913\tNC_000913.3\t511145\t556503834\n\
+                                   NZ_CP012345\tNZ_CP012345.1\t562\t0\n\
+                                   NZ_CP099999\tNZ_CP099999.2\tNA\t0\n";
+
+    fn set(items: &[&str]) -> HashSet<String> {
+        items.iter().map(|item| item.to_string()).collect()
+    }
+
+    fn write_gzipped(path: &str, contents: &str) {
+        let mut encoder = GzEncoder::new(std::fs::File::create(path).unwrap(), Compression::default());
+        encoder.write_all(contents.as_bytes()).unwrap();
+        encoder.finish().unwrap();
+    }
+
+    #[test]
+    fn lines_are_matched_with_and_without_version() {
+        let accessions = set(&["NC_000913.3", "NZ_CP012345"]);
+        assert_eq!(match_line("NC_000913\tNC_000913.3\t511145\t1", &accessions), vec![("NC_000913.3".to_string(), "511145".to_string())]);
+        assert_eq!(match_line("NZ_CP012345\tNZ_CP012345.1\t562\t0", &accessions), vec![("NZ_CP012345".to_string(), "562".to_string())]);
+        assert!(match_line("accession\taccession.version\ttaxid\tgi", &set(&["accession"])).is_empty());
+        assert!(match_line("NC_000913\tNC_000913.3", &accessions).is_empty());
+    }
+
+    #[test]
+    fn gzipped_files_are_scanned() {
+        let dir = TestDir::new();
+        let file = dir.path("nucl_gb.accession2taxid.gz");
+        write_gzipped(&file, ACCESSION2TAXID);
+        let found = find_tax_ids(&set(&["NC_000913.3", "NZ_CP012345", "NZ_CP099999.2", "NC_000001.1"]), &[file], 2).unwrap();
+        assert_eq!(found, HashMap::from([("NC_000913.3".to_string(), "511145".to_string()), ("NZ_CP012345".to_string(), "562".to_string())]));
+    }
+
+    #[test]
+    fn scan_stops_once_every_accession_is_found() {
+        let dir = TestDir::new();
+        let file = dir.path("nucl_wgs.accession2taxid");
+        // the line after the first batch cannot be read
+        let mut contents = ACCESSION2TAXID.as_bytes().to_vec();
+        contents.extend_from_slice(b"\xff\xfe\t\xff\n");
+        std::fs::write(&file, contents).unwrap();
+
+        let found = find_tax_ids(&set(&["NC_000913.3", "NZ_CP012345"]), &[file.to_string()], 4).unwrap();
+        assert_eq!(found.len(), 2);
+        assert!(matches!(find_tax_ids(&set(&["NC_000913.3", "NC_000001.1"]), &[file], 4), Err(MoraError::Io { .. })));
+    }
+
+    #[test]
+    fn prepare_writes_the_accessions_of_a_fasta_file() {
+        let dir = TestDir::new();
+        let (references, accession2taxid, output) = (dir.path("references.fa.gz"), dir.path("nucl_gb.accession2taxid"), dir.path("accessionsTaxIDs.tab"));
+        write_gzipped(&references, ">NZ_CP012345 Escherichia coli plasmid\nACGT\n>NC_000913.3 Escherichia coli K-12\nACGT\n>NC_000001.1\nACGT\n");
+        std::fs::write(&accession2taxid, ACCESSION2TAXID).unwrap();
+
+        prepare(references, vec![accession2taxid], output.to_string(), None).unwrap();
+        assert_eq!(std::fs::read_to_string(&output).unwrap(), "NC_000913.3\t511145\nNZ_CP012345\t562\n");
+    }
+}
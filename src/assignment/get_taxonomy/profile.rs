@@ -0,0 +1,327 @@
+use std::collections::HashMap;
+
+use super::{rank_matches, Lineage};
+use crate::error::{create_file, write_to, Result};
+
+const ROOT: &str = "1";
+
+/*
+    What a profile output is computed from
+
+    Reads: the assigned reads of the per-read lineages
+    Abundance: the estimated abundances aggregated over the lineages of the references
+*/
+#[derive(Clone, Copy, Default)]
+pub enum ProfileSource {
+    #[default]
+    Reads,
+    Abundance,
+}
+
+impl ProfileSource {
+    pub fn new(source: &str) -> ProfileSource {
+        match source {
+            "abundance" => ProfileSource::Abundance,
+            _ => ProfileSource::Reads
+        }
+    }
+}
+
+/*
+    A node of the taxonomic profile
+
+    name: scientific name of the taxon
+    rank: rank of the taxon
+    parent: TaxID of the parent, None for the root
+    children: TaxIDs of the children
+    direct_reads: reads assigned to a reference of exactly this taxon
+    clade_reads: reads assigned to a reference of this taxon or one of its descendants
+    abundance: summed abundance of the references of this taxon and its descendants
+*/
+pub(super) struct ProfileNode {
+    pub(super) name: String,
+    pub(super) rank: String,
+    pub(super) parent: Option<String>,
+    pub(super) children: Vec<String>,
+    pub(super) direct_reads: usize,
+    pub(super) clade_reads: usize,
+    pub(super) abundance: f32,
+}
+
+/*
+    The part of the taxonomy covered by the assigned reads and the estimated abundances, with the
+    read counts and abundances rolled up from the references to the root.
+
+    nodes: <TaxID, node>
+    unclassified_reads: reads without a lineage (unmapped, unassigned or with an unknown reference)
+    total_abundance: the abundance of all references with a lineage
+*/
+pub(super) struct ProfileTree {
+    pub(super) nodes: HashMap<String, ProfileNode>,
+    pub(super) unclassified_reads: usize,
+    pub(super) total_abundance: f32,
+}
+
+impl ProfileTree {
+    pub(super) fn new() -> ProfileTree {
+        let mut nodes = HashMap::new();
+        nodes.insert(ROOT.to_string(), ProfileNode {
+            name: "root".to_string(), rank: "no rank".to_string(), parent: None, children: Vec::new(),
+            direct_reads: 0, clade_reads: 0, abundance: 0.0
+        });
+        ProfileTree { nodes, unclassified_reads: 0, total_abundance: 0.0 }
+    }
+
+    /*
+        Insert the taxa of a lineage (the key followed by the ranks, see fix_empty_ranks) below the root
+        OUTPUT: the path of TaxIDs from the root to the most specific taxon, empty if nothing is known about the lineage
+    */
+    fn insert(&mut self, lineage: &[Lineage]) -> Vec<String> {
+        let mut taxa: Vec<&Lineage> = lineage.iter().skip(1).rev().collect();
+        if let Some(key) = lineage.first() {
+            taxa.push(key);
+        }
+
+        let mut path = vec![ROOT.to_string()];
+        for taxon in taxa {
+            let tax_id = taxon.get_tax_id();
+            if tax_id == "NA" || path.contains(&tax_id) {
+                continue;
+            }
+            let parent = path.last().unwrap().to_string();
+            if !self.nodes.contains_key(&tax_id) {
+                self.nodes.insert(tax_id.to_string(), ProfileNode {
+                    name: taxon.get_name(), rank: taxon.get_rank(), parent: Some(parent.to_string()), children: Vec::new(),
+                    direct_reads: 0, clade_reads: 0, abundance: 0.0
+                });
+                self.nodes.get_mut(&parent).unwrap().children.push(tax_id.to_string());
+            }
+            path.push(tax_id);
+        }
+        if path.len() == 1 {
+            path.clear();
+        }
+        path
+    }
+
+    // add reads assigned to a reference with the given lineage
+    pub(super) fn add_reads(&mut self, lineage: Option<&Vec<Lineage>>, reads: usize) {
+        let path = lineage.map(|lineage| self.insert(lineage)).unwrap_or_default();
+        if path.is_empty() {
+            self.unclassified_reads += reads;
+            return;
+        }
+        for tax_id in &path {
+            self.nodes.get_mut(tax_id).unwrap().clade_reads += reads;
+        }
+        self.nodes.get_mut(path.last().unwrap()).unwrap().direct_reads += reads;
+    }
+
+    // add the estimated abundance of a reference with the given lineage, references without a lineage are left out
+    pub(super) fn add_abundance(&mut self, lineage: Option<&Vec<Lineage>>, abundance: f32) {
+        let path = lineage.map(|lineage| self.insert(lineage)).unwrap_or_default();
+        if path.is_empty() || abundance <= 0.0 {
+            return;
+        }
+        for tax_id in &path {
+            self.nodes.get_mut(tax_id).unwrap().abundance += abundance;
+        }
+        self.total_abundance += abundance;
+    }
+
+    // children of a taxon, the largest first according to the given value
+    pub(super) fn sorted_children(&self, tax_id: &str, value: impl Fn(&ProfileNode) -> f32) -> Vec<&String> {
+        let mut children: Vec<&String> = self.nodes[tax_id].children.iter().collect();
+        children.sort_by(|a, b| value(&self.nodes[*b]).total_cmp(&value(&self.nodes[*a])).then(a.cmp(b)));
+        children
+    }
+
+    // the TaxIDs from the root to the taxon, without the root
+    pub(super) fn path(&self, tax_id: &str) -> Vec<&String> {
+        let mut path = Vec::new();
+        let mut current = tax_id;
+        while let Some(parent) = &self.nodes[current].parent {
+            path.push(self.nodes.get_key_value(current).unwrap().0);
+            current = parent;
+        }
+        path.reverse();
+        path
+    }
+}
+
+// one letter Kraken2 code of the rank, None for ranks without a code
+fn kraken_rank_code(rank: &str) -> Option<&'static str> {
+    let codes = [("superkingdom", "D"), ("kingdom", "K"), ("phylum", "P"), ("class", "C"), ("order", "O"),
+                    ("family", "F"), ("genus", "G"), ("species", "S")];
+    codes.iter().find(|(name, _)| rank_matches(name, rank)).map(|(_, code)| *code)
+}
+
+// ranks without a code get the code of their parent followed by their depth below it, e.g. S1 for a strain
+fn child_rank_code(rank: &str, parent_code: &str) -> String {
+    match kraken_rank_code(rank) {
+        Some(code) => code.to_string(),
+        None => {
+            let letter: String = parent_code.chars().filter(|c| c.is_alphabetic()).collect();
+            let depth: usize = parent_code.trim_start_matches(char::is_alphabetic).parse().unwrap_or(0);
+            format!("{}{}", letter, depth + 1)
+        }
+    }
+}
+
+/*
+Write a Kraken2-style report with the columns:
+percentage of reads in the clade      reads in the clade      reads assigned directly     rank code       TaxID       indented name
+*/
+pub(super) fn write_kraken_report(tree: &ProfileTree, output_filename: &str) -> Result<()> {
+    let mut output = create_file(output_filename)?;
+    let total = tree.nodes[ROOT].clade_reads + tree.unclassified_reads;
+    let percentage = |reads: usize| if total == 0 { 0.0 } else { 100.0 * reads as f32 / total as f32 };
+
+    if tree.unclassified_reads > 0 {
+        let data = format!("{:.2}\t{}\t{}\tU\t0\tunclassified\n", percentage(tree.unclassified_reads), tree.unclassified_reads, tree.unclassified_reads);
+        write_to(&mut output, output_filename, data.as_bytes())?;
+    }
+
+    // depth first, the largest clades first
+    let mut stack = vec![(ROOT, "R".to_string(), 0)];
+    while let Some((tax_id, code, depth)) = stack.pop() {
+        let node = &tree.nodes[tax_id];
+        if node.clade_reads == 0 {
+            continue;
+        }
+        let data = format!("{:.2}\t{}\t{}\t{}\t{}\t{}{}\n", percentage(node.clade_reads), node.clade_reads, node.direct_reads,
+                            code, tax_id, "  ".repeat(depth), node.name);
+        write_to(&mut output, output_filename, data.as_bytes())?;
+        for child in tree.sorted_children(tax_id, |node| node.clade_reads as f32).into_iter().rev() {
+            stack.push((child, child_rank_code(&tree.nodes[child].rank, &code), depth + 1));
+        }
+    }
+    Ok(())
+}
+
+// MetaPhlAn prefix of the rank, None for ranks that are not part of MetaPhlAn clade names
+fn metaphlan_prefix(rank: &str) -> Option<&'static str> {
+    let prefixes = [("superkingdom", "k"), ("phylum", "p"), ("class", "c"), ("order", "o"), ("family", "f"),
+                    ("genus", "g"), ("species", "s"), ("strain", "t")];
+    prefixes.iter().find(|(name, _)| rank_matches(name, rank)).map(|(_, prefix)| *prefix)
+}
+
+/*
+Write a MetaPhlAn-style profile of the relative abundances (in percent) with the columns:
+clade name (k__...|p__...|...)      TaxIDs of the clade name        relative abundance      additional species
+Clades are grouped by their depth and sorted by abundance within a depth.
+*/
+pub(super) fn write_metaphlan_profile(tree: &ProfileTree, sample_id: &str, output_filename: &str) -> Result<()> {
+    let mut clades = Vec::new();
+    for (tax_id, node) in &tree.nodes {
+        if node.abundance <= 0.0 || metaphlan_prefix(&node.rank).is_none() {
+            continue;
+        }
+        let path: Vec<&String> = tree.path(tax_id).into_iter().filter(|t| metaphlan_prefix(&tree.nodes[*t].rank).is_some()).collect();
+        let names: Vec<String> = path.iter().map(|t| format!("{}__{}", metaphlan_prefix(&tree.nodes[*t].rank).unwrap(), tree.nodes[*t].name.replace(' ', "_"))).collect();
+        let tax_ids: Vec<&str> = path.iter().map(|t| t.as_str()).collect();
+        clades.push((path.len(), node.abundance, names.join("|"), tax_ids.join("|")));
+    }
+    clades.sort_by(|a, b| a.0.cmp(&b.0).then(b.1.total_cmp(&a.1)).then(a.2.cmp(&b.2)));
+
+    let relative = |abundance: f32| if tree.total_abundance > 0.0 { 100.0 * abundance / tree.total_abundance } else { 0.0 };
+    let mut output = create_file(output_filename)?;
+    write_to(&mut output, output_filename, b"#MORA taxonomic profile\n")?;
+    write_to(&mut output, output_filename, format!("#SampleID\t{}\n", sample_id).as_bytes())?;
+    write_to(&mut output, output_filename, b"#clade_name\tNCBI_tax_id\trelative_abundance\tadditional_species\n")?;
+    for (_, abundance, names, tax_ids) in clades {
+        let data = format!("{}\t{}\t{:.5}\t\n", names, tax_ids, relative(abundance));
+        write_to(&mut output, output_filename, data.as_bytes())?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+pub(super) mod tests {
+    use super::*;
+    use crate::test_util::TestDir;
+
+    // a lineage as built for the output: the key (the first taxon) followed by the taxa at the ranks strain, species, genus, superkingdom
+    pub(in super::super) fn lineage(taxa: &[(&str, &str, &str)]) -> Vec<Lineage> {
+        let mut lineage: Vec<Lineage> = ["strain", "species", "genus", "superkingdom"].iter()
+            .map(|rank| match taxa.iter().find(|(taxon_rank, _, _)| taxon_rank == rank) {
+                Some((rank, name, tax_id)) => Lineage::new(rank.to_string(), name.to_string(), tax_id.to_string()),
+                None => Lineage::new(rank.to_string(), "NA".to_string(), "NA".to_string())
+            }).collect();
+        let (rank, name, tax_id) = taxa[0];
+        lineage.insert(0, Lineage::new(rank.to_string(), name.to_string(), tax_id.to_string()));
+        lineage
+    }
+
+    /*
+        E. coli K-12: 2 reads and an abundance of 0.25, E. coli: 4 reads and 0.5, Salmonella (a genus): 2 reads and 0.25,
+        2 reads without a lineage and an abundance of 0.5 of a reference without a lineage
+    */
+    pub(in super::super) fn example_tree() -> ProfileTree {
+        let bacteria = ("superkingdom", "Bacteria", "2");
+        let escherichia = ("genus", "Escherichia", "561");
+        let k12 = lineage(&[("strain", "Escherichia coli K-12", "83333"), ("species", "Escherichia coli", "562"), escherichia, bacteria]);
+        let coli = lineage(&[("species", "Escherichia coli", "562"), escherichia, bacteria]);
+        let salmonella = lineage(&[("genus", "Salmonella", "590"), bacteria]);
+
+        let mut tree = ProfileTree::new();
+        for (lineage, reads, abundance) in [(Some(&k12), 2, 0.25), (Some(&coli), 4, 0.5), (Some(&salmonella), 2, 0.25), (None, 2, 0.5)] {
+            tree.add_reads(lineage, reads);
+            tree.add_abundance(lineage, abundance);
+        }
+        tree
+    }
+
+    #[test]
+    fn reads_and_abundances_are_rolled_up_to_the_root() {
+        let tree = example_tree();
+        let node = |tax_id: &str| &tree.nodes[tax_id];
+        assert_eq!((node("1").clade_reads, node("1").direct_reads, tree.unclassified_reads), (8, 0, 2));
+        assert_eq!((node("562").clade_reads, node("562").direct_reads, node("83333").direct_reads), (6, 4, 2));
+        assert_eq!((node("561").abundance, node("2").abundance, tree.total_abundance), (0.75, 1.0, 1.0));
+        assert_eq!(tree.path("83333"), vec!["2", "561", "562", "83333"]);
+    }
+
+    #[test]
+    fn kraken_report_rows() {
+        let dir = TestDir::new();
+        let report = dir.path("kraken.tsv");
+        write_kraken_report(&example_tree(), &report).unwrap();
+        assert_eq!(std::fs::read_to_string(&report).unwrap(), "\
+            20.00\t2\t2\tU\t0\tunclassified\n\
+            80.00\t8\t0\tR\t1\troot\n\
+            80.00\t8\t0\tD\t2\t  Bacteria\n\
+            60.00\t6\t0\tG\t561\t    Escherichia\n\
+            60.00\t6\t4\tS\t562\t      Escherichia coli\n\
+            20.00\t2\t2\tS1\t83333\t        Escherichia coli K-12\n\
+            20.00\t2\t2\tG\t590\t    Salmonella\n");
+    }
+
+    #[test]
+    fn metaphlan_profile_rows() {
+        let dir = TestDir::new();
+        let profile = dir.path("metaphlan.tsv");
+        write_metaphlan_profile(&example_tree(), "sample1", &profile).unwrap();
+        assert_eq!(std::fs::read_to_string(&profile).unwrap(), "\
+            #MORA taxonomic profile\n\
+            #SampleID\tsample1\n\
+            #clade_name\tNCBI_tax_id\trelative_abundance\tadditional_species\n\
+            k__Bacteria\t2\t100.00000\t\n\
+            k__Bacteria|g__Escherichia\t2|561\t75.00000\t\n\
+            k__Bacteria|g__Salmonella\t2|590\t25.00000\t\n\
+            k__Bacteria|g__Escherichia|s__Escherichia_coli\t2|561|562\t75.00000\t\n\
+            k__Bacteria|g__Escherichia|s__Escherichia_coli|t__Escherichia_coli_K-12\t2|561|562|83333\t25.00000\t\n");
+    }
+
+    #[test]
+    fn empty_profiles_have_no_rows() {
+        let dir = TestDir::new();
+        let (report, profile) = (dir.path("kraken.tsv"), dir.path("metaphlan.tsv"));
+        let mut tree = ProfileTree::new();
+        tree.add_abundance(None, 1.0);
+        write_kraken_report(&tree, &report).unwrap();
+        write_metaphlan_profile(&tree, "sample1", &profile).unwrap();
+        assert_eq!(std::fs::read_to_string(&report).unwrap(), "");
+        assert_eq!(std::fs::read_to_string(&profile).unwrap().lines().count(), 3);
+    }
+}
@@ -0,0 +1,189 @@
+use std::collections::HashMap;
+
+use super::Lineage;
+use super::profile::ProfileSource;
+use crate::assignment::AssignmentStatus;
+use crate::error::{create_file, write_to, Result};
+
+/*
+    Reads (or estimated reads) to be counted at every rank
+
+    assigned: (lineage of the reference, None if the reference has no lineage, number of reads)
+    unassigned: mapped reads that were not assigned to a reference
+    unmapped: reads without an alignment
+*/
+pub(super) struct RankCounts<'a> {
+    assigned: Vec<(Option<&'a Vec<Lineage>>, f32)>,
+    unassigned: f32,
+    unmapped: f32,
+}
+
+impl<'a> RankCounts<'a> {
+    // from the per-read lineages: <query name, (reference name, lineage)>
+    pub(super) fn from_reads(assignments2lineage: &'a HashMap<String, (String, Vec<Lineage>)>) -> RankCounts<'a> {
+        let mut counts = RankCounts { assigned: Vec::new(), unassigned: 0.0, unmapped: 0.0 };
+        for (reference, lineage) in assignments2lineage.values() {
+            if reference == AssignmentStatus::Unmapped.label() {
+                counts.unmapped += 1.0;
+            } else if AssignmentStatus::is_unassigned_label(reference) {
+                counts.unassigned += 1.0;
+            } else {
+                counts.assigned.push((Some(lineage), 1.0));
+            }
+        }
+        counts
+    }
+
+    /*
+        from the estimated abundances, the abundance of a reference is turned into its share of the mapped reads
+        reference_abundance: <reference name, estimated abundance>
+        lineage_of: the lineage of a reference name
+        assignments: <query name, reference name> for the number of mapped and unmapped reads
+    */
+    pub(super) fn from_abundance(reference_abundance: &HashMap<String, f32>, lineage_of: impl Fn(&String) -> Option<&'a Vec<Lineage>>,
+                                    assignments: &HashMap<String, String>) -> RankCounts<'a> {
+        let unmapped = assignments.values().filter(|reference| *reference == AssignmentStatus::Unmapped.label()).count() as f32;
+        let mapped = assignments.len() as f32 - unmapped;
+        let total_abundance: f32 = reference_abundance.values().filter(|abundance| **abundance > 0.0).sum();
+
+        let mut counts = RankCounts { assigned: Vec::new(), unassigned: 0.0, unmapped };
+        if total_abundance > 0.0 {
+            for (reference, abundance) in reference_abundance {
+                if *abundance > 0.0 {
+                    counts.assigned.push((lineage_of(reference), mapped * abundance / total_abundance));
+                }
+            }
+        }
+        counts
+    }
+}
+
+/*
+Write the reads counted per taxon at every rank with the columns:
+Rank    TaxID   Name    Reads (or EstimatedReads)   PercentOfTotal  PercentOfMapped     PercentOfAssigned
+Every rank also has a NA row for the assigned reads without a taxon at that rank and UNASSIGNED and UNMAPPED rows,
+percentages that do not apply to a row are NA.
+*/
+pub(super) fn write_rank_counts(counts: &RankCounts, source: ProfileSource, ranks: &[String], output_filename: &str) -> Result<()> {
+    let assigned: f32 = counts.assigned.iter().map(|(_, reads)| reads).sum();
+    let mapped = assigned + counts.unassigned;
+    let total = mapped + counts.unmapped;
+    let percentage = |reads: f32, of: f32| if of > 0.0 { format!("{:.4}", 100.0 * reads / of) } else { "NA".to_string() };
+    let format_reads = |reads: f32| match source {
+        ProfileSource::Reads => reads.to_string(),
+        ProfileSource::Abundance => format!("{:.2}", reads)
+    };
+
+    let mut output = create_file(output_filename)?;
+    let reads_header = match source {
+        ProfileSource::Reads => "Reads",
+        ProfileSource::Abundance => "EstimatedReads"
+    };
+    write_to(&mut output, output_filename, format!("Rank\tTaxID\tName\t{}\tPercentOfTotal\tPercentOfMapped\tPercentOfAssigned\n", reads_header).as_bytes())?;
+
+    for (i, rank) in ranks.iter().enumerate() {
+        // <TaxID, (name, reads)>, the lineages hold the key before the ranks
+        let mut taxa: HashMap<String, (String, f32)> = HashMap::new();
+        let mut na = 0.0;
+        for (lineage, reads) in &counts.assigned {
+            match lineage.and_then(|lineage| lineage.get(i + 1)).filter(|taxon| taxon.get_tax_id() != "NA") {
+                Some(taxon) => taxa.entry(taxon.get_tax_id()).or_insert_with(|| (taxon.get_name(), 0.0)).1 += reads,
+                None => na += reads
+            }
+        }
+        let mut rows: Vec<(String, String, f32)> = taxa.into_iter().map(|(tax_id, (name, reads))| (tax_id, name, reads)).collect();
+        rows.sort_by(|a, b| b.2.total_cmp(&a.2).then(a.0.cmp(&b.0)));
+
+        for (tax_id, name, reads) in rows {
+            let data = format!("{}\t{}\t{}\t{}\t{}\t{}\t{}\n", rank, tax_id, name, format_reads(reads), percentage(reads, total), percentage(reads, mapped), percentage(reads, assigned));
+            write_to(&mut output, output_filename, data.as_bytes())?;
+        }
+        let data = format!("{}\tNA\tNA\t{}\t{}\t{}\t{}\n", rank, format_reads(na), percentage(na, total), percentage(na, mapped), percentage(na, assigned));
+        write_to(&mut output, output_filename, data.as_bytes())?;
+        let data = format!("{}\tUNASSIGNED\tUNASSIGNED\t{}\t{}\t{}\tNA\n", rank, format_reads(counts.unassigned), percentage(counts.unassigned, total), percentage(counts.unassigned, mapped));
+        write_to(&mut output, output_filename, data.as_bytes())?;
+        let data = format!("{}\tUNMAPPED\tUNMAPPED\t{}\t{}\tNA\tNA\n", rank, format_reads(counts.unmapped), percentage(counts.unmapped, total));
+        write_to(&mut output, output_filename, data.as_bytes())?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::create_na_lineage;
+    use super::super::profile::tests::lineage;
+    use crate::test_util::TestDir;
+
+    const RANKS: [&str; 4] = ["strain", "species", "genus", "superkingdom"];
+
+    // the written rows of a rank
+    fn rows(counts: &RankCounts, source: ProfileSource, rank: &str) -> Vec<String> {
+        let dir = TestDir::new();
+        let output = dir.path("rank_counts.tsv");
+        let ranks: Vec<String> = RANKS.iter().map(|rank| rank.to_string()).collect();
+        write_rank_counts(counts, source, &ranks, &output).unwrap();
+        std::fs::read_to_string(&output).unwrap().lines().filter(|line| line.starts_with(&format!("{}\t", rank))).map(|line| line.to_string()).collect()
+    }
+
+    fn lineages() -> HashMap<String, Vec<Lineage>> {
+        HashMap::from([
+            ("NC_000913.3".to_string(), lineage(&[("species", "Escherichia coli", "562"), ("genus", "Escherichia", "561")])),
+            ("NZ_CP000001.1".to_string(), lineage(&[("genus", "Salmonella", "590")])),
+        ])
+    }
+
+    #[test]
+    fn rank_counts_of_the_reads() {
+        let lineages = lineages();
+        let lineage_of = |reference: &str| lineages.get(reference).cloned().unwrap_or_else(|| create_na_lineage(RANKS.len()));
+        let assignments2lineage: HashMap<String, (String, Vec<Lineage>)> = [("q1", "NC_000913.3"), ("q2", "NC_000913.3"), ("q3", "NZ_CP000001.1"),
+                ("q4", "unknown"), ("q5", "UNASSIGNED"), ("q6", "REFERENCE_FILTERED"), ("q7", "UNMAPPED"), ("q8", "UNMAPPED")].iter()
+            .map(|(query, reference)| (query.to_string(), (reference.to_string(), lineage_of(reference)))).collect();
+        let counts = RankCounts::from_reads(&assignments2lineage);
+
+        assert_eq!(rows(&counts, ProfileSource::Reads, "species"), vec![
+            "species\t562\tEscherichia coli\t2\t25.0000\t33.3333\t50.0000",
+            "species\tNA\tNA\t2\t25.0000\t33.3333\t50.0000",
+            "species\tUNASSIGNED\tUNASSIGNED\t2\t25.0000\t33.3333\tNA",
+            "species\tUNMAPPED\tUNMAPPED\t2\t25.0000\tNA\tNA",
+        ]);
+        assert_eq!(rows(&counts, ProfileSource::Reads, "genus"), vec![
+            "genus\t561\tEscherichia\t2\t25.0000\t33.3333\t50.0000",
+            "genus\t590\tSalmonella\t1\t12.5000\t16.6667\t25.0000",
+            "genus\tNA\tNA\t1\t12.5000\t16.6667\t25.0000",
+            "genus\tUNASSIGNED\tUNASSIGNED\t2\t25.0000\t33.3333\tNA",
+            "genus\tUNMAPPED\tUNMAPPED\t2\t25.0000\tNA\tNA",
+        ]);
+    }
+
+    #[test]
+    fn rank_counts_estimated_from_the_abundances() {
+        let lineages = lineages();
+        let abundance = HashMap::from([("NC_000913.3".to_string(), 0.5), ("NZ_CP000001.1".to_string(), 0.25), ("unknown".to_string(), 0.25),
+                                        ("NC_000001.1".to_string(), 0.0)]);
+        let assignments: HashMap<String, String> = [("q1", "NC_000913.3"), ("q2", "NC_000913.3"), ("q3", "UNASSIGNED"), ("q4", "unknown"),
+                                                    ("q5", "UNMAPPED"), ("q6", "UNMAPPED"), ("q7", "NC_000913.3"), ("q8", "NZ_CP000001.1")].iter()
+            .map(|(query, reference)| (query.to_string(), reference.to_string())).collect();
+        let counts = RankCounts::from_abundance(&abundance, |reference| lineages.get(reference), &assignments);
+
+        // the 6 mapped reads are split by abundance, so none of them is unassigned
+        assert_eq!(rows(&counts, ProfileSource::Abundance, "species"), vec![
+            "species\t562\tEscherichia coli\t3.00\t37.5000\t50.0000\t50.0000",
+            "species\tNA\tNA\t3.00\t37.5000\t50.0000\t50.0000",
+            "species\tUNASSIGNED\tUNASSIGNED\t0.00\t0.0000\t0.0000\tNA",
+            "species\tUNMAPPED\tUNMAPPED\t2.00\t25.0000\tNA\tNA",
+        ]);
+    }
+
+    #[test]
+    fn empty_rank_counts_have_no_percentages() {
+        let empty = HashMap::new();
+        let counts = RankCounts::from_reads(&empty);
+        assert_eq!(rows(&counts, ProfileSource::Reads, "genus"), vec![
+            "genus\tNA\tNA\t0\tNA\tNA\tNA",
+            "genus\tUNASSIGNED\tUNASSIGNED\t0\tNA\tNA\tNA",
+            "genus\tUNMAPPED\tUNMAPPED\t0\tNA\tNA\tNA",
+        ]);
+    }
+}
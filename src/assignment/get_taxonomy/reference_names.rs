@@ -0,0 +1,259 @@
+use std::collections::{HashMap, HashSet};
+use regex::Regex;
+
+use crate::assignment::AssignmentStatus;
+use crate::error::{MoraError, Result};
+
+/*
+    A rule that turns a reference name into an accession (or directly into a TaxID)
+
+    Verbatim: the reference name as it is
+    FirstToken: the reference name up to the first whitespace
+    StripVersion: the first token without its version suffix (NZ_CP012345.1 -> NZ_CP012345), the versions
+        of the accessions in the taxonomy are then ignored as well
+    Regex: the first capture group of the regular expression (or the whole match if it has no groups)
+    KrakenTaxId: the TaxID embedded Kraken style in the reference name (kraken:taxid|562|NC_000913.3)
+*/
+pub(crate) enum NameRule {
+    Verbatim,
+    FirstToken,
+    StripVersion,
+    Regex(Regex),
+    KrakenTaxId(Regex),
+}
+
+impl NameRule {
+    fn label(&self) -> &'static str {
+        match self {
+            NameRule::Verbatim => "verbatim",
+            NameRule::FirstToken => "first_token",
+            NameRule::StripVersion => "strip_version",
+            NameRule::Regex(_) => "regex",
+            NameRule::KrakenTaxId(_) => "kraken_taxid",
+        }
+    }
+
+    // the key of the reference name under this rule, None if the rule does not apply to the name
+    fn key(&self, reference: &str) -> Option<String> {
+        let first_token = reference.split_whitespace().next().unwrap_or(reference);
+        match self {
+            NameRule::Verbatim => Some(reference.to_string()),
+            NameRule::FirstToken => Some(first_token.to_string()),
+            NameRule::StripVersion => Some(strip_version(first_token).to_string()),
+            NameRule::Regex(regex) | NameRule::KrakenTaxId(regex) => {
+                let captures = regex.captures(reference)?;
+                captures.get(1).or_else(|| captures.get(0)).map(|key| key.as_str().to_string())
+            }
+        }
+    }
+}
+
+pub const DEFAULT_NAME_RULES: &str = "verbatim,first_token,strip_version,kraken_taxid";
+
+// accession without the version suffix, the accession itself if it has none
+pub(crate) fn strip_version(accession: &str) -> &str {
+    match accession.rsplit_once('.') {
+        Some((base, version)) if !base.is_empty() && !version.is_empty() && version.chars().all(|c| c.is_ascii_digit()) => base,
+        _ => accession
+    }
+}
+
+/*
+    How reference names are matched to the accessions of the taxonomy, the rules are tried in order
+    and the first one whose key is found in the taxonomy is used for a reference
+*/
+pub struct ReferenceNames {
+    rules: Vec<NameRule>,
+}
+
+impl ReferenceNames {
+    // rules: comma separated rule names, pattern: the regular expression of the regex rule
+    pub fn new(rules: &str, pattern: Option<&str>) -> Result<ReferenceNames> {
+        let pattern = match pattern {
+            Some(pattern) => Some(Regex::new(pattern).map_err(|e| MoraError::invalid_value("--ref_name_regex", pattern, e.to_string()))?),
+            None => None
+        };
+        let kraken_taxid = Regex::new(r"kraken:taxid\|(\d+)").unwrap();
+        let mut parsed = Vec::new();
+        for rule in rules.split(',').map(|rule| rule.trim()).filter(|rule| !rule.is_empty()) {
+            parsed.push(match rule {
+                "verbatim" => NameRule::Verbatim,
+                "first_token" => NameRule::FirstToken,
+                "strip_version" => NameRule::StripVersion,
+                "kraken_taxid" => NameRule::KrakenTaxId(kraken_taxid.clone()),
+                "regex" => match &pattern {
+                    Some(pattern) => NameRule::Regex(pattern.clone()),
+                    None => return Err(MoraError::invalid_value("--ref_name_rules", rules, "the regex rule needs a pattern (--ref_name_regex)"))
+                },
+                _ => return Err(MoraError::invalid_value("--ref_name_rules", rules, format!("unknown rule {}", rule)))
+            });
+        }
+        // a pattern without the regex rule is tried after the other rules
+        if let Some(pattern) = pattern {
+            if !parsed.iter().any(|rule| matches!(rule, NameRule::Regex(_))) {
+                parsed.push(NameRule::Regex(pattern));
+            }
+        }
+        Ok(ReferenceNames { rules: parsed })
+    }
+
+    // if the versions of the accessions are ignored
+    pub(crate) fn ignores_versions(&self) -> bool {
+        self.rules.iter().any(|rule| matches!(rule, NameRule::StripVersion))
+    }
+
+    // the accessions to look up in the taxonomy for the references
+    pub(crate) fn lookup_keys(&self, references: &HashSet<String>) -> HashSet<String> {
+        let mut keys = HashSet::new();
+        for reference in references {
+            for rule in &self.rules {
+                if matches!(rule, NameRule::KrakenTaxId(_)) {
+                    continue;
+                }
+                if let Some(key) = rule.key(reference) {
+                    keys.insert(key);
+                }
+            }
+        }
+        keys
+    }
+
+    /*
+        record the TaxID of an accession of the taxonomy for the keys it answers: the accession itself and,
+        when versions are ignored, the accession without its version (an exact match is never replaced)
+    */
+    pub(crate) fn add_accession(&self, keys: &HashSet<String>, accessions_2_tax: &mut HashMap<String, String>, accession: &str, tax_id: &str) {
+        if keys.contains(accession) {
+            accessions_2_tax.insert(accession.to_string(), tax_id.to_string());
+        }
+        if self.ignores_versions() {
+            let base = strip_version(accession);
+            if base != accession && keys.contains(base) {
+                accessions_2_tax.entry(base.to_string()).or_insert_with(|| tax_id.to_string());
+            }
+        }
+    }
+
+    /*
+        references: the reference names, the assignment statuses among them are skipped
+        accessions_2_tax: <accession, TaxID> of the keys found in the taxonomy
+        embedded_tax_ids: if the TaxIDs in the reference names are used (kraken_taxid), they are NCBI TaxIDs that GTDB does not have
+        OUTPUT: <reference name, TaxID>, prints how many references were matched by each rule
+    */
+    pub(crate) fn resolve(&self, references: &HashSet<String>, accessions_2_tax: &HashMap<String, String>, embedded_tax_ids: bool) -> HashMap<String, String> {
+        let rules: Vec<&NameRule> = self.rules.iter().filter(|rule| embedded_tax_ids || !matches!(rule, NameRule::KrakenTaxId(_))).collect();
+        let references: Vec<&String> = references.iter().filter(|reference| !AssignmentStatus::is_unassigned_label(reference)).collect();
+        let mut reference_2_tax = HashMap::new();
+        let mut matched = vec![0; rules.len()];
+        for reference in &references {
+            for (i, rule) in rules.iter().enumerate() {
+                let tax_id = match (rule, rule.key(reference)) {
+                    (NameRule::KrakenTaxId(_), Some(tax_id)) => Some(tax_id),
+                    (_, Some(key)) => accessions_2_tax.get(&key).cloned(),
+                    (_, None) => None
+                };
+                if let Some(tax_id) = tax_id {
+                    reference_2_tax.insert(reference.to_string(), tax_id);
+                    matched[i] += 1;
+                    break;
+                }
+            }
+        }
+
+        let counts: Vec<String> = rules.iter().zip(&matched).map(|(rule, cnt)| format!("{}: {}", rule.label(), cnt)).collect();
+        println!("references matched to a TaxID by rule: {}", counts.join(", "));
+        println!("number of accessions not found: {}", references.len() - reference_2_tax.len());
+        reference_2_tax
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn set(items: &[&str]) -> HashSet<String> {
+        items.iter().map(|item| item.to_string()).collect()
+    }
+
+    fn map(items: &[(&str, &str)]) -> HashMap<String, String> {
+        items.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect()
+    }
+
+    #[test]
+    fn keys_of_each_rule() {
+        let reference = "NC_000913.3 Escherichia coli str. K-12";
+        let kraken = "kraken:taxid|562|NC_000913.3";
+        let regex = NameRule::Regex(Regex::new(r"^ref\|([^|]+)\|").unwrap());
+        let whole_match = NameRule::Regex(Regex::new(r"GCF_\d+").unwrap());
+        let kraken_taxid = NameRule::KrakenTaxId(Regex::new(r"kraken:taxid\|(\d+)").unwrap());
+
+        assert_eq!(NameRule::Verbatim.key(reference).as_deref(), Some(reference));
+        assert_eq!(NameRule::FirstToken.key(reference).as_deref(), Some("NC_000913.3"));
+        assert_eq!(NameRule::StripVersion.key(reference).as_deref(), Some("NC_000913"));
+        assert_eq!(regex.key("ref|NZ_CP012345.1|chromosome").as_deref(), Some("NZ_CP012345.1"));
+        assert_eq!(regex.key(reference), None);
+        assert_eq!(whole_match.key("genome_GCF_000005845_1").as_deref(), Some("GCF_000005845"));
+        assert_eq!(kraken_taxid.key(kraken).as_deref(), Some("562"));
+        assert_eq!(kraken_taxid.key(reference), None);
+    }
+
+    #[test]
+    fn versions_are_stripped_only_when_numerical() {
+        assert_eq!(strip_version("NZ_CP012345.1"), "NZ_CP012345");
+        assert_eq!(strip_version("NZ_CP012345"), "NZ_CP012345");
+        assert_eq!(strip_version("contig.a"), "contig.a");
+        assert_eq!(strip_version(".1"), ".1");
+    }
+
+    #[test]
+    fn rules_are_parsed_in_order() {
+        let names = ReferenceNames::new(" first_token , regex", Some(r"^(\w+)")).unwrap();
+        assert_eq!(names.rules.iter().map(|rule| rule.label()).collect::<Vec<_>>(), vec!["first_token", "regex"]);
+        assert!(!names.ignores_versions());
+
+        // a pattern without the regex rule is tried last
+        let names = ReferenceNames::new(DEFAULT_NAME_RULES, Some(r"^(\w+)")).unwrap();
+        assert_eq!(names.rules.iter().map(|rule| rule.label()).collect::<Vec<_>>(), vec!["verbatim", "first_token", "strip_version", "kraken_taxid", "regex"]);
+        assert!(names.ignores_versions());
+
+        for (rules, pattern) in [("verbatim,accession", None), ("regex", None), ("verbatim", Some("(unclosed"))] {
+            assert!(matches!(ReferenceNames::new(rules, pattern), Err(MoraError::InvalidValue { .. })), "{}", rules);
+        }
+    }
+
+    #[test]
+    fn lookup_keys_and_accessions() {
+        let names = ReferenceNames::new(DEFAULT_NAME_RULES, None).unwrap();
+        let keys = names.lookup_keys(&set(&["NC_000913.3 E. coli", "kraken:taxid|562|NC_000913.3"]));
+        // the TaxID of the kraken_taxid rule is not looked up
+        assert_eq!(keys, set(&["NC_000913.3 E. coli", "NC_000913.3", "NC_000913", "kraken:taxid|562|NC_000913.3", "kraken:taxid|562|NC_000913"]));
+
+        // an exact match is kept over a match of another version
+        let mut accessions_2_tax = HashMap::new();
+        for (accession, tax_id) in [("NC_000913.2", "83333"), ("NC_000913.3", "511145"), ("NC_000913.4", "562")] {
+            names.add_accession(&keys, &mut accessions_2_tax, accession, tax_id);
+        }
+        assert_eq!(accessions_2_tax, map(&[("NC_000913.3", "511145"), ("NC_000913", "83333")]));
+
+        let verbatim = ReferenceNames::new("verbatim", None).unwrap();
+        let mut accessions_2_tax = HashMap::new();
+        verbatim.add_accession(&set(&["NC_000913"]), &mut accessions_2_tax, "NC_000913.3", "562");
+        assert!(accessions_2_tax.is_empty());
+    }
+
+    #[test]
+    fn references_are_resolved_by_the_first_matching_rule() {
+        let names = ReferenceNames::new("first_token,kraken_taxid,strip_version", None).unwrap();
+        let references = set(&["NC_000913.3 E. coli", "kraken:taxid|590|NZ_CP000001.1", "NZ_CP012345.2", "unknown", "UNASSIGNED", "UNMAPPED"]);
+        let accessions_2_tax = map(&[("NC_000913.3", "562"), ("NZ_CP012345", "1280"), ("kraken:taxid|590|NZ_CP000001.1", "1")]);
+
+        let resolved = names.resolve(&references, &accessions_2_tax, true);
+        assert_eq!(resolved, map(&[("NC_000913.3 E. coli", "562"), ("kraken:taxid|590|NZ_CP000001.1", "1"), ("NZ_CP012345.2", "1280")]));
+
+        // the TaxIDs in the names are NCBI TaxIDs, so they are not used with GTDB
+        let resolved = names.resolve(&set(&["kraken:taxid|590|NZ_CP000001.1"]), &HashMap::new(), false);
+        assert!(resolved.is_empty());
+        let resolved = names.resolve(&set(&["kraken:taxid|590|NZ_CP000001.1"]), &HashMap::new(), true);
+        assert_eq!(resolved, map(&[("kraken:taxid|590|NZ_CP000001.1", "590")]));
+    }
+}
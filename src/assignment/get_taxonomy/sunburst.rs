@@ -0,0 +1,110 @@
+use std::f32::consts::PI;
+
+use super::profile::{ProfileSource, ProfileTree};
+use crate::error::{create_file, write_to, Result};
+
+const ROOT: &str = "1";
+const CENTER: f32 = 400.0;
+const RING_WIDTH: f32 = 36.0;
+
+// point on the circle of the given radius at the given fraction of a full turn, starting at the top
+fn point(radius: f32, turn: f32) -> (f32, f32) {
+    let angle = 2.0 * PI * turn - PI / 2.0;
+    (CENTER + radius * angle.cos(), CENTER + radius * angle.sin())
+}
+
+// SVG path of the part of the ring at the given depth between the two fractions of a full turn
+fn ring_segment(depth: usize, start: f32, end: f32) -> String {
+    let (inner, outer) = (RING_WIDTH * depth as f32, RING_WIDTH * (depth + 1) as f32);
+    // a full ring cannot be drawn with a single arc
+    let end = if end - start >= 1.0 { start + 0.9999 } else { end };
+    let large_arc = if end - start > 0.5 { 1 } else { 0 };
+    let (x0, y0) = point(outer, start);
+    let (x1, y1) = point(outer, end);
+    let (x2, y2) = point(inner, end);
+    let (x3, y3) = point(inner, start);
+    format!("M{:.2},{:.2} A{},{} 0 {} 1 {:.2},{:.2} L{:.2},{:.2} A{},{} 0 {} 0 {:.2},{:.2} Z",
+            x0, y0, outer, outer, large_arc, x1, y1, x2, y2, inner, inner, large_arc, x3, y3)
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+/*
+Write a self-contained HTML page with a sunburst chart of the profile: the root in the center and each ring
+one level further down the lineages, the size of a segment is the value of the taxon and its descendants.
+Hovering a segment shows its name, rank and value. The chart is a static SVG drawn here, it does not use Krona
+and cannot be zoomed into a clade like a Krona chart (for that, the Krona text input can be given to ktImportText).
+*/
+pub(super) fn write_sunburst_html(tree: &ProfileTree, source: ProfileSource, sample_id: &str, output_filename: &str) -> Result<()> {
+    let unclassified = source.unclassified_value(tree);
+    let total = source.clade_value(tree, &tree.nodes[ROOT]) + unclassified;
+    let unit = match source {
+        ProfileSource::Reads => "reads",
+        ProfileSource::Abundance => "%"
+    };
+
+    let mut segments = Vec::new();
+    if total > 0.0 {
+        // (TaxID, depth, start of the segment, hue of the segment)
+        let mut stack = vec![(ROOT, 0, 0.0, 0.0)];
+        while let Some((tax_id, depth, start, hue)) = stack.pop() {
+            let node = &tree.nodes[tax_id];
+            let value = source.clade_value(tree, node);
+            let span = value / total;
+            let title = format!("{} ({}): {:.2} {} ({:.2}%)", escape_html(&node.name), escape_html(&node.rank), value, unit, 100.0 * span);
+            let color = if depth == 0 { "#f2f2f2".to_string() } else { format!("hsl({:.0},65%,{}%)", hue, (40 + 6 * depth).min(85)) };
+            // the root is the full center circle, also when there are unclassified reads
+            let end = if depth == 0 { 1.0 } else { start + span };
+            segments.push(format!("<path d=\"{}\" fill=\"{}\"><title>{}</title></path>", ring_segment(depth, start, end), color, title));
+
+            let mut child_start = start;
+            for (i, child) in tree.sorted_children(tax_id, |node| source.clade_value(tree, node)).into_iter().enumerate() {
+                let child_span = source.clade_value(tree, &tree.nodes[child]) / total;
+                if child_span <= 0.0 {
+                    continue;
+                }
+                // the top level taxa get their own color, their descendants share it
+                let child_hue = if depth == 0 { (i as f32 * 137.5) % 360.0 } else { hue };
+                stack.push((child, depth + 1, child_start, child_hue));
+                child_start += child_span;
+            }
+        }
+        if unclassified > 0.0 {
+            let span = unclassified / total;
+            let title = format!("Unclassified: {:.2} {} ({:.2}%)", unclassified, unit, 100.0 * span);
+            segments.push(format!("<path d=\"{}\" fill=\"#bbbbbb\"><title>{}</title></path>", ring_segment(1, 1.0 - span, 1.0), title));
+        }
+    }
+
+    let sample_id = escape_html(sample_id);
+    let html = format!("<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>MORA - {}</title>\n\
+                        <style>body {{ font-family: sans-serif; }} path {{ stroke: #ffffff; stroke-width: 0.5; }} path:hover {{ opacity: 0.7; }}</style>\n\
+                        </head>\n<body>\n<h2>{}</h2>\n<p>Total: {:.2} {}</p>\n<svg width=\"{}\" height=\"{}\" viewBox=\"0 0 {} {}\">\n{}\n</svg>\n</body>\n</html>\n",
+                        sample_id, sample_id, total, unit, 2.0 * CENTER, 2.0 * CENTER, 2.0 * CENTER, 2.0 * CENTER, segments.join("\n"));
+    let mut output = create_file(output_filename)?;
+    write_to(&mut output, output_filename, html.as_bytes())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::profile::tests::example_tree;
+    use crate::test_util::TestDir;
+
+    #[test]
+    fn sunburst_has_a_segment_per_taxon() {
+        let dir = TestDir::new();
+        let chart = dir.path("chart.html");
+        write_sunburst_html(&example_tree(), ProfileSource::Reads, "<sample>", &chart).unwrap();
+        let html = std::fs::read_to_string(&chart).unwrap();
+        // the root, Bacteria, Escherichia, E. coli, E. coli K-12, Salmonella and the unclassified reads
+        assert_eq!(html.matches("<path").count(), 7);
+        assert!(html.contains("<title>MORA - &lt;sample&gt;</title>"));
+        assert!(html.contains("<p>Total: 10.00 reads</p>"));
+        assert!(html.contains("<title>Escherichia coli (species): 6.00 reads (60.00%)</title>"));
+        assert!(html.contains("<title>Unclassified: 2.00 reads (20.00%)</title>"));
+    }
+}
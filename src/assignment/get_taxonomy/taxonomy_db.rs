@@ -0,0 +1,398 @@
+use std::collections::{HashMap, HashSet};
+use std::io::{BufWriter, Write};
+use memmap2::Mmap;
+
+use super::{read_accessions, TaxonomyTree};
+use super::reference_names::strip_version;
+use crate::error::{create_file, open_file, write_to, MoraError, Result};
+
+const MAGIC: &[u8; 8] = b"MORATAX\0";
+const VERSION: u32 = 1;
+const HEADER_LEN: usize = 40;
+
+// the TaxIDs are stored as numbers
+fn numerical_tax_id(tax_id: &str) -> Result<u32> {
+    tax_id.parse().map_err(|_| MoraError::Taxonomy { tax_id: tax_id.to_string(), message: "not a number, a taxonomy database only stores numerical TaxIDs".to_string() })
+}
+
+/*
+    Compact binary taxonomy that is memory mapped instead of parsed.
+    All numbers are little endian and strings are stored in a single blob referenced by offsets.
+
+    header: magic (8 bytes), version (u32), # of nodes (u32), # of ranks (u32), # of accessions (u32), # of merged TaxIDs (u32),
+        # of deleted TaxIDs (u32), length of the string blob (u64)
+    name_offsets: u64 x (# of nodes + 1), the scientific name of node i is blob[name_offsets[i]..name_offsets[i + 1]]
+    rank_offsets: u64 x (# of ranks + 1), the names of the distinct ranks
+    accession_offsets: u64 x (# of accessions + 1), the accessions sorted in byte order
+    tax_ids: u32 x # of nodes, sorted so that nodes can be found with a binary search
+    parents: u32 x # of nodes, index of the parent node (the root is its own parent)
+    ranks: u32 x # of nodes, index into the ranks
+    accession_tax_ids: u32 x # of accessions, the TaxID of each accession
+    merged_tax_ids: u32 x # of merged TaxIDs, the sorted deprecated TaxIDs
+    merged_into: u32 x # of merged TaxIDs, the TaxID each deprecated TaxID was merged into
+    deleted_tax_ids: u32 x # of deleted TaxIDs, sorted
+    blob: the strings
+*/
+pub struct TaxonomyDb {
+    mmap: Mmap,
+    node_cnt: usize,
+    rank_cnt: usize,
+    accession_cnt: usize,
+    merged_cnt: usize,
+    deleted_cnt: usize,
+}
+
+impl TaxonomyDb {
+    /*
+        compile the accessions 2 taxIDs file and the NCBI dump files of a taxonomy directory into a taxonomy database
+    */
+    pub fn build(tax_dir: String, output_filename: String) -> Result<()> {
+        let tree = TaxonomyTree::from_dmp(tax_dir.to_string() + "/nodes.dmp", tax_dir.to_string() + "/names.dmp",
+                                            tax_dir.to_string() + "/merged.dmp", tax_dir.to_string() + "/delnodes.dmp")?;
+        let accessions = read_accessions(tax_dir.to_string() + "/accessionsTaxIDs.tab")?;
+        TaxonomyDb::write(&tree, &accessions, output_filename)
+    }
+
+    /*
+        tree: the taxonomy tree
+        accessions: <accession, TaxID>
+        output_filename: path for the taxonomy database
+    */
+    pub(crate) fn write(tree: &TaxonomyTree, accessions: &HashMap<String, String>, output_filename: String) -> Result<()> {
+        let mut tax_ids: Vec<u32> = tree.nodes.keys().map(|tax_id| numerical_tax_id(tax_id)).collect::<Result<_>>()?;
+        tax_ids.sort_unstable();
+        let index: HashMap<u32, u32> = tax_ids.iter().enumerate().map(|(i, tax_id)| (*tax_id, i as u32)).collect();
+
+        let mut rank_ids: HashMap<&str, u32> = HashMap::new();
+        let mut rank_names = Vec::new();
+        let (mut parents, mut ranks) = (Vec::new(), Vec::new());
+        let mut blob = Vec::new();
+        let mut name_offsets = vec![0];
+        for tax_id in &tax_ids {
+            let node = &tree.nodes[&tax_id.to_string()];
+            // parents that are not in the dump are treated as the root
+            let parent = tree.resolve(&node[0]).parse().ok().and_then(|parent: u32| index.get(&parent).copied()).unwrap_or(index[tax_id]);
+            parents.push(parent);
+
+            let rank = *rank_ids.entry(&node[1]).or_insert_with(|| {
+                rank_names.push(node[1].to_string());
+                rank_names.len() as u32 - 1
+            });
+            ranks.push(rank);
+
+            if let Some(name) = tree.names.get(&tax_id.to_string()) {
+                blob.extend_from_slice(name.as_bytes());
+            }
+            name_offsets.push(blob.len() as u64);
+        }
+
+        let mut rank_offsets = vec![blob.len() as u64];
+        for rank in &rank_names {
+            blob.extend_from_slice(rank.as_bytes());
+            rank_offsets.push(blob.len() as u64);
+        }
+
+        let mut sorted_accessions: Vec<(&String, &String)> = accessions.iter().collect();
+        sorted_accessions.sort_unstable_by(|a, b| a.0.as_bytes().cmp(b.0.as_bytes()));
+        let mut accession_offsets = vec![blob.len() as u64];
+        let mut accession_tax_ids = Vec::new();
+        for (accession, tax_id) in sorted_accessions {
+            blob.extend_from_slice(accession.as_bytes());
+            accession_offsets.push(blob.len() as u64);
+            accession_tax_ids.push(numerical_tax_id(tax_id)?);
+        }
+
+        let mut merged: Vec<(u32, u32)> = tree.merged.iter().map(|(old, new)| Ok((numerical_tax_id(old)?, numerical_tax_id(new)?))).collect::<Result<_>>()?;
+        merged.sort_unstable();
+        let (merged_tax_ids, merged_into): (Vec<u32>, Vec<u32>) = merged.into_iter().unzip();
+        let mut deleted_tax_ids: Vec<u32> = tree.deleted.iter().map(|tax_id| numerical_tax_id(tax_id)).collect::<Result<_>>()?;
+        deleted_tax_ids.sort_unstable();
+
+        let mut output = BufWriter::new(create_file(&output_filename)?);
+        write_to(&mut output, &output_filename, MAGIC)?;
+        for value in [VERSION, tax_ids.len() as u32, rank_names.len() as u32, accession_tax_ids.len() as u32,
+                        merged_tax_ids.len() as u32, deleted_tax_ids.len() as u32] {
+            write_to(&mut output, &output_filename, &value.to_le_bytes())?;
+        }
+        write_to(&mut output, &output_filename, &(blob.len() as u64).to_le_bytes())?;
+        for section in [&name_offsets, &rank_offsets, &accession_offsets] {
+            for value in section {
+                write_to(&mut output, &output_filename, &value.to_le_bytes())?;
+            }
+        }
+        for section in [&tax_ids, &parents, &ranks, &accession_tax_ids, &merged_tax_ids, &merged_into, &deleted_tax_ids] {
+            for value in section {
+                write_to(&mut output, &output_filename, &value.to_le_bytes())?;
+            }
+        }
+        write_to(&mut output, &output_filename, &blob)?;
+        output.flush().map_err(|e| MoraError::io(&output_filename, e))?;
+
+        println!("taxonomy database with {} nodes and {} accessions written to {}", tax_ids.len(), accession_tax_ids.len(), output_filename);
+        Ok(())
+    }
+
+    pub(crate) fn open(db_file: String) -> Result<TaxonomyDb> {
+        let f = open_file(&db_file)?;
+        // the file is only read and is not expected to change while MORA is running
+        let mmap = unsafe { Mmap::map(&f).map_err(|e| MoraError::io(&db_file, e))? };
+        if mmap.len() < HEADER_LEN || &mmap[0..8] != MAGIC {
+            return Err(MoraError::invalid_value("--tax", &db_file, "not a MORA taxonomy database"));
+        }
+        let header = |i: usize| u32::from_le_bytes(mmap[8 + 4 * i..12 + 4 * i].try_into().unwrap());
+        let version = header(0);
+        if version != VERSION {
+            return Err(MoraError::invalid_value("--tax", &db_file, 
+                format!("taxonomy database version {}, expected {}, rebuild it with `mora taxonomy build`", version, VERSION)));
+        }
+
+        let (node_cnt, rank_cnt, accession_cnt) = (header(1) as usize, header(2) as usize, header(3) as usize);
+        let (merged_cnt, deleted_cnt) = (header(4) as usize, header(5) as usize);
+        let blob_len = u64::from_le_bytes(mmap[32..40].try_into().unwrap()) as usize;
+        let db = TaxonomyDb { mmap, node_cnt, rank_cnt, accession_cnt, merged_cnt, deleted_cnt };
+        if db.mmap.len() != db.blob_start() + blob_len {
+            return Err(MoraError::invalid_value("--tax", &db_file, "the taxonomy database is truncated or corrupted"));
+        }
+        db.validate()?;
+        Ok(db)
+    }
+
+    // checks the strings, parents and ranks of every node and the strings of every accession, so they can be read without checks
+    fn validate(&self) -> Result<()> {
+        let corrupted = |tax_id: u32, what: &str| MoraError::Taxonomy { tax_id: tax_id.to_string(), message: format!("{} is not valid, the taxonomy database is corrupted", what) };
+        for i in 0..self.node_cnt {
+            let tax_id = self.read_u32(self.tax_ids_start(), i);
+            if self.checked_str(self.name_offsets_start(), i).is_none() {
+                return Err(corrupted(tax_id, "the name"));
+            }
+            if self.read_u32(self.parents_start(), i) as usize >= self.node_cnt {
+                return Err(corrupted(tax_id, "the parent"));
+            }
+            let rank = self.read_u32(self.ranks_start(), i) as usize;
+            if rank >= self.rank_cnt || self.checked_str(self.rank_offsets_start(), rank).is_none() {
+                return Err(corrupted(tax_id, "the rank"));
+            }
+        }
+        for i in 0..self.accession_cnt {
+            if self.checked_str(self.accession_offsets_start(), i).is_none() {
+                return Err(corrupted(self.read_u32(self.accession_tax_ids_start(), i), "the accession"));
+            }
+        }
+        Ok(())
+    }
+
+    // section starts in bytes
+    fn name_offsets_start(&self) -> usize { HEADER_LEN }
+    fn rank_offsets_start(&self) -> usize { self.name_offsets_start() + 8 * (self.node_cnt + 1) }
+    fn accession_offsets_start(&self) -> usize { self.rank_offsets_start() + 8 * (self.rank_cnt + 1) }
+    fn tax_ids_start(&self) -> usize { self.accession_offsets_start() + 8 * (self.accession_cnt + 1) }
+    fn parents_start(&self) -> usize { self.tax_ids_start() + 4 * self.node_cnt }
+    fn ranks_start(&self) -> usize { self.parents_start() + 4 * self.node_cnt }
+    fn accession_tax_ids_start(&self) -> usize { self.ranks_start() + 4 * self.node_cnt }
+    fn merged_tax_ids_start(&self) -> usize { self.accession_tax_ids_start() + 4 * self.accession_cnt }
+    fn merged_into_start(&self) -> usize { self.merged_tax_ids_start() + 4 * self.merged_cnt }
+    fn deleted_tax_ids_start(&self) -> usize { self.merged_into_start() + 4 * self.merged_cnt }
+    fn blob_start(&self) -> usize { self.deleted_tax_ids_start() + 4 * self.deleted_cnt }
+
+    fn read_u32(&self, section: usize, i: usize) -> u32 {
+        let at = section + 4 * i;
+        u32::from_le_bytes(self.mmap[at..at + 4].try_into().unwrap())
+    }
+
+    fn read_u64(&self, section: usize, i: usize) -> u64 {
+        let at = section + 8 * i;
+        u64::from_le_bytes(self.mmap[at..at + 8].try_into().unwrap())
+    }
+
+    // i-th string of a section of offsets into the blob, None if the offsets are outside of the blob or it is not UTF-8
+    fn checked_str(&self, section: usize, i: usize) -> Option<&str> {
+        let blob = self.blob_start();
+        let (start, end) = (self.read_u64(section, i) as usize, self.read_u64(section, i + 1) as usize);
+        let bytes = self.mmap.get(blob.checked_add(start)?..blob.checked_add(end)?)?;
+        std::str::from_utf8(bytes).ok()
+    }
+
+    // i-th string of a section, the strings are checked when the database is opened
+    fn read_str(&self, section: usize, i: usize) -> &str {
+        self.checked_str(section, i).unwrap_or_default()
+    }
+
+    // binary search for a value in a sorted u32 section of length len
+    fn search_u32(&self, section: usize, len: usize, value: u32) -> Option<usize> {
+        let (mut low, mut high) = (0, len);
+        while low < high {
+            let mid = (low + high) / 2;
+            match self.read_u32(section, mid).cmp(&value) {
+                std::cmp::Ordering::Less => low = mid + 1,
+                std::cmp::Ordering::Greater => high = mid,
+                std::cmp::Ordering::Equal => return Some(mid),
+            }
+        }
+        None
+    }
+
+    // TaxID of an accession
+    fn accession_tax_id(&self, accession: &str) -> Option<u32> {
+        let (mut low, mut high) = (0, self.accession_cnt);
+        while low < high {
+            let mid = (low + high) / 2;
+            match self.read_str(self.accession_offsets_start(), mid).as_bytes().cmp(accession.as_bytes()) {
+                std::cmp::Ordering::Less => low = mid + 1,
+                std::cmp::Ordering::Greater => high = mid,
+                std::cmp::Ordering::Equal => return Some(self.read_u32(self.accession_tax_ids_start(), mid)),
+            }
+        }
+        None
+    }
+
+    // TaxID of any version of an accession given without a version, the accessions with the prefix accession. are 
+    // contiguous in byte order
+    fn unversioned_accession_tax_id(&self, accession: &str) -> Option<u32> {
+        let prefix = accession.to_string() + ".";
+        let (mut low, mut high) = (0, self.accession_cnt);
+        while low < high {
+            let mid = (low + high) / 2;
+            if self.read_str(self.accession_offsets_start(), mid).as_bytes() < prefix.as_bytes() {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
+        while low < self.accession_cnt {
+            let found = self.read_str(self.accession_offsets_start(), low);
+            if !found.starts_with(&prefix) {
+                break;
+            }
+            if strip_version(found) == accession {
+                return Some(self.read_u32(self.accession_tax_ids_start(), low));
+            }
+            low += 1;
+        }
+        None
+    }
+
+    /*
+        accessions: the accessions to look up
+        ignore_versions: if an accession without a version also matches the versioned accessions
+        OUTPUT: <accession, TaxID> of the accessions found
+    */
+    pub(crate) fn accessions_2_tax_id(&self, accessions: &HashSet<String>, ignore_versions: bool) -> HashMap<String, String> {
+        let mut accessions_2_tax = HashMap::new();
+        for accession in accessions {
+            let tax_id = self.accession_tax_id(accession).or_else(|| {
+                if ignore_versions { self.unversioned_accession_tax_id(accession) } else { None }
+            });
+            if let Some(tax_id) = tax_id {
+                accessions_2_tax.insert(accession.to_string(), tax_id.to_string());
+            }
+        }
+        accessions_2_tax
+    }
+
+    /*
+        the part of the taxonomy tree needed for the TaxIDs: the TaxIDs, all their ancestors and
+        whether they were merged or deleted
+    */
+    pub(crate) fn subtree<'a>(&self, tax_ids: impl Iterator<Item = &'a String>) -> TaxonomyTree {
+        let mut tree = TaxonomyTree { nodes: HashMap::new(), names: HashMap::new(), merged: HashMap::new(), deleted: HashSet::new() };
+        for tax_id in tax_ids {
+            let mut tax_id_value: u32 = match tax_id.parse() {
+                Ok(value) => value,
+                Err(_) => continue
+            };
+            if let Some(i) = self.search_u32(self.merged_tax_ids_start(), self.merged_cnt, tax_id_value) {
+                tax_id_value = self.read_u32(self.merged_into_start(), i);
+                tree.merged.insert(tax_id.to_string(), tax_id_value.to_string());
+            }
+            if self.search_u32(self.deleted_tax_ids_start(), self.deleted_cnt, tax_id_value).is_some() {
+                tree.deleted.insert(tax_id_value.to_string());
+            }
+
+            let mut current = self.search_u32(self.tax_ids_start(), self.node_cnt, tax_id_value);
+            while let Some(i) = current {
+                let tax_id = self.read_u32(self.tax_ids_start(), i).to_string();
+                if tree.nodes.contains_key(&tax_id) {
+                    break;
+                }
+                let parent = self.read_u32(self.parents_start(), i) as usize;
+                let rank = self.read_str(self.rank_offsets_start(), self.read_u32(self.ranks_start(), i) as usize);
+                tree.nodes.insert(tax_id.to_string(), vec![self.read_u32(self.tax_ids_start(), parent).to_string(), rank.to_string()]);
+                let name = self.read_str(self.name_offsets_start(), i);
+                if !name.is_empty() {
+                    tree.names.insert(tax_id, name.to_string());
+                }
+                current = if parent == i { None } else { Some(parent) };
+            }
+        }
+        tree
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TestDir;
+
+    // root <- Bacteria (superkingdom) <- Escherichia coli (species), 100 was merged into 562 and 999 was deleted
+    fn tree() -> TaxonomyTree {
+        let nodes = HashMap::from([
+            ("1".to_string(), vec!["1".to_string(), "no rank".to_string()]),
+            ("2".to_string(), vec!["1".to_string(), "superkingdom".to_string()]),
+            ("562".to_string(), vec!["2".to_string(), "species".to_string()]),
+        ]);
+        let names = HashMap::from([
+            ("1".to_string(), "root".to_string()),
+            ("2".to_string(), "Bacteria".to_string()),
+            ("562".to_string(), "Escherichia coli".to_string()),
+        ]);
+        let merged = HashMap::from([("100".to_string(), "562".to_string())]);
+        let deleted = HashSet::from(["999".to_string()]);
+        TaxonomyTree { nodes, names, merged, deleted }
+    }
+
+    #[test]
+    fn build_open_lookup_round_trip() {
+        let dir = TestDir::new();
+        let path = dir.path("round_trip.db");
+        let accessions = HashMap::from([("NC_000913.3".to_string(), "562".to_string()), ("NZ_CP012345.1".to_string(), "100".to_string()),
+                                        ("NC_999999.1".to_string(), "999".to_string())]);
+        TaxonomyDb::write(&tree(), &accessions, path.to_string()).unwrap();
+        let db = TaxonomyDb::open(path.to_string()).unwrap();
+
+        let keys = HashSet::from(["NC_000913.3".to_string(), "NZ_CP012345".to_string(), "NC_000001.1".to_string()]);
+        let found = db.accessions_2_tax_id(&keys, true);
+        assert_eq!(found.len(), 2);
+        assert_eq!(found["NC_000913.3"], "562");
+        assert_eq!(found["NZ_CP012345"], "100");
+        assert!(!db.accessions_2_tax_id(&keys, false).contains_key("NZ_CP012345"));
+
+        let tax_ids = ["100".to_string(), "999".to_string()];
+        let subtree = db.subtree(tax_ids.iter());
+        assert_eq!(subtree.merged["100"], "562");
+        assert!(subtree.deleted.contains("999"));
+        assert_eq!(subtree.nodes["562"], vec!["2".to_string(), "species".to_string()]);
+        assert_eq!(subtree.nodes["2"], vec!["1".to_string(), "superkingdom".to_string()]);
+        assert_eq!(subtree.names["562"], "Escherichia coli");
+        assert!(subtree.nodes.contains_key("1"));
+    }
+
+    #[test]
+    fn non_numerical_tax_ids_are_rejected() {
+        let dir = TestDir::new();
+        let path = dir.path("non_numerical.db");
+        let accessions = HashMap::from([("NC_000913.3".to_string(), "s__Escherichia coli".to_string())]);
+        assert!(matches!(TaxonomyDb::write(&tree(), &accessions, path.to_string()), Err(MoraError::Taxonomy { .. })));
+    }
+
+    #[test]
+    fn corrupted_strings_are_rejected() {
+        let dir = TestDir::new();
+        let path = dir.path("corrupted.db");
+        TaxonomyDb::write(&tree(), &HashMap::new(), path.to_string()).unwrap();
+        // the end offset of the name of the first node points past the blob
+        let mut bytes = std::fs::read(&path).unwrap();
+        bytes[HEADER_LEN + 8..HEADER_LEN + 16].copy_from_slice(&u64::MAX.to_le_bytes());
+        std::fs::write(&path, bytes).unwrap();
+        assert!(matches!(TaxonomyDb::open(path.to_string()), Err(MoraError::Taxonomy { .. })));
+    }
+}
@@ -0,0 +1,257 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::time::Instant;
+
+use crate::cedar::{Cedar, ReferenceIndex};
+use crate::cedar::readers::Reference;
+use crate::assignment::{assign_mappings, records_to_output, write_output, write_output_with_loaded_taxonomy};
+use crate::assignment::get_taxonomy::{load_taxonomy, LoadedTaxonomy, TaxonomyOptions, TaxonomyReports, TaxonomySource};
+use crate::config::MoraConfig;
+use crate::error::{create_file, open_file, write_to, MoraError, Result};
+
+/*
+    A line of the sample sheet
+
+    id: sample ID used for the output files and the columns of the abundance matrix
+    file: the SAM/BAM file of the sample
+    group: the samples with the same group (e.g. the same subject) are estimated together in joint mode, empty if not given
+*/
+struct Sample {
+    id: String,
+    file: String,
+    group: String,
+}
+
+/*
+    sample sheet: one sample per line with the columns sample ID, path of its SAM/BAM file and optionally its group,
+    empty lines and lines starting with # are skipped
+    OUTPUT: the samples in the order of the sheet
+*/
+fn read_sample_sheet(file_name: &str) -> Result<Vec<Sample>> {
+    let f = open_file(file_name)?;
+    let mut samples = Vec::new();
+    let mut sample_ids = HashSet::new();
+    for (i, line) in BufReader::new(f).lines().enumerate() {
+        let line = line.map_err(|e| MoraError::io(file_name, e))?;
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let chunks: Vec<&str> = line.split('\t').map(|chunk| chunk.trim()).collect();
+        if chunks.len() < 2 {
+            return Err(MoraError::parse(file_name, i + 1, "expected a sample ID and a SAM/BAM file"));
+        }
+        if !sample_ids.insert(chunks[0].to_string()) {
+            return Err(MoraError::parse(file_name, i + 1, format!("sample {} is in the sheet more than once", chunks[0])));
+        }
+        let group = chunks.get(2).unwrap_or(&"").to_string();
+        samples.push(Sample { id: chunks[0].to_string(), file: chunks[1].to_string(), group });
+    }
+    if samples.is_empty() {
+        return Err(MoraError::parse(file_name, 0, "no samples in the sheet"));
+    }
+    Ok(samples)
+}
+
+/*
+    Write the estimated abundances of all samples with the references as rows in order of their ID
+    and the samples as columns in the order of the sample sheet
+*/
+fn write_abundance_matrix(samples: &[Sample], references: &HashMap<usize, Reference>, abundances: &HashMap<String, HashMap<usize, f32>>, output_filename: String) -> Result<()> {
+    println!("Writing abundance matrix into the file: {}", &output_filename);
+    let mut ref_ids: Vec<&usize> = references.keys().collect();
+    ref_ids.sort();
+
+    let mut output = BufWriter::new(create_file(&output_filename)?);
+    let header: Vec<&str> = samples.iter().map(|sample| sample.id.as_str()).collect();
+    write_to(&mut output, &output_filename, format!("Reference\t{}\n", header.join("\t")).as_bytes())?;
+    for ref_id in ref_ids {
+        let row: Vec<String> = samples.iter().map(|sample| abundances[&sample.id].get(ref_id).unwrap_or(&0.0).to_string()).collect();
+        write_to(&mut output, &output_filename, format!("{}\t{}\n", references[ref_id].ref_name, row.join("\t")).as_bytes())?;
+    }
+    output.flush().map_err(|e| MoraError::io(&output_filename, e))
+}
+
+/*
+    The taxonomy reports of a sample: every report requested for the batch is written into the output directory
+    as <sample ID>_<file name of the requested path>
+*/
+fn sample_reports(reports: &TaxonomyReports, sample: &Sample, out_dir: &str) -> TaxonomyReports {
+    let path = |report: &Option<String>| report.as_ref().map(|report| {
+        let file_name = Path::new(report).file_name().map_or_else(|| report.to_string(), |name| name.to_string_lossy().to_string());
+        Path::new(out_dir).join(format!("{}_{}", sample.id, file_name)).to_string_lossy().to_string()
+    });
+    TaxonomyReports {
+        sample_id: sample.id.to_string(),
+        kraken_report: path(&reports.kraken_report),
+        metaphlan_profile: path(&reports.metaphlan_profile),
+        cami_profile: path(&reports.cami_profile),
+        cami_binning: path(&reports.cami_binning),
+        krona_text: path(&reports.krona_text),
+        sunburst_html: path(&reports.sunburst_html),
+        krona_source: reports.krona_source,
+        rank_counts: path(&reports.rank_counts),
+        rank_counts_source: reports.rank_counts_source,
+    }
+}
+
+// the samples of each group in the order of the sheet, every sample is its own group if the estimation is not joint or it has no group
+fn group_samples(samples: &[Sample], joint: bool) -> Vec<Vec<&Sample>> {
+    let mut groups: Vec<Vec<&Sample>> = Vec::new();
+    let mut group_index: HashMap<&str, usize> = HashMap::new();
+    for sample in samples {
+        match group_index.get(sample.group.as_str()) {
+            Some(i) if joint => groups[*i].push(sample),
+            _ => {
+                if !sample.group.is_empty() {
+                    group_index.insert(&sample.group, groups.len());
+                }
+                groups.push(vec![sample]);
+            }
+        }
+    }
+    groups
+}
+
+/*
+    Write the abundances of a sample whose EM is done, assign its reads and write the assignments, the taxonomy reports and the summary
+    OUTPUT: the estimated abundances of the sample for the abundance matrix
+*/
+fn write_sample_outputs(mut cedar: Cedar, sample: &Sample, out_dir: &str, config: &MoraConfig, taxonomy: &Option<(LoadedTaxonomy, TaxonomyOptions)>,
+                        reports: &TaxonomyReports) -> Result<HashMap<usize, f32>> {
+    let prefix = Path::new(out_dir).join(&sample.id).to_string_lossy().to_string();
+    let start = Instant::now();
+    cedar.serialize_simple(prefix.to_string() + "_abundance.txt")?;
+    let abundance = cedar.get_strain_abundance();
+    let mut summary = cedar.get_summary();
+    summary.add_timing("abundance_outputs", start);
+
+    let reference_abundance = cedar.get_reference_abundances();
+    let start = Instant::now();
+    let (records, assignment_summary) = assign_mappings(cedar, config)?;
+    let output = records_to_output(&records);
+    summary.assignment = Some(assignment_summary);
+    summary.add_timing("assignment", start);
+
+    let start = Instant::now();
+    let output_filename = prefix.to_string() + "_assignments.txt";
+    println!("\nWriting results to {}", &output_filename);
+    match taxonomy {
+        Some((loaded, tax_options)) => {
+            let reports = sample_reports(reports, sample, out_dir);
+            summary.taxonomy = Some(write_output_with_loaded_taxonomy(output_filename, output, reference_abundance, loaded, tax_options, &reports)?);
+        },
+        None => write_output(output_filename, output)?
+    }
+    summary.add_timing("output", start);
+    summary.write(prefix + "_summary.json")?;
+    Ok(abundance)
+}
+
+/*
+    Run all the samples of a sample sheet against the same references
+
+    the references are read from the header of the first SAM/BAM file and the taxonomy is loaded for all of them once,
+    every sample gets <out_dir>/<sample ID>_abundance.txt, <sample ID>_assignments.txt and <sample ID>_summary.json.
+    In joint mode the mappings of all the samples of a group are loaded before their EMs run with the joint set cover,
+    otherwise every sample is quantified and assigned on its own. A sample without a group is always on its own.
+    matrix_filename: the merged abundances of all samples, <out_dir>/abundance_matrix.tsv if not given
+    config: the settings used for every sample
+    joint: if the valid references are decided jointly for the samples of a group
+    taxonomy: where the taxonomy is read from and how the lineages are written, the assignments have no lineages without it
+    reports: the taxonomy reports written for every sample with the taxonomy, see sample_reports
+*/
+pub fn run_batch(sample_sheet: String, out_dir: String, matrix_filename: Option<String>, config: &MoraConfig, joint: bool,
+                    taxonomy: Option<(TaxonomySource, TaxonomyOptions)>, reports: &TaxonomyReports) -> Result<()> {
+    let samples = read_sample_sheet(&sample_sheet)?;
+    println!("Batch of {} samples from {}", samples.len(), sample_sheet);
+    fs::create_dir_all(&out_dir).map_err(|e| MoraError::io(&out_dir, e))?;
+
+    let start = Instant::now();
+    let index = ReferenceIndex::from_file(&samples[0].file, config.segment_size)?;
+    println!("references loaded in {:.2}s", start.elapsed().as_secs_f64());
+
+    let taxonomy = match taxonomy {
+        Some((source, tax_options)) => {
+            let start = Instant::now();
+            let accessions: HashSet<String> = index.get_references().values().map(|reference| reference.ref_name.to_string()).collect();
+            let loaded = load_taxonomy(&accessions, source, &tax_options)?;
+            println!("taxonomy loaded in {:.2}s", start.elapsed().as_secs_f64());
+            Some((loaded, tax_options))
+        },
+        None => None
+    };
+
+    let mut abundances = HashMap::new();
+    for group in group_samples(&samples, joint) {
+        if group.len() == 1 {
+            let sample = group[0];
+            println!("\nSample {}: {}", sample.id, sample.file);
+            let mut cedar = Cedar::new();
+            cedar.run_parallel(sample.file.to_string(), config, Some(&index))?;
+            abundances.insert(sample.id.to_string(), write_sample_outputs(cedar, sample, &out_dir, config, &taxonomy, reports)?);
+            continue;
+        }
+
+        println!("\nGroup {} with {} samples", group[0].group, group.len());
+        let mut cedars: Vec<Cedar> = Vec::new();
+        for sample in &group {
+            println!("\nSample {}: {}", sample.id, sample.file);
+            let mut cedar = Cedar::new();
+            cedar.load_mappings(sample.file.to_string(), config, Some(&index))?;
+            cedars.push(cedar);
+        }
+        Cedar::run_joint_em(&mut cedars, config);
+        for (cedar, sample) in cedars.into_iter().zip(group) {
+            abundances.insert(sample.id.to_string(), write_sample_outputs(cedar, sample, &out_dir, config, &taxonomy, reports)?);
+        }
+    }
+
+    let matrix_filename = matrix_filename.unwrap_or_else(|| Path::new(&out_dir).join("abundance_matrix.tsv").to_string_lossy().to_string());
+    write_abundance_matrix(&samples, index.get_references(), &abundances, matrix_filename)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TestDir;
+
+    fn write_sheet(dir: &TestDir, name: &str, contents: &str) -> String {
+        let path = dir.path(name);
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn sample_sheet_is_read_in_order() {
+        let dir = TestDir::new();
+        let path = write_sheet(&dir, "sheet.tsv", "# sample\tfile\tgroup\ns1\ts1.bam\tsubject1\n\n s2 \t s2.sam \ns3\ts3.bam\tsubject1\n");
+        let samples = read_sample_sheet(&path).unwrap();
+        let rows: Vec<(&str, &str, &str)> = samples.iter().map(|sample| (sample.id.as_str(), sample.file.as_str(), sample.group.as_str())).collect();
+        assert_eq!(rows, vec![("s1", "s1.bam", "subject1"), ("s2", "s2.sam", ""), ("s3", "s3.bam", "subject1")]);
+    }
+
+    #[test]
+    fn invalid_sample_sheets_are_rejected() {
+        let dir = TestDir::new();
+        for (name, contents, line) in [("no_file.tsv", "s1\ts1.bam\ns2\n", 2), ("duplicate.tsv", "s1\ts1.bam\n# comment\ns1\ts2.bam\n", 3),
+                                        ("empty.tsv", "# sample\tfile\n\n", 0)] {
+            let path = write_sheet(&dir, name, contents);
+            match read_sample_sheet(&path) {
+                Err(MoraError::Parse { line: error_line, .. }) => assert_eq!(error_line, line, "{}", name),
+                _ => panic!("{} should not be read", name)
+            }
+        }
+    }
+
+    #[test]
+    fn ungrouped_samples_are_estimated_alone() {
+        let dir = TestDir::new();
+        let path = write_sheet(&dir, "groups.tsv", "s1\ts1.bam\tsubject1\ns2\ts2.bam\ns3\ts3.bam\tsubject2\ns4\ts4.bam\tsubject1\ns5\ts5.bam\n");
+        let samples = read_sample_sheet(&path).unwrap();
+        let ids = |groups: Vec<Vec<&Sample>>| -> Vec<Vec<String>> { groups.iter().map(|group| group.iter().map(|sample| sample.id.to_string()).collect()).collect() };
+        assert_eq!(ids(group_samples(&samples, true)), vec![vec!["s1", "s4"], vec!["s2"], vec!["s3"], vec!["s5"]]);
+        assert_eq!(ids(group_samples(&samples, false)), vec![vec!["s1"], vec!["s2"], vec!["s3"], vec!["s4"], vec!["s5"]]);
+    }
+}
@@ -1,9 +1,13 @@
 use std::collections::{HashMap, HashSet};
-use std::fs::File;
-use std::io::Write;
+use std::io::{BufWriter, Write};
+use std::time::Instant;
+
+use crate::config::MoraConfig;
+use crate::error::{create_file, write_to, MoraError, Result};
+use crate::summary::{ReadSummary, RunSummary};
 
 mod equivalence_class_builder;
-use equivalence_class_builder::EquivalenceClassBuilder;
+pub use equivalence_class_builder::{EquivalenceClassBuilder, TargetGroup, TGValue};
 
 mod stats;
 use stats::Stats;
@@ -11,10 +15,21 @@ use stats::Stats;
 mod set_covers;
 use set_covers::greedy_set_cover;
 
-pub(crate) mod readers;
-use readers::{read_initial_alignments, Query, Reference};
+pub mod readers;
+use readers::{read_alignments_with_references, read_initial_alignments, Query, Reference};
+
+mod reference_index;
+pub use reference_index::ReferenceIndex;
+
+mod quant;
+pub use quant::{read_abundances, AbundanceRecord};
 
-use self::equivalence_class_builder::TargetGroup;
+mod coverage;
+pub use coverage::CoverageFormat;
+
+mod eq_classes;
+
+mod joint;
 
 mod util;
 
@@ -30,10 +45,8 @@ output: data needed to create an equivalence class
 fn create_eqb(read_per_strain_prob_inst: Vec<(usize, f32)>) -> (TargetGroup, Vec<f32>) {
     // sort the read_per_strain_prob_inst so that the usize is in order of smallest to biggest
     let sorted_vec = util::sort_vec(&read_per_strain_prob_inst);
-    let mut genome_ids = Vec::new();
-    genome_ids.reserve(read_per_strain_prob_inst.len());
-    let mut probs = Vec::new();
-    probs.reserve(read_per_strain_prob_inst.len());
+    let mut genome_ids = Vec::with_capacity(read_per_strain_prob_inst.len());
+    let mut probs = Vec::with_capacity(read_per_strain_prob_inst.len());
 
     for it in sorted_vec {
         genome_ids.push(it.0);
@@ -45,7 +58,7 @@ fn create_eqb(read_per_strain_prob_inst: Vec<(usize, f32)>) -> (TargetGroup, Vec
 
 
 #[derive(Clone)]
-pub(crate) struct Cedar {
+pub struct Cedar {
     /*
     eqb: The equivalence Class Builder
     taxa_node_map: 
@@ -57,6 +70,7 @@ pub(crate) struct Cedar {
     strain_coverage: coverage of each reference
     strain_coverage_bins: the references IDs and their corresponding bin coverages 
     strain_abundance: contains the ref_IDs and their percent abundance
+    strain_valid: contains the ref_IDs and if they are still valid after the EM
     taxa_abundance: contains the taxa_IDs and their abundance
     unampping_reads: the number of queries that are mapped to 0 references
 
@@ -64,12 +78,14 @@ pub(crate) struct Cedar {
     cov: the coverage of each reference 
     queries: the hashmap of query names to queries
     references: the hashmap of ref_ids to references, ref_id starts at 1
+    summary: the read counts, EM information and timings of the run for --summary
     */
     eqb: EquivalenceClassBuilder,
     pub(crate) query_id_2_name: HashMap<usize, String>,
     strain_coverage: HashMap<usize, f32>,
     strain_coverage_bins: HashMap<usize, Vec<usize>>, 
     strain_abundance: HashMap<usize, f32>, 
+    strain_valid: HashMap<usize, bool>,
     read_cnt: usize,
     ref_id_to_tax_id: HashMap<usize, usize>,
     cov: HashMap<usize, usize>,
@@ -77,18 +93,42 @@ pub(crate) struct Cedar {
     references: HashMap<usize, Reference>,
     taxa_abundance: HashMap<usize, f32>,
     unmapping_reads: usize,
+    summary: RunSummary,
+}
+
+impl Default for Cedar {
+    fn default() -> Cedar {
+        Cedar::new()
+    }
 }
 
 impl Cedar {
-    pub(crate) fn new() -> Cedar {
+    pub fn new() -> Cedar {
         println!{"Constructing Cedar"};        
         Cedar { eqb: EquivalenceClassBuilder::new(), strain_coverage: HashMap::new(), 
-            strain_coverage_bins: HashMap::new(), strain_abundance: HashMap::new(), read_cnt: 0, ref_id_to_tax_id: HashMap::new(), query_id_2_name: HashMap::new(), cov: HashMap::new(), 
-            queries: HashMap::new(), references: HashMap::new(), taxa_abundance: HashMap::new(), unmapping_reads: 0 }
+            strain_coverage_bins: HashMap::new(), strain_abundance: HashMap::new(), strain_valid: HashMap::new(), read_cnt: 0, ref_id_to_tax_id: HashMap::new(), query_id_2_name: HashMap::new(), cov: HashMap::new(), 
+            queries: HashMap::new(), references: HashMap::new(), taxa_abundance: HashMap::new(), unmapping_reads: 0, summary: RunSummary::default() }
+    }
+
+    // a Cedar with the queries and the abundances of an EM that is not run, for the tests of the assignment
+    #[cfg(test)]
+    pub(crate) fn with_abundances(references: HashMap<usize, Reference>, queries: HashMap<usize, Query>, strain_abundance: HashMap<usize, f32>) -> Cedar {
+        let query_id_2_name = queries.keys().map(|query_id| (*query_id, format!("query{}", query_id))).collect();
+        let strain_valid = strain_abundance.keys().map(|ref_id| (*ref_id, true)).collect();
+        Cedar { query_id_2_name, queries, references, strain_abundance, strain_valid, ..Cedar::new() }
     }
 
     // find the stats of the current list of queries and also updates the equivalence class builder
-    fn process_reads_parallel(&mut self) -> Stats {
+    fn process_reads_parallel(&mut self, mapper_output_filename: &str) -> Result<Stats> {
+        // every mapping has to be on a reference of the header (or of the first file of a batch)
+        let unknown = self.queries.par_iter().find_map_any(|(query_id, query)| query.mappings.iter()
+            .find(|mapping| !self.references.contains_key(&mapping.get_reference_id()))
+            .map(|mapping| (*query_id, mapping.get_reference_id())));
+        if let Some((query_id, ref_id)) = unknown {
+            return Err(MoraError::UnknownReference { location: format!("{}, query {}", mapper_output_filename, self.query_id_2_name[&query_id]), reference: ref_id.to_string(),
+                                                    known: "the references of the header".to_string() });
+        }
+
         let (total_read_cnt, total_multi_mapped_reads, total_unmapped_reads) = (Mutex::new(0), Mutex::new(0), Mutex::new(0));
         let (sender, receiver) = channel();
  
@@ -101,7 +141,7 @@ impl Cedar {
                 let mut mapping_score = 0;
                 for mapping in &query.mappings {
                     mapping_score += mapping.get_score() as usize;
-                    let value = mapping.get_score() / self.references.get(&mapping.get_reference_id()).unwrap().ref_len as f32;
+                    let value = mapping.get_score() / self.references[&mapping.get_reference_id()].ref_len as f32;
                     read_per_strain_prob_inst.push((mapping.get_reference_id(), value));
                 }
                 // sends sum of the mapping scores and the vector of <references. mapping scores / reference length> of a single query read
@@ -131,21 +171,21 @@ impl Cedar {
             self.eqb.add_group(c.0, c.1);
         });
 
-        Stats::new_with_stats(total_read_cnt.into_inner().unwrap(), total_multi_mapped_reads.into_inner().unwrap(), total_unmapped_reads.into_inner().unwrap())
+        Ok(Stats::new_with_stats(total_read_cnt.into_inner().unwrap(), total_multi_mapped_reads.into_inner().unwrap(), total_unmapped_reads.into_inner().unwrap()))
     }
 
 
     // updates the bins 
     // the mappings in the self.queries[i] are the ones that have a reference_id, so we don't need to check if the reference_id is valid
     fn update_bins(&mut self, segment_size: usize) {
-        for (_, query) in &self.queries {
+        for query in self.queries.values() {
             for mapping in &query.mappings {
                 let bin_number = mapping.get_position() as usize / segment_size;
                 if mapping.get_reference_id() == usize::MAX {
                     continue;
                 }
-                let entry = self.strain_coverage_bins.entry(mapping.get_reference_id()).or_insert(Vec::new());
-                entry[bin_number as usize] += 1;
+                let entry = self.strain_coverage_bins.entry(mapping.get_reference_id()).or_default();
+                entry[bin_number] += 1;
             }
         }
     }
@@ -153,14 +193,14 @@ impl Cedar {
     // updates the coverage of the references 
     fn calculate_coverage(&mut self) {
         for i in self.strain_coverage_bins.keys() {
-            let bins = self.strain_coverage_bins.get(&i).unwrap();
+            let bins = self.strain_coverage_bins.get(i).unwrap();
             let mut covered = 0.0;
             // let mut expression_depth = 0;
-            for j in 0..bins.len() {
-                if bins[j] > 0 {
+            for bin in bins {
+                if *bin > 0 {
                     covered += 1.0;
                 }
-                // expression_depth += bins[j];
+                // expression_depth += bin;
             }
             self.strain_coverage.insert(*i, covered / bins.len() as f32 );
         }
@@ -176,57 +216,56 @@ impl Cedar {
     only_perfect:
     segment_size: the size of each bin
     range_factorization: 
+    pair_mates: if both mates of a fragment are combined into a single mapping per reference
+    index: the references and coverage bins shared by the samples of a batch, read from the header of the file if there is none
     */
-    pub(crate) fn load_mapping_info_parallel(&mut self, mapper_output_filename: String, segment_size: usize, method: String) {
+    pub(crate) fn load_mapping_info_parallel(&mut self, mapper_output_filename: String, segment_size: usize, method: String, pair_mates: bool, index: Option<&ReferenceIndex>) -> Result<()> {
         println!("Cedar: Load Mapping File");
         println!("Mapping Ouput File: {}", mapper_output_filename);
+        if segment_size == 0 {
+            return Err(MoraError::invalid_value("--segment_size", "0", "the bins need at least one base"));
+        }
         
         // load the information from the file
-        let c = read_initial_alignments(mapper_output_filename, method);
-        self.references = c.0;
-        self.queries = c.1;
-        self.query_id_2_name = c.2;
+        let index = match index {
+            Some(index) => {
+                let c = read_alignments_with_references(mapper_output_filename.to_string(), &index.references, method, pair_mates)?;
+                self.queries = c.0;
+                self.query_id_2_name = c.1;
+                index.clone()
+            },
+            None => {
+                let c = read_initial_alignments(mapper_output_filename.to_string(), method, pair_mates)?;
+                self.queries = c.1;
+                self.query_id_2_name = c.2;
+                // constructing coverage bins;
+                ReferenceIndex::new(c.0, segment_size)
+            }
+        };
+        self.references = index.references;
+        self.strain_coverage_bins = index.coverage_bins;
 
         for key in self.references.keys() {
             self.strain_abundance.insert(*key, 0.0);
+            self.ref_id_to_tax_id.insert(*key, *key);
+            self.cov.insert(*key, 0);
         }
 
-        // constructing coverage bins;
-        let (sender, receiver) = channel();
-
-        (0..self.references.len()).into_par_iter().for_each_with(sender, |s, index| {
-            let ref_length = self.references.get(&index).unwrap().ref_len;
-            let bin_cnt = ref_length / segment_size + 1;
-            let bins = vec![0; bin_cnt];
-            let tid = index;
-            let data = (index, bins, tid);
-            s.send(data).ok();
-        });
-
-        let res: Vec<_> = receiver.iter().collect();
-        
-        res.iter().for_each(|x| {
-            let index = x.0;
-            let bins = &x.1;
-            let tid = x.2;
-            self.strain_coverage_bins.insert(index, bins.to_vec());
-            self.ref_id_to_tax_id.insert(index, tid);
-            self.cov.insert(tid, 0);
-        });
-
         // update the information in the Cedar struct
-        let stats = self.process_reads_parallel();
+        let stats = self.process_reads_parallel(&mapper_output_filename)?;
         self.read_cnt = stats.get_total_read_cnt();
         self.update_bins(segment_size);
         self.calculate_coverage();
         self.unmapping_reads = stats.get_total_unmapped_reads();
+        self.summary.reads = ReadSummary { total: stats.get_total_read_cnt(), multi_mapped: stats.get_total_multi_mapped_reads(), unmapped: stats.get_total_unmapped_reads() };
 
         // print the information obtained from the mapping_output_file 
         stats.print_stats();
+        Ok(())
     }
 
     // applying the (greedy) set cover to find the minimum number of references that covers all the equivalence classes
-    fn apply_set_cover(&self, strain_cnt: &Vec<f32>, mut strain_valid: HashMap<usize, bool>, 
+    fn apply_set_cover(&self, strain_cnt: &[f32], mut strain_valid: HashMap<usize, bool>, 
         mut strain_potentially_removable: HashMap<usize, bool>, min_cnt: f32, mut can_help: bool) -> (bool, HashMap<usize, bool>, HashMap<usize, bool>) {
 
         let mut previously_valid:u64 = 0;
@@ -239,7 +278,7 @@ impl Cedar {
             } else {
                 strain_potentially_removable.insert(i, false);
             }
-            if strain_valid[&i] == true {
+            if strain_valid[&i] {
                 previously_valid += 1;
             }
         }
@@ -261,7 +300,7 @@ impl Cedar {
         }
         // if the reference has only one query mapping to it, it is potentially removable
         for i in 1..strain_cnt.len() {
-            if strain_valid[&i] && unique_reads_refs.contains(&i) != true {
+            if strain_valid[&i] && !unique_reads_refs.contains(&i) {
                 strain_potentially_removable.insert(i, true);
             }
         }
@@ -294,7 +333,7 @@ impl Cedar {
                 for read_mapping_cntr in 0..csize {
                     let tgt = tg.get_tgts()[read_mapping_cntr];
                     if strain_valid[&tgt] && strain_potentially_removable[&tgt] {
-                        let entry = ref_2_eqset.entry(tgt).or_insert(HashSet::new());
+                        let entry = ref_2_eqset.entry(tgt).or_default();
                         entry.insert(tg.get_hash());
                     }
                 }
@@ -303,14 +342,14 @@ impl Cedar {
     
         let mut eq_2_id:HashMap<u64, u64> = HashMap::new();         // key: reference ID, value: an ID we use for the greedy algorithm
 
-        if ref_2_eqset.len() > 0 {
+        if !ref_2_eqset.is_empty() {
             // set cover input preparation
             // convert the input to proper format for the library that runs setCover algo. 
             let mut id = 1;
             // turns all the references in ref_2_eqset into a list
             for kv in ref_2_eqset.keys() {
                 for v in &ref_2_eqset[kv] {
-                    if eq_2_id.contains_key(&v) != true { 
+                    if !eq_2_id.contains_key(v) { 
                         eq_2_id.insert(*v, id);
                         id += 1;
                     }
@@ -355,7 +394,7 @@ impl Cedar {
 
             // go over the list of references
             for ref_cntr in 0..strain_valid.len() {
-                if strain_potentially_removable[&ref_cntr] && remaining_refs.contains(&ref_cntr) != true {
+                if strain_potentially_removable[&ref_cntr] && !remaining_refs.contains(&ref_cntr) {
                     strain_valid.insert(ref_cntr, false);
                 }
             }
@@ -372,8 +411,18 @@ impl Cedar {
     }
 
     // the EM function to calculate the abundances
-    pub(crate) fn parallel_em(&mut self, max_iter: usize, eps: f32, min_cnt: f32) {
+    pub fn parallel_em(&mut self, max_iter: usize, eps: f32, min_cnt: f32) {
+        self.em_with_validity(max_iter, eps, min_cnt, None);
+    }
+
+    /*
+        the EM with the valid references either decided by the set cover or given (e.g. by the joint set cover of several samples),
+        given valid references are not changed
+    */
+    fn em_with_validity(&mut self, max_iter: usize, eps: f32, min_cnt: f32, fixed_valid: Option<&HashMap<usize, bool>>) {
         self.eqb.finish();
+        self.summary.em.equivalence_classes = self.eqb.count_map.len();
+        self.summary.em.references = self.references.len();
 
         // finds the maximum sequence ID in the strains.
         let max_seq_id = self.strain_abundance.len();
@@ -382,7 +431,7 @@ impl Cedar {
         let mut strain_potentially_removable:HashMap<usize, bool> = HashMap::new();
 
         for i in 0..(max_seq_id) {
-            strain_valid.insert(i, true);
+            strain_valid.insert(i, fixed_valid.map_or(true, |valid| valid[&i]));
             strain_potentially_removable.insert(i, false);
         }
 
@@ -391,13 +440,14 @@ impl Cedar {
         for (key, value) in &self.strain_abundance {
             strain_cnt[*key] = *value;
         }
+        self.summary.em.valid_references_before_set_cover = strain_cnt.iter().filter(|cnt| **cnt > 0.0).count();
 
         let mut cntr:usize = 0;
         let mut converged = false;
         let thresholding_iter_step = 10;
-        let mut can_help = true;
+        let mut can_help = fixed_valid.is_none();
 
-        while cntr < max_iter && converged == false {
+        while cntr < max_iter && !converged {
             if cntr % thresholding_iter_step == 0 && can_help {
                 let a = self.apply_set_cover(&strain_cnt, strain_valid, strain_potentially_removable, min_cnt, can_help);
                 can_help = a.0;
@@ -416,18 +466,16 @@ impl Cedar {
                 let csize = v.get_weights().len();
                 let mut tmp_read_prob = vec![0.0; csize];
                 let mut denom = 0.0;
-                for read_mapping_cntr in 0..csize { //iterate through the list of strains in this equivalence class
+                for (read_mapping_cntr, prob) in tmp_read_prob.iter_mut().enumerate() { //iterate through the list of strains in this equivalence class
                     let tgt = tg.get_tgts()[read_mapping_cntr];
                     if strain_valid[&tgt] {              // if the strain is valid, update the temp probability (score * current abundance * coverage 
-                        let val = v.get_weights()[read_mapping_cntr] * strain_cnt[tgt] * self.strain_coverage[&tgt];
-                        tmp_read_prob[read_mapping_cntr] = val;
-                        denom += tmp_read_prob[read_mapping_cntr];
+                        *prob = v.get_weights()[read_mapping_cntr] * strain_cnt[tgt] * self.strain_coverage[&tgt];
+                        denom += *prob;
                     }
                 }
-                for read_mapping_cntr in 0..csize {
-                    let tgt = tg.get_tgts()[read_mapping_cntr];
-                    if strain_valid[&tgt] {
-                        s.send((tgt, v.get_count() as f32 * (tmp_read_prob[read_mapping_cntr] / denom))).ok();
+                for (tgt, prob) in tg.get_tgts().iter().zip(&tmp_read_prob) {
+                    if strain_valid[tgt] {
+                        s.send((*tgt, v.get_count() as f32 * (prob / denom))).ok();
                     }
                 }
             });
@@ -459,6 +507,8 @@ impl Cedar {
             }
             cntr += 1;
         }
+        self.summary.em.iterations = cntr;
+        self.summary.em.converged = converged;
         // input results into the strain_abundance variable. 
         let mut output_map = HashMap::new();
         let mut final_read_cnt = 0.0;
@@ -472,7 +522,7 @@ impl Cedar {
 
         for i in self.strain_abundance.keys() {
             final_read_cnt += strain_cnt[*i];
-            if strain_valid[&i] {
+            if strain_valid[i] {
                 output_map.insert(self.ref_id_to_tax_id[i], strain_cnt[*i] / sum);
                 num_of_valids += 1;
                 // update taxa_abundance
@@ -483,51 +533,91 @@ impl Cedar {
             }
         }
         println!("Final Reference-level read cnt: {}, # of valid refs: {}", final_read_cnt, num_of_valids);
+        self.summary.em.valid_references_after_set_cover = num_of_valids;
         
         std::mem::swap(&mut self.strain_abundance, &mut output_map);
+        self.strain_valid = strain_valid;
     }
 
     // outputs file with the references and their estimated abundances
-    pub(crate) fn serialize_simple(&mut self, output_filename: String) {
+    pub fn serialize_simple(&mut self, output_filename: String) -> Result<()> {
         println!("Writing abundances into the file: {}", &output_filename);
 
-        let mut output = File::create(output_filename).unwrap();
+        let mut output = BufWriter::new(create_file(&output_filename)?);
         for i in 0..self.strain_abundance.len(){
             let mut data = i.to_string();
-            data.push_str("\t");
-            data.push_str(&self.references.get(&i).unwrap().ref_name);
-            data.push_str("\t"); 
+            data.push('\t');
+            let reference = self.references.get(&i).ok_or_else(|| MoraError::UnknownReference { location: output_filename.to_string(),
+                reference: i.to_string(), known: "the references of the SAM/BAM file".to_string() })?;
+            data.push_str(&reference.ref_name);
+            data.push('\t'); 
             data.push_str(&self.strain_abundance[&i].to_string());
-            data.push_str("\n");
-            output.write(data.as_bytes()).ok();
+            data.push('\n');
+            write_to(&mut output, &output_filename, data.as_bytes())?;
         }
+        output.flush().map_err(|e| MoraError::io(&output_filename, e))?;
         println!("File has been written");
+        Ok(())
     }
 
-    pub(crate) fn get_queries(&self) -> HashMap<usize, Query> {
+    pub fn get_queries(&self) -> HashMap<usize, Query> {
         self.queries.clone()
     }
 
-    pub(crate) fn get_references(&self) -> HashMap<usize, Reference> {
+    pub fn get_references(&self) -> HashMap<usize, Reference> {
         self.references.clone()
     }
 
-    pub(crate) fn get_strain_abundance(&self) -> HashMap<usize, f32> {
+    pub fn get_strain_abundance(&self) -> HashMap<usize, f32> {
         self.strain_abundance.clone()
     }
 
-    pub(crate) fn get_unmapping_reads(&self) -> usize {
+    // <reference name, estimated abundance>
+    pub fn get_reference_abundances(&self) -> HashMap<String, f32> {
+        self.strain_abundance.iter().map(|(ref_id, abundance)| (self.references[ref_id].ref_name.to_string(), *abundance)).collect()
+    }
+
+    pub fn get_strain_valid(&self) -> HashMap<usize, bool> {
+        self.strain_valid.clone()
+    }
+
+    pub fn get_unmapping_reads(&self) -> usize {
         self.unmapping_reads
     }
 
-    // main function for Cedar
-    pub(crate) fn run_parallel(&mut self, mapper_output_name: String, 
-        max_iter: usize,
-        eps: f32,
-        min_cnt: f32,
-        segment_size: usize,
-        method: String) {
-            self.load_mapping_info_parallel(mapper_output_name, segment_size, method);
-            self.parallel_em(max_iter, eps, min_cnt);
+    pub fn get_summary(&self) -> RunSummary {
+        self.summary.clone()
+    }
+
+    pub fn get_equivalence_classes(&self) -> &EquivalenceClassBuilder {
+        &self.eqb
+    }
+
+    // main function for Cedar: loads the mappings of a SAM/BAM file and estimates the abundances with the settings of config
+    pub fn run_parallel(&mut self, mapper_output_name: String, config: &MoraConfig, index: Option<&ReferenceIndex>) -> Result<()> {
+        self.load_mappings(mapper_output_name, config, index)?;
+        let start = Instant::now();
+        self.parallel_em(config.max_em_iterations, config.eps, config.min_cnt);
+        self.summary.add_timing("em", start);
+        Ok(())
+    }
+
+    // loads the mappings without running the EM, e.g. for the joint EM of several samples
+    pub fn load_mappings(&mut self, mapper_output_name: String, config: &MoraConfig, index: Option<&ReferenceIndex>) -> Result<()> {
+        let start = Instant::now();
+        self.load_mapping_info_parallel(mapper_output_name, config.segment_size, config.mapping_method.to_string(), config.pair_mates, index)?;
+        self.summary.add_timing("load_mappings", start);
+        Ok(())
+    }
+
+    // runs the EM on equivalence classes written by --dump_eq instead of the mappings of a SAM file
+    pub fn run_from_equivalence_classes(&mut self, eq_filename: String, config: &MoraConfig) -> Result<()> {
+        let start = Instant::now();
+        self.load_equivalence_classes(eq_filename)?;
+        self.summary.add_timing("load_equivalence_classes", start);
+        let start = Instant::now();
+        self.parallel_em(config.max_em_iterations, config.eps, config.min_cnt);
+        self.summary.add_timing("em", start);
+        Ok(())
     }
 }
@@ -0,0 +1,212 @@
+use std::collections::HashMap;
+use std::io::{BufWriter, Write};
+
+use super::Cedar;
+use super::readers::{Mapping, Query};
+use crate::error::{create_file, write_to, MoraError, Result};
+
+/*
+    How the coverage profiles are written
+
+    BedGraph: one line per bin with the mean depth: name  start  end  depth
+    Tsv: one line per reference with the read starts and the mean depth of every bin as comma separated lists
+*/
+#[derive(Clone, Copy)]
+pub enum CoverageFormat {
+    BedGraph,
+    Tsv,
+}
+
+impl CoverageFormat {
+    pub fn new(format: &str) -> CoverageFormat {
+        match format {
+            "tsv" => CoverageFormat::Tsv,
+            _ => CoverageFormat::BedGraph
+        }
+    }
+}
+
+impl Cedar {
+    /*
+        the mappings of a read with the share of the read the EM gives them: score / reference length * abundance * coverage
+        of the valid references, normalized over the mappings of the read, so a multi-mapped read adds up to one read
+        OUTPUT: empty if the read has no mapping to a valid reference with an abundance
+    */
+    fn em_responsibilities<'a>(&self, query: &'a Query) -> Vec<(&'a Mapping, f64)> {
+        let weights: Vec<(&Mapping, f64)> = query.mappings.iter().filter_map(|mapping| {
+            let ref_id = mapping.get_reference_id();
+            let reference = self.references.get(&ref_id)?;
+            if !*self.strain_valid.get(&ref_id).unwrap_or(&false) || reference.ref_len == 0 {
+                return None;
+            }
+            let abundance = *self.ref_id_to_tax_id.get(&ref_id).and_then(|tax_id| self.strain_abundance.get(tax_id)).unwrap_or(&0.0);
+            let coverage = *self.strain_coverage.get(&ref_id).unwrap_or(&0.0);
+            Some((mapping, mapping.get_score() as f64 / reference.ref_len as f64 * abundance as f64 * coverage as f64))
+        }).collect();
+        let denom: f64 = weights.iter().map(|(_, weight)| weight).sum();
+        if denom <= 0.0 || !denom.is_finite() {
+            return Vec::new();
+        }
+        weights.into_iter().map(|(mapping, weight)| (mapping, weight / denom)).collect()
+    }
+
+    /*
+        aligned bases in each bin of each reference, each mapping of a read weighted by its EM responsibility
+        the aligned bases of a mapping are spread evenly over the reference positions the alignment spans
+    */
+    fn aligned_bases_bins(&self, segment_size: usize) -> HashMap<usize, Vec<f64>> {
+        let mut bins: HashMap<usize, Vec<f64>> = HashMap::new();
+        for query in self.queries.values() {
+            for (mapping, responsibility) in self.em_responsibilities(query) {
+                let ref_id = mapping.get_reference_id();
+                let reference = match self.references.get(&ref_id) {
+                    Some(reference) => reference,
+                    None => continue
+                };
+                let entry = bins.entry(ref_id).or_insert_with(|| vec![0.0; reference.ref_len / segment_size + 1]);
+                let (start, end) = (mapping.get_position().max(0) as usize, mapping.get_end_position().max(0) as usize);
+                if end <= start {
+                    continue;
+                }
+                let bases_per_position = responsibility * mapping.get_aligned_bases() as f64 / (end - start) as f64;
+                let mut position = start;
+                while position < end {
+                    let bin = position / segment_size;
+                    if bin >= entry.len() {
+                        break;
+                    }
+                    let bin_end = ((bin + 1) * segment_size).min(end);
+                    entry[bin] += (bin_end - position) as f64 * bases_per_position;
+                    position = bin_end;
+                }
+            }
+        }
+        bins
+    }
+
+    /*
+        writes the coverage profiles of the references with at least min_reads reads
+        the read counts are the read starts of all the mappings per bin in strain_coverage_bins, the depth is the number
+        of aligned bases in a bin divided by the length of the bin, with the mappings weighted by their EM responsibilities
+    */
+    pub fn write_coverage(&self, output_filename: String, format: CoverageFormat, segment_size: usize, min_reads: usize) -> Result<()> {
+        if segment_size == 0 {
+            return Err(MoraError::invalid_value("--segment_size", "0", "the bins need at least one base"));
+        }
+        println!("Writing coverage profiles into the file: {}", &output_filename);
+        let aligned_bases = self.aligned_bases_bins(segment_size);
+        let mut ref_ids: Vec<&usize> = self.strain_coverage_bins.keys().collect();
+        ref_ids.sort();
+
+        let mut output = BufWriter::new(create_file(&output_filename)?);
+        if let CoverageFormat::Tsv = format {
+            write_to(&mut output, &output_filename, b"Name\tLength\tBinSize\tReads\tReadStarts\tMeanDepth\n")?;
+        }
+        let mut written = 0;
+        for ref_id in ref_ids {
+            let read_starts = &self.strain_coverage_bins[ref_id];
+            let reads: usize = read_starts.iter().sum();
+            if reads < min_reads {
+                continue;
+            }
+            let reference = &self.references[ref_id];
+            let bin_length = |bin: usize| (reference.ref_len.min((bin + 1) * segment_size)).saturating_sub(bin * segment_size).max(1);
+            let depths: Vec<f64> = match aligned_bases.get(ref_id) {
+                Some(bases) => bases.iter().enumerate().map(|(bin, bases)| bases / bin_length(bin) as f64).collect(),
+                None => vec![0.0; read_starts.len()]
+            };
+
+            match format {
+                CoverageFormat::BedGraph => {
+                    for (bin, depth) in depths.iter().enumerate() {
+                        let start = bin * segment_size;
+                        let end = reference.ref_len.min(start + segment_size);
+                        if start >= end {
+                            continue;
+                        }
+                        write_to(&mut output, &output_filename, format!("{}\t{}\t{}\t{:.4}\n", reference.ref_name, start, end, depth).as_bytes())?;
+                    }
+                },
+                CoverageFormat::Tsv => {
+                    let read_starts: Vec<String> = read_starts.iter().map(|cnt| cnt.to_string()).collect();
+                    let depths: Vec<String> = depths.iter().map(|depth| format!("{:.4}", depth)).collect();
+                    let data = format!("{}\t{}\t{}\t{}\t{}\t{}\n", reference.ref_name, reference.ref_len, segment_size, reads,
+                                        read_starts.join(","), depths.join(","));
+                    write_to(&mut output, &output_filename, data.as_bytes())?;
+                }
+            }
+            written += 1;
+        }
+        output.flush().map_err(|e| MoraError::io(&output_filename, e))?;
+        println!("coverage profiles written for {} references", written);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+    use crate::cedar::readers::{Mate, Reference};
+    use crate::test_util::TestDir;
+
+    /*
+        a: 250 bases, one read on the first bin and one shared read spanning the last 100 bases with 80 aligned bases
+        b: 100 bases, the other mapping of the shared read, with the same score / length * abundance as on a
+        c: 50 bases, removed by the set cover, with one read
+    */
+    fn cedar() -> Cedar {
+        let mut cedar = Cedar::new();
+        for (ref_id, name, ref_len, abundance, valid, read_starts) in [(0, "a", 250, 0.5, true, vec![1, 1, 0]), (1, "b", 100, 0.2, true, vec![1, 0]),
+                                                                       (2, "c", 50, 0.0, false, vec![1])] {
+            cedar.references.insert(ref_id, Reference { ref_len, ref_name: name.to_string() });
+            cedar.ref_id_to_tax_id.insert(ref_id, ref_id);
+            cedar.strain_abundance.insert(ref_id, abundance);
+            cedar.strain_valid.insert(ref_id, valid);
+            cedar.strain_coverage.insert(ref_id, 1.0);
+            cedar.strain_coverage_bins.insert(ref_id, read_starts);
+        }
+        // (reference, start, end, aligned bases) of the mappings of each read
+        for (query_id, mappings) in [(0, vec![(0, 0, 100, 100)]), (1, vec![(0, 150, 250, 80), (1, 0, 100, 100)]), (2, vec![(2, 0, 50, 50)])] {
+            let mappings: HashSet<Mapping> = mappings.iter().map(|(ref_id, start, end, aligned_bases)| Mapping::new(*ref_id, 100, *start, *end, *aligned_bases, false, Mate::Single)).collect();
+            cedar.queries.insert(query_id, Query::new(query_id, mappings.len(), 100, mappings, false));
+        }
+        cedar
+    }
+
+    fn write(format: CoverageFormat, min_reads: usize) -> String {
+        let dir = TestDir::new();
+        let output = dir.path("coverage");
+        cedar().write_coverage(output.clone(), format, 100, min_reads).unwrap();
+        std::fs::read_to_string(&output).unwrap()
+    }
+
+    #[test]
+    fn shared_reads_are_weighted_by_their_responsibilities() {
+        let cedar = cedar();
+        let responsibilities: Vec<(usize, f64)> = cedar.em_responsibilities(&cedar.queries[&1]).iter().map(|(mapping, responsibility)| (mapping.get_reference_id(), *responsibility)).collect();
+        assert_eq!(responsibilities.len(), 2);
+        assert!(responsibilities.iter().all(|(_, responsibility)| (responsibility - 0.5).abs() < 1e-6));
+        // no mapping on a valid reference
+        assert!(cedar.em_responsibilities(&cedar.queries[&2]).is_empty());
+    }
+
+    #[test]
+    fn bedgraph_intervals_have_the_depth_of_the_aligned_bases() {
+        // half of the 80 aligned bases over 100 positions: 20 bases in [100, 200) and 20 bases in the 50 bases of [200, 250)
+        assert_eq!(write(CoverageFormat::BedGraph, 1), "a\t0\t100\t1.0000\na\t100\t200\t0.2000\na\t200\t250\t0.4000\n\
+                                                        b\t0\t100\t0.5000\nc\t0\t50\t0.0000\n");
+    }
+
+    #[test]
+    fn tsv_profiles_of_the_references_with_enough_reads() {
+        assert_eq!(write(CoverageFormat::Tsv, 2), "Name\tLength\tBinSize\tReads\tReadStarts\tMeanDepth\na\t250\t100\t2\t1,1,0\t1.0000,0.2000,0.4000\n");
+    }
+
+    #[test]
+    fn bins_need_a_size() {
+        let dir = TestDir::new();
+        assert!(matches!(cedar().write_coverage(dir.path("coverage"), CoverageFormat::Tsv, 0, 0), Err(MoraError::InvalidValue { .. })));
+    }
+}
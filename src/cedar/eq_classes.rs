@@ -0,0 +1,208 @@
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+
+use super::Cedar;
+use super::equivalence_class_builder::TargetGroup;
+use super::readers::Reference;
+use crate::error::{create_file, open_file, parse_field, write_to, MoraError, Result};
+
+// the reference table written next to the equivalence classes
+fn reference_table_filename(eq_filename: &str) -> String {
+    eq_filename.to_string() + ".refs.tsv"
+}
+
+impl Cedar {
+    /*
+        writes the equivalence classes in salmon's eq_classes format:
+        number of references
+        number of equivalence classes
+        reference names, one per line in order of their ID
+        one line per equivalence class: number of references  reference IDs  normalized weights  number of reads
+
+        and the reference table (eq_filename.refs.tsv) with what the EM needs besides the equivalence classes:
+        #reads, #multi_mapped_reads and #unmapped_reads lines followed by the columns ID  Name  Length  CoverageBreadth
+    */
+    pub fn dump_equivalence_classes(&self, eq_filename: String) -> Result<()> {
+        println!("Writing equivalence classes into the file: {}", &eq_filename);
+        let mut ref_ids: Vec<&usize> = self.references.keys().collect();
+        ref_ids.sort();
+
+        let mut output = BufWriter::new(create_file(&eq_filename)?);
+        write_to(&mut output, &eq_filename, format!("{}\n{}\n", ref_ids.len(), self.eqb.count_map.len()).as_bytes())?;
+        for ref_id in &ref_ids {
+            write_to(&mut output, &eq_filename, format!("{}\n", self.references[ref_id].ref_name).as_bytes())?;
+        }
+        let mut eq_classes: Vec<_> = self.eqb.count_map.iter().collect();
+        eq_classes.sort_by(|a, b| a.0.get_tgts().cmp(b.0.get_tgts()));
+        for (tg, val) in eq_classes {
+            let mut line = vec![tg.get_tgts().len().to_string()];
+            line.extend(tg.get_tgts().iter().map(|tgt| tgt.to_string()));
+            line.extend(val.get_weights().iter().map(|weight| weight.to_string()));
+            line.push(val.get_count().to_string());
+            write_to(&mut output, &eq_filename, (line.join("\t") + "\n").as_bytes())?;
+        }
+        output.flush().map_err(|e| MoraError::io(&eq_filename, e))?;
+
+        let table_filename = reference_table_filename(&eq_filename);
+        let mut output = BufWriter::new(create_file(&table_filename)?);
+        write_to(&mut output, &table_filename, format!("#reads\t{}\n#multi_mapped_reads\t{}\n#unmapped_reads\t{}\n", self.read_cnt, self.summary.reads.multi_mapped, self.unmapping_reads).as_bytes())?;
+        write_to(&mut output, &table_filename, b"ID\tName\tLength\tCoverageBreadth\n")?;
+        for ref_id in ref_ids {
+            let reference = &self.references[ref_id];
+            let data = format!("{}\t{}\t{}\t{}\n", ref_id, reference.ref_name, reference.ref_len, self.strain_coverage.get(ref_id).unwrap_or(&0.0));
+            write_to(&mut output, &table_filename, data.as_bytes())?;
+        }
+        output.flush().map_err(|e| MoraError::io(&table_filename, e))
+    }
+
+    /*
+        loads equivalence classes written by dump_equivalence_classes, so that the EM can be rerun without reading the SAM file again
+        the initial abundances are built the same way as from the reads: every read adds 1 / (number of references) to its references
+    */
+    pub(crate) fn load_equivalence_classes(&mut self, eq_filename: String) -> Result<()> {
+        println!("Cedar: Load Equivalence Classes");
+        println!("Equivalence class file: {}", eq_filename);
+
+        let table_filename = reference_table_filename(&eq_filename);
+        let f = open_file(&table_filename)?;
+        for (line_number, line) in BufReader::new(f).lines().enumerate() {
+            let line = line.map_err(|e| MoraError::io(&table_filename, e))?;
+            let line_number = line_number + 1;
+            let chunks: Vec<&str> = line.split('\t').collect();
+            let field = |i: usize| chunks.get(i).copied();
+            match chunks[0] {
+                "#reads" => self.read_cnt = parse_field(field(1), &table_filename, line_number, "#reads")?,
+                "#multi_mapped_reads" => self.summary.reads.multi_mapped = parse_field(field(1), &table_filename, line_number, "#multi_mapped_reads")?,
+                "#unmapped_reads" => self.unmapping_reads = parse_field(field(1), &table_filename, line_number, "#unmapped_reads")?,
+                "ID" => continue,
+                _ => {
+                    let ref_id: usize = parse_field(field(0), &table_filename, line_number, "ID")?;
+                    let ref_name = field(1).ok_or_else(|| MoraError::parse(&table_filename, line_number, "no Name column"))?;
+                    let ref_len = parse_field(field(2), &table_filename, line_number, "Length")?;
+                    self.references.insert(ref_id, Reference { ref_len, ref_name: ref_name.to_string() });
+                    self.strain_coverage.insert(ref_id, parse_field(field(3), &table_filename, line_number, "CoverageBreadth")?);
+                    self.strain_abundance.insert(ref_id, 0.0);
+                    self.ref_id_to_tax_id.insert(ref_id, ref_id);
+                    self.cov.insert(ref_id, 0);
+                }
+            }
+        }
+
+        let f = open_file(&eq_filename)?;
+        let mut lines = BufReader::new(f).lines();
+        let mut line_number = 0;
+        let mut next_line = || -> Result<String> {
+            line_number += 1;
+            match lines.next() {
+                Some(line) => line.map_err(|e| MoraError::io(&eq_filename, e)),
+                None => Err(MoraError::parse(&eq_filename, line_number, "the file ends before all equivalence classes were read")),
+            }
+        };
+        let ref_cnt: usize = parse_field(Some(&next_line()?), &eq_filename, 1, "number of references")?;
+        let eq_cnt: usize = parse_field(Some(&next_line()?), &eq_filename, 2, "number of equivalence classes")?;
+        if ref_cnt != self.references.len() {
+            return Err(MoraError::parse(&eq_filename, 1, format!("{} references but the reference table has {}", ref_cnt, self.references.len())));
+        }
+        // the names are already known from the reference table
+        for _ in 0..ref_cnt {
+            next_line()?;
+        }
+
+        let mut initial_cnt: HashMap<usize, f32> = HashMap::new();
+        for i in 0..eq_cnt {
+            let line = next_line()?;
+            let line_number = ref_cnt + 3 + i;
+            let chunks: Vec<&str> = line.split('\t').collect();
+            let field = |i: usize| chunks.get(i).copied();
+            let size: usize = parse_field(field(0), &eq_filename, line_number, "number of references")?;
+            let tgts = (1..=size).map(|i| parse_field(field(i), &eq_filename, line_number, "reference ID")).collect::<Result<Vec<usize>>>()?;
+            let weights = (size + 1..=2 * size).map(|i| parse_field(field(i), &eq_filename, line_number, "weight")).collect::<Result<Vec<f32>>>()?;
+            let count: usize = parse_field(field(2 * size + 1), &eq_filename, line_number, "number of reads")?;
+            if let Some(tgt) = tgts.iter().find(|tgt| !self.references.contains_key(tgt)) {
+                return Err(MoraError::UnknownReference { location: format!("{} line {}", eq_filename, line_number), reference: tgt.to_string(), known: table_filename.to_string() });
+            }
+            for tgt in &tgts {
+                *initial_cnt.entry(*tgt).or_insert(0.0) += count as f32 / size as f32;
+            }
+            self.eqb.add_group_with_count(TargetGroup::new_with_tgt(tgts), &weights, count);
+        }
+        for (ref_id, cnt) in initial_cnt {
+            self.strain_abundance.insert(ref_id, cnt);
+        }
+        self.summary.reads.total = self.read_cnt;
+        self.summary.reads.unmapped = self.unmapping_reads;
+        println!("references: {}, equivalence classes: {}", ref_cnt, eq_cnt);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TestDir;
+
+    // three references, one read only on the first, the others shared with the second and between the second and the third
+    fn cedar() -> Cedar {
+        let mut cedar = Cedar::new();
+        for (ref_id, name, ref_len, coverage) in [(0, "a", 1000, 1.0), (1, "b", 1000, 0.5), (2, "c", 500, 0.2)] {
+            cedar.references.insert(ref_id, Reference { ref_len, ref_name: name.to_string() });
+            cedar.strain_coverage.insert(ref_id, coverage);
+            cedar.strain_abundance.insert(ref_id, 0.0);
+            cedar.ref_id_to_tax_id.insert(ref_id, ref_id);
+            cedar.cov.insert(ref_id, 0);
+        }
+        for (tgts, weights, count) in [(vec![0], vec![0.1], 10), (vec![0, 1], vec![0.1, 0.05], 5), (vec![1, 2], vec![0.1, 0.2], 3)] {
+            for tgt in &tgts {
+                *cedar.strain_abundance.get_mut(tgt).unwrap() += count as f32 / tgts.len() as f32;
+            }
+            cedar.eqb.add_group_with_count(TargetGroup::new_with_tgt(tgts), &weights, count);
+        }
+        cedar.read_cnt = 20;
+        cedar.unmapping_reads = 2;
+        cedar
+    }
+
+    #[test]
+    fn dump_load_em_round_trip() {
+        let dir = TestDir::new();
+        let eq_filename = dir.path("round_trip.eq");
+        let mut original = cedar();
+        original.dump_equivalence_classes(eq_filename.to_string()).unwrap();
+
+        let mut loaded = Cedar::new();
+        loaded.load_equivalence_classes(eq_filename.to_string()).unwrap();
+        assert_eq!((loaded.read_cnt, loaded.unmapping_reads), (20, 2));
+        for (ref_id, reference) in &original.references {
+            assert_eq!(loaded.references[ref_id].ref_name, reference.ref_name);
+            assert_eq!(loaded.references[ref_id].ref_len, reference.ref_len);
+            assert_eq!(loaded.strain_coverage[ref_id], original.strain_coverage[ref_id]);
+        }
+        assert_eq!(loaded.strain_abundance, original.strain_abundance);
+        assert_eq!(loaded.eqb.count_map.len(), original.eqb.count_map.len());
+        for (tg, val) in &original.eqb.count_map {
+            let loaded_val = &loaded.eqb.count_map[tg];
+            assert_eq!(loaded_val.get_count(), val.get_count());
+            assert_eq!(loaded_val.get_weights(), val.get_weights());
+        }
+
+        original.parallel_em(100, 0.001, 0.0);
+        loaded.parallel_em(100, 0.001, 0.0);
+        for (ref_id, abundance) in &original.strain_abundance {
+            assert!((loaded.strain_abundance[ref_id] - abundance).abs() < 1e-6);
+        }
+        assert_eq!(loaded.strain_valid, original.strain_valid);
+    }
+
+    #[test]
+    fn unknown_reference_is_rejected() {
+        let dir = TestDir::new();
+        let eq_filename = dir.path("unknown_reference.eq");
+        let original = cedar();
+        original.dump_equivalence_classes(eq_filename.to_string()).unwrap();
+        let contents = std::fs::read_to_string(&eq_filename).unwrap().replace("2\t1\t2\t", "2\t1\t7\t");
+        std::fs::write(&eq_filename, contents).unwrap();
+
+        let result = Cedar::new().load_equivalence_classes(eq_filename.to_string());
+        assert!(matches!(result, Err(MoraError::UnknownReference { .. })));
+    }
+}
@@ -9,8 +9,8 @@ fn calculate_hash<T: Hash>(t: &T) -> u64 {
     s.finish()
 }
 
-#[derive(Eq, Hash, Clone)]
-pub(crate) struct TargetGroup{
+#[derive(Eq, Clone)]
+pub struct TargetGroup{
     /*
     tgts: A vector of potential targets in the references
     hash: the hash value?
@@ -23,7 +23,7 @@ pub(crate) struct TargetGroup{
 
 impl TargetGroup{
     //The different constructors for TargetGroup
-    pub(crate) fn new_with_tgt(tgts: Vec<usize>) -> TargetGroup {
+    pub fn new_with_tgt(tgts: Vec<usize>) -> TargetGroup {
         let hash_value = calculate_hash(&tgts);
         TargetGroup{
             tgts, hash: hash_value, valid: true
@@ -53,8 +53,15 @@ impl PartialEq for TargetGroup{
         self.tgts == other.tgts  
     }
 }
+
+// hashes only the targets, like eq, with the hash computed from them when the group was created
+impl Hash for TargetGroup {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        state.write_u64(self.hash);
+    }
+}
 #[derive(Clone)]
-pub(crate) struct TGValue {
+pub struct TGValue {
     /*
     weights: vector over the references in the equivalence class, with value
         sum over reads of [score of read to this reference] / self.references[mapping.get_reference_id()].ref_len as f32
@@ -106,21 +113,31 @@ impl TGValue {
 }
 
 #[derive(Clone)]
-pub(crate) struct EquivalenceClassBuilder {
+pub struct EquivalenceClassBuilder {
     //active_: whether the EquivalenceClassBuilder is active or not
     //count_map: a HashMap containing the TargetGroups and their TGValues
     active_: bool,
     pub(crate) count_map: HashMap<TargetGroup, TGValue>,
 }
 
+impl Default for EquivalenceClassBuilder {
+    fn default() -> EquivalenceClassBuilder {
+        EquivalenceClassBuilder::new()
+    }
+}
+
 impl EquivalenceClassBuilder {
 
-    pub(crate) fn new() -> EquivalenceClassBuilder {
+    pub fn new() -> EquivalenceClassBuilder {
         EquivalenceClassBuilder { active_: false, count_map: HashMap::new() }
     }
 
+    pub fn get_count_map(&self) -> &HashMap<TargetGroup, TGValue> {
+        &self.count_map
+    }
+
     //ends the EquivalenceClassBuilder
-    pub(crate) fn finish(&mut self) {
+    pub fn finish(&mut self) {
         self.active_ = false;
         let mut total_count = 0;
         let mut new_map = HashMap::new();
@@ -134,14 +151,28 @@ impl EquivalenceClassBuilder {
         println!("Counted {} total reads in {} equivalence classes", total_count, self.count_map.len());
     }
 
+    // Adds a group with the weights and the number of reads of an equivalence class that was built before (e.g. read from a file
+    // or of another sample), the weights and counts are added to the ones of an existing entry
+    pub fn add_group_with_count(&mut self, g: TargetGroup, weights: &[f32], count: usize) {
+        match self.count_map.get_mut(&g) {
+            Some(val) => {
+                val.weights.iter_mut().zip(weights).for_each(|(weight, other)| *weight += other);
+                val.count += count;
+            },
+            None => {
+                self.count_map.insert(g, TGValue::new_with_tgvalue_count(weights.to_vec(), count));
+            }
+        }
+    }
+
     // Adds a group to the count_map with the weights if the target group is not in count_map,
     // otherwise, it adds the weights to the weights of the existing entry. 
-    pub(crate) fn add_group(&mut self, g: TargetGroup, weights: Vec<f32>) {
+    pub fn add_group(&mut self, g: TargetGroup, weights: Vec<f32>) {
         if self.count_map.contains_key(&g) {
             let mut tg_val = TGValue::new_from(self.count_map.get(&g).unwrap());
             tg_val.count += 1;
-            for i in 0..tg_val.weights.len() {
-                tg_val.weights[i] += weights[i];
+            for (weight, other) in tg_val.weights.iter_mut().zip(&weights) {
+                *weight += other;
             }
             self.count_map.insert(g, tg_val);
         } else {
@@ -0,0 +1,121 @@
+use std::time::Instant;
+
+use super::Cedar;
+use crate::config::MoraConfig;
+use crate::summary::PhaseTiming;
+
+impl Cedar {
+    /*
+        Cedar over the union of the equivalence classes of samples mapped against the same references,
+        only used to decide which references are valid
+
+        the weights and read counts of equivalence classes found in several samples are added up, so the union is
+        the same as the equivalence classes of all the reads together. The coverage of a reference is its best coverage
+        in any sample, so a reference that is clearly present in one sample is not removed because of low-depth samples.
+    */
+    fn new_joint(samples: &[Cedar]) -> Cedar {
+        let mut joint = Cedar::new();
+        joint.references = samples[0].references.clone();
+        for ref_id in joint.references.keys() {
+            joint.strain_abundance.insert(*ref_id, 0.0);
+            joint.strain_coverage.insert(*ref_id, 0.0);
+            joint.ref_id_to_tax_id.insert(*ref_id, *ref_id);
+            joint.cov.insert(*ref_id, 0);
+        }
+        for sample in samples {
+            for (tg, val) in &sample.eqb.count_map {
+                joint.eqb.add_group_with_count(tg.clone(), val.get_weights(), val.get_count());
+            }
+            // the samples share their references, a reference of another sample would not be in the joint cedar
+            for (ref_id, cnt) in &sample.strain_abundance {
+                if let Some(entry) = joint.strain_abundance.get_mut(ref_id) {
+                    *entry += cnt;
+                }
+            }
+            for (ref_id, coverage) in &sample.strain_coverage {
+                if let Some(entry) = joint.strain_coverage.get_mut(ref_id) {
+                    *entry = entry.max(*coverage);
+                }
+            }
+            joint.read_cnt += sample.read_cnt;
+            joint.unmapping_reads += sample.unmapping_reads;
+        }
+        joint
+    }
+
+    /*
+        Joint estimation for samples whose mappings are loaded but whose EM has not run yet:
+        the set cover is applied once with the EM over the union of their equivalence classes and every sample
+        then runs its own EM on its own equivalence classes with the references that are valid for the union
+    */
+    pub fn run_joint_em(samples: &mut [Cedar], config: &MoraConfig) {
+        println!("Cedar: Joint EM of {} samples", samples.len());
+        let start = Instant::now();
+        let mut joint = Cedar::new_joint(samples);
+        joint.parallel_em(config.max_em_iterations, config.eps, config.min_cnt);
+        let joint_time = start.elapsed().as_secs_f64();
+        println!("valid references of the joint set cover: {}", joint.strain_valid.values().filter(|valid| **valid).count());
+
+        for sample in samples.iter_mut() {
+            let start = Instant::now();
+            sample.em_with_validity(config.max_em_iterations, config.eps, config.min_cnt, Some(&joint.strain_valid));
+            sample.summary.em.joint_set_cover = true;
+            sample.summary.timings.push(PhaseTiming { phase: "joint_set_cover".to_string(), seconds: joint_time });
+            sample.summary.add_timing("em", start);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cedar::equivalence_class_builder::TargetGroup;
+    use crate::cedar::readers::Reference;
+
+    // a sample over the references 0, 1 and 2 with the read counts of its equivalence classes and the coverage of each reference
+    fn sample(classes: &[(Vec<usize>, usize)], coverage: [f32; 3]) -> Cedar {
+        let mut cedar = Cedar::new();
+        for (ref_id, name) in ["a", "b", "c"].iter().enumerate() {
+            cedar.references.insert(ref_id, Reference { ref_len: 1000, ref_name: name.to_string() });
+            cedar.strain_coverage.insert(ref_id, coverage[ref_id]);
+            cedar.strain_abundance.insert(ref_id, 0.0);
+            cedar.ref_id_to_tax_id.insert(ref_id, ref_id);
+            cedar.cov.insert(ref_id, 0);
+        }
+        for (tgts, count) in classes {
+            for tgt in tgts {
+                *cedar.strain_abundance.get_mut(tgt).unwrap() += *count as f32 / tgts.len() as f32;
+            }
+            cedar.eqb.add_group_with_count(TargetGroup::new_with_tgt(tgts.clone()), &vec![0.1; tgts.len()], *count);
+            cedar.read_cnt += count;
+        }
+        cedar
+    }
+
+    // the approximate abundances of the references 0, 1 and 2
+    fn abundances(cedar: &Cedar) -> Vec<f32> {
+        (0..3).map(|ref_id| (cedar.strain_abundance[&ref_id] * 1000.0).round() / 1000.0).collect()
+    }
+
+    #[test]
+    fn a_reference_supported_in_one_sample_is_kept_in_the_others() {
+        // b has unique reads in the first sample, in the second it only shares its reads with c and the set cover keeps c
+        let first = || sample(&[(vec![0], 20), (vec![1], 10), (vec![2], 10)], [1.0, 1.0, 1.0]);
+        let second = || sample(&[(vec![0], 20), (vec![1, 2], 10)], [1.0, 1.0, 0.2]);
+
+        let mut alone = second();
+        alone.parallel_em(300, 0.001, 0.1);
+        assert!(!alone.strain_valid[&1]);
+        assert_eq!(abundances(&alone), vec![0.667, 0.0, 0.333]);
+
+        let mut samples = vec![first(), second()];
+        Cedar::run_joint_em(&mut samples, &MoraConfig::default());
+        for sample in &samples {
+            assert!(sample.strain_valid.values().all(|valid| *valid));
+            assert!(sample.summary.em.joint_set_cover);
+        }
+        // every sample runs the EM on its own reads
+        assert_eq!(abundances(&samples[0]), vec![0.5, 0.25, 0.25]);
+        assert_eq!(abundances(&samples[1]), vec![0.667, 0.333, 0.0]);
+    }
+}
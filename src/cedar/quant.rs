@@ -0,0 +1,244 @@
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+
+use super::Cedar;
+use crate::error::{create_file, open_file, parse_field, write_to, MoraError, Result};
+
+/*
+    The estimate of a reference in the abundance table
+
+    abundance: the estimated relative abundance
+    num_reads: the number of reads the EM assigned to the reference
+    tpm: the abundance per length normalized to a million, as in salmon's quant.sf
+    coverage_breadth: the fraction of bins of the reference with at least one read
+    unique_reads: the number of reads that map to only this reference
+    valid: if the reference was kept by the set cover
+*/
+#[derive(Clone, Debug)]
+pub struct AbundanceRecord {
+    pub reference_id: usize,
+    pub name: String,
+    pub length: usize,
+    pub abundance: f32,
+    pub num_reads: f32,
+    pub tpm: f32,
+    pub coverage_breadth: f32,
+    pub unique_reads: usize,
+    pub valid: bool,
+}
+
+/*
+    reads the abundances of a previous run, written with --abund_tsv or with -a
+    the references of a -a file have no status, so they are valid if their abundance is above 0
+    OUTPUT: <reference name, (abundance, valid)>
+*/
+pub fn read_abundances(file_name: &str) -> Result<HashMap<String, (f32, bool)>> {
+    let f = open_file(file_name)?;
+    let mut abundances = HashMap::new();
+    let mut lines = BufReader::new(f).lines().peekable();
+    let is_tsv = matches!(lines.peek(), Some(Ok(header)) if header.starts_with("Name\t"));
+    for (line_number, line) in lines.enumerate().skip(is_tsv as usize) {
+        let line = line.map_err(|e| MoraError::io(file_name, e))?;
+        let line_number = line_number + 1;
+        let chunks: Vec<&str> = line.split('\t').collect();
+        let field = |i: usize| chunks.get(i).copied();
+        if is_tsv {
+            let status = field(8).ok_or_else(|| MoraError::parse(file_name, line_number, "no Status column"))?;
+            abundances.insert(chunks[0].to_string(), (parse_field(field(5), file_name, line_number, "Abundance")?, status == "VALID"));
+        } else {
+            let name = field(1).ok_or_else(|| MoraError::parse(file_name, line_number, "no reference name column"))?;
+            let abundance: f32 = parse_field(field(2), file_name, line_number, "abundance")?;
+            abundances.insert(name.to_string(), (abundance, abundance > 0.0));
+        }
+    }
+    Ok(abundances)
+}
+
+impl Cedar {
+    /*
+        uses the abundances of a previous run instead of running the EM, so the reads can be assigned again
+        abundances: <reference name, (abundance, valid)> as read by read_abundances
+    */
+    pub fn set_abundances(&mut self, abundances: &HashMap<String, (f32, bool)>) -> Result<()> {
+        for (ref_id, reference) in &self.references {
+            let (abundance, valid) = abundances.get(&reference.ref_name)
+                .ok_or_else(|| MoraError::UnknownReference { location: "the SAM/BAM file".to_string(), reference: reference.ref_name.to_string(), known: "the abundance file".to_string() })?;
+            self.strain_abundance.insert(self.ref_id_to_tax_id[ref_id], *abundance);
+            self.strain_valid.insert(*ref_id, *valid);
+        }
+        Ok(())
+    }
+
+    // number of reads the EM assigned to each reference, 0 for references removed by the set cover
+    fn em_read_counts(&self) -> HashMap<usize, f32> {
+        self.ref_id_to_tax_id.iter().map(|(ref_id, tax_id)| (*ref_id, *self.taxa_abundance.get(tax_id).unwrap_or(&0.0))).collect()
+    }
+
+    // number of reads that map to only one reference, for each reference
+    fn unique_read_counts(&self) -> HashMap<usize, usize> {
+        let mut unique_reads = HashMap::new();
+        for query in self.queries.values() {
+            if query.mappings.len() == 1 {
+                let ref_id = query.mappings.iter().next().unwrap().get_reference_id();
+                *unique_reads.entry(ref_id).or_insert(0) += 1;
+            }
+        }
+        unique_reads
+    }
+
+    /*
+        the columns shared by quant.sf and the extended TSV for each reference in order of its ID:
+        (ref_id, Name, Length, EffectiveLength, TPM, NumReads)
+        there is no fragment length model, so the effective length is the length of the reference
+    */
+    fn quant_rows(&self) -> Vec<(usize, String, usize, f32, f32, f32)> {
+        let counts = self.em_read_counts();
+        let mut ref_ids: Vec<&usize> = self.references.keys().collect();
+        ref_ids.sort();
+
+        let rate = |ref_id: &usize| {
+            let effective_length = self.references[ref_id].ref_len as f32;
+            if effective_length > 0.0 { counts[ref_id] / effective_length } else { 0.0 }
+        };
+        let total_rate: f32 = ref_ids.iter().map(|ref_id| rate(ref_id)).sum();
+
+        ref_ids.into_iter().map(|ref_id| {
+            let reference = &self.references[ref_id];
+            let tpm = if total_rate > 0.0 { 1_000_000.0 * rate(ref_id) / total_rate } else { 0.0 };
+            (*ref_id, reference.ref_name.to_string(), reference.ref_len, reference.ref_len as f32, tpm, counts[ref_id])
+        }).collect()
+    }
+
+    // writes the abundances in the format of salmon's quant.sf: Name  Length  EffectiveLength  TPM  NumReads
+    pub fn write_quant_sf(&self, output_filename: String) -> Result<()> {
+        println!("Writing quant.sf into the file: {}", &output_filename);
+        let mut output = BufWriter::new(create_file(&output_filename)?);
+        write_to(&mut output, &output_filename, b"Name\tLength\tEffectiveLength\tTPM\tNumReads\n")?;
+        for (_, name, length, effective_length, tpm, num_reads) in self.quant_rows() {
+            let data = format!("{}\t{}\t{:.3}\t{:.6}\t{:.3}\n", name, length, effective_length, tpm, num_reads);
+            write_to(&mut output, &output_filename, data.as_bytes())?;
+        }
+        output.flush().map_err(|e| MoraError::io(&output_filename, e))
+    }
+
+    // the estimates of all references in order of their ID, after the EM
+    pub fn abundance_table(&self) -> Vec<AbundanceRecord> {
+        let unique_reads = self.unique_read_counts();
+        self.quant_rows().into_iter().map(|(ref_id, name, length, _, tpm, num_reads)| AbundanceRecord {
+            reference_id: ref_id,
+            name,
+            length,
+            abundance: *self.strain_abundance.get(&self.ref_id_to_tax_id[&ref_id]).unwrap_or(&0.0),
+            num_reads,
+            tpm,
+            coverage_breadth: *self.strain_coverage.get(&ref_id).unwrap_or(&0.0),
+            unique_reads: *unique_reads.get(&ref_id).unwrap_or(&0),
+            valid: *self.strain_valid.get(&ref_id).unwrap_or(&false),
+        }).collect()
+    }
+
+    /*
+        writes the quant.sf columns followed by:
+        Abundance: the estimated relative abundance
+        CoverageBreadth: the fraction of bins of the reference with at least one read
+        UniqueReads: the number of reads that map to only this reference
+        Status: VALID or REMOVED by the set cover
+    */
+    pub fn write_abundance_tsv(&self, output_filename: String) -> Result<()> {
+        println!("Writing abundances with coverage into the file: {}", &output_filename);
+        let mut output = BufWriter::new(create_file(&output_filename)?);
+        write_to(&mut output, &output_filename, b"Name\tLength\tEffectiveLength\tTPM\tNumReads\tAbundance\tCoverageBreadth\tUniqueReads\tStatus\n")?;
+        for record in self.abundance_table() {
+            let status = if record.valid { "VALID" } else { "REMOVED" };
+            let data = format!("{}\t{}\t{:.3}\t{:.6}\t{:.3}\t{}\t{:.4}\t{}\t{}\n", record.name, record.length, record.length as f32, record.tpm, record.num_reads,
+                                record.abundance, record.coverage_breadth, record.unique_reads, status);
+            write_to(&mut output, &output_filename, data.as_bytes())?;
+        }
+        output.flush().map_err(|e| MoraError::io(&output_filename, e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+    use crate::cedar::readers::{Mapping, Mate, Query, Reference};
+    use crate::test_util::TestDir;
+
+    /*
+        three references: a and b kept by the set cover with the same reads per base, c removed without reads
+        two reads only on a and one shared between a and b
+    */
+    fn cedar() -> Cedar {
+        let mut cedar = Cedar::new();
+        for (ref_id, name, ref_len, reads, abundance, valid) in [(0, "a", 1000, 6.0, 0.6, true), (1, "b", 500, 3.0, 0.4, true), (2, "c", 2000, 0.0, 0.0, false)] {
+            cedar.references.insert(ref_id, Reference { ref_len, ref_name: name.to_string() });
+            cedar.ref_id_to_tax_id.insert(ref_id, ref_id);
+            if reads > 0.0 {
+                cedar.taxa_abundance.insert(ref_id, reads);
+            }
+            cedar.strain_abundance.insert(ref_id, abundance);
+            cedar.strain_valid.insert(ref_id, valid);
+        }
+        cedar.strain_coverage.insert(0, 0.5);
+        for (query_id, ref_ids) in [(0, vec![0]), (1, vec![0]), (2, vec![0, 1])] {
+            let mappings: HashSet<Mapping> = ref_ids.iter().map(|ref_id| Mapping::new(*ref_id, 100, 0, 100, 100, false, Mate::Single)).collect();
+            cedar.queries.insert(query_id, Query::new(query_id, mappings.len(), 100, mappings, false));
+        }
+        cedar
+    }
+
+    fn columns(file_name: &str) -> Vec<Vec<String>> {
+        std::fs::read_to_string(file_name).unwrap().lines().map(|line| line.split('\t').map(|column| column.to_string()).collect()).collect()
+    }
+
+    #[test]
+    fn quant_sf_columns() {
+        let dir = TestDir::new();
+        let output = dir.path("quant.sf");
+        cedar().write_quant_sf(output.clone()).unwrap();
+
+        let rows = columns(&output);
+        assert_eq!(rows[0], vec!["Name", "Length", "EffectiveLength", "TPM", "NumReads"]);
+        let rows: Vec<(&str, &str, &str, f32, &str)> = rows[1..].iter().map(|row| (row[0].as_str(), row[1].as_str(), row[2].as_str(), row[3].parse().unwrap(), row[4].as_str())).collect();
+        let expected = [("a", "1000", "1000.000", 500_000.0, "6.000"), ("b", "500", "500.000", 500_000.0, "3.000"), ("c", "2000", "2000.000", 0.0, "0.000")];
+        assert_eq!(rows.len(), expected.len());
+        for (row, expected) in rows.iter().zip(expected) {
+            assert_eq!((row.0, row.1, row.2, row.4), (expected.0, expected.1, expected.2, expected.4));
+            assert!((row.3 - expected.3).abs() < 0.1, "TPM of {}: {}", row.0, row.3);
+        }
+    }
+
+    #[test]
+    fn tpm_sums_to_a_million() {
+        let table = cedar().abundance_table();
+        let total: f32 = table.iter().map(|record| record.tpm).sum();
+        assert!((total - 1_000_000.0).abs() < 1.0, "{}", total);
+        let num_reads: Vec<f32> = table.iter().map(|record| record.num_reads).collect();
+        assert_eq!(num_reads, vec![6.0, 3.0, 0.0]);
+        let unique_reads: Vec<usize> = table.iter().map(|record| record.unique_reads).collect();
+        assert_eq!(unique_reads, vec![2, 0, 0]);
+    }
+
+    #[test]
+    fn abundance_tsv_has_the_status_of_the_set_cover() {
+        let dir = TestDir::new();
+        let output = dir.path("abundance.tsv");
+        cedar().write_abundance_tsv(output.clone()).unwrap();
+
+        let rows = columns(&output);
+        assert_eq!(rows[0], vec!["Name", "Length", "EffectiveLength", "TPM", "NumReads", "Abundance", "CoverageBreadth", "UniqueReads", "Status"]);
+        let without_tpm: Vec<Vec<&str>> = rows[1..].iter().map(|row| row.iter().enumerate().filter(|(i, _)| *i != 3).map(|(_, column)| column.as_str()).collect()).collect();
+        assert_eq!(without_tpm, vec![
+            vec!["a", "1000", "1000.000", "6.000", "0.6", "0.5000", "2", "VALID"],
+            vec!["b", "500", "500.000", "3.000", "0.4", "0.0000", "0", "VALID"],
+            vec!["c", "2000", "2000.000", "0.000", "0", "0.0000", "0", "REMOVED"],
+        ]);
+
+        // the status is read back so the reads can be assigned again
+        let abundances = read_abundances(&output).unwrap();
+        assert_eq!(abundances["a"], (0.6, true));
+        assert_eq!(abundances["c"], (0.0, false));
+    }
+}
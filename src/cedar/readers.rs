@@ -1,42 +1,69 @@
 use std::collections::{HashMap, HashSet};
-use rust_htslib::bam::{Reader, Read,  Header, record::Aux};
+use rust_htslib::bam::{Reader, Read,  Header, record::{Aux, Cigar}};
 
 use std::str;
 
+use crate::error::{MoraError, Result};
+
+/*
+    Which read of a fragment a mapping comes from
+
+    Single: the read is not paired
+    First, Second: the mapping of the first or second mate
+    Fragment: the combined mapping of both mates to the same reference
+ */
 #[derive(Clone, PartialEq, Eq, Hash, Copy)]
-pub(crate) struct Mapping {
+pub enum Mate {
+    Single,
+    First,
+    Second,
+    Fragment,
+}
+
+#[derive(Clone, PartialEq, Eq, Hash, Copy)]
+pub struct Mapping {
     /*
     stores the information of a mapping of the read to a reference (the read is known by which Query struct the Mapping is stored in)
 
     reference_id: the ID of the reference that this read is mapped to (-1 means no reference)
     score: the score of the mapping obtained from the SAM file
     position: the start position of the reference where the read is mapped to. 
+    end_position: the position after the last reference base covered by the alignment
+    aligned_bases: the number of read bases aligned to the reference (M, = and X in the CIGAR)
+    mate: which mate of the fragment the mapping belongs to
     
     Currently not considering directionality
     */
     reference_id: i32,
     score: usize,
     position: i64,
-    pub(crate) paired: bool
+    end_position: i64,
+    aligned_bases: u32,
+    pub(crate) paired: bool,
+    mate: Mate,
 }
 
 impl Mapping {
-    fn new(reference_id: i32, score: usize, position: i64, paired: bool) -> Mapping {
-        Mapping { reference_id, score, position, paired}
+    pub(crate) fn new(reference_id: i32, score: usize, position: i64, end_position: i64, aligned_bases: u32, paired: bool, mate: Mate) -> Mapping {
+        Mapping { reference_id, score, position, end_position, aligned_bases, paired, mate }
     }
 
-    pub(crate) fn get_score(&self) -> f32 { self.score as f32}
+    pub fn get_score(&self) -> f32 { self.score as f32}
+
+    pub fn get_position(&self) -> i64 { self.position }
 
-    pub(crate) fn get_position(&self) -> i64 { self.position }
+    pub fn get_end_position(&self) -> i64 { self.end_position }
 
-    pub(crate) fn get_reference_id(&self) -> usize {
+    pub fn get_aligned_bases(&self) -> u32 { self.aligned_bases }
+
+    pub fn get_reference_id(&self) -> usize {
         if self.reference_id != -1 { self.reference_id as usize} 
         else { usize::MAX }
     }
 }
 
 #[derive(Clone, PartialEq)]
-pub(crate) struct Query {
+pub struct Query {
     /*
     Query = read, otherwise there are too many things that start with an r. 
 
@@ -45,6 +72,7 @@ pub(crate) struct Query {
     len: the length of the query
     total_score: sum of all the mapping scores
     unmapped: if cnt > 0
+    discordant: if both mates are mapped but never to the same reference
     */
     pub(crate) query_id: usize, 
     cnt: usize,
@@ -53,21 +81,24 @@ pub(crate) struct Query {
     is_paired: bool, 
     total_score: f32,
     unmapped: bool,
+    discordant: bool,
 }
 
 impl Query {
-    fn new(query_name: usize, cnt: usize, len: u32, mappings: HashSet<Mapping>, is_paired: bool) -> Query {
+    pub(crate) fn new(query_name: usize, cnt: usize, len: u32, mappings: HashSet<Mapping>, is_paired: bool) -> Query {
         let mut total_score = 0.0;
         for map in &mappings {
             total_score += map.get_score();
         }
         let unmapped = {cnt != 0};
-        Query { query_id: query_name, cnt, len, mappings, is_paired, total_score, unmapped}
+        Query { query_id: query_name, cnt, len, mappings, is_paired, total_score, unmapped, discordant: false}
     }
 
-    pub(crate) fn get_cnt(&self) -> usize { self.cnt }
+    pub fn get_cnt(&self) -> usize { self.cnt }
+
+    pub fn is_discordant(&self) -> bool { self.discordant }
 
-    pub(crate) fn get_total_score(&self) -> f32 { self.total_score }
+    pub fn get_total_score(&self) -> f32 { self.total_score }
 
     fn add_mapping(&mut self, mapping: Mapping) {
         if mapping.reference_id != -1 {
@@ -78,8 +109,46 @@ impl Query {
         }
     }
 
+    // combine the mappings of both mates into a single mapping per reference that both mates are mapped to, 
+    // the score of the combined mapping is the sum of the best score of each mate on that reference
+    pub(crate) fn pair_mates(&mut self) {
+        let (mut firsts, mut seconds): (HashMap<usize, Mapping>, HashMap<usize, Mapping>) = (HashMap::new(), HashMap::new());
+        for mapping in &self.mappings {
+            let best = match mapping.mate {
+                Mate::First => firsts.entry(mapping.get_reference_id()).or_insert(*mapping),
+                Mate::Second => seconds.entry(mapping.get_reference_id()).or_insert(*mapping),
+                _ => continue
+            };
+            if mapping.score > best.score {
+                *best = *mapping;
+            }
+        }
+        // only one of the mates is mapped, so there is nothing to keep together
+        if firsts.is_empty() || seconds.is_empty() {
+            return;
+        }
+
+        let mut fragment_mappings = HashSet::new();
+        for (ref_id, first) in &firsts {
+            if let Some(second) = seconds.get(ref_id) {
+                let position = first.position.min(second.position);
+                let end_position = first.end_position.max(second.end_position);
+                fragment_mappings.insert(Mapping::new(first.reference_id, first.score + second.score, position, end_position, 
+                                                        first.aligned_bases + second.aligned_bases, true, Mate::Fragment));
+            }
+        }
+        if fragment_mappings.is_empty() {
+            self.discordant = true;
+            return;
+        }
+
+        self.total_score = fragment_mappings.iter().map(|mapping| mapping.get_score()).sum();
+        self.cnt = fragment_mappings.len();
+        self.mappings = fragment_mappings;
+    }
+
     // returns the id and score of the best and second best mappings
-    pub(crate) fn get_best_mappings(&self) -> (usize, f32, usize, f32) {
+    pub fn get_best_mappings(&self) -> (usize, f32, usize, f32) {
         let (mut highest_score, mut best_ref_id, mut second_highest_score, mut second_best_ref_id) = (0.0, 0, 0.0, 0);
 
         for mapping in &self.mappings {
@@ -95,7 +164,7 @@ impl Query {
         (best_ref_id, highest_score, second_best_ref_id, second_highest_score)
     }
 
-    pub(crate) fn sort_mappings(&self) -> Vec<&Mapping> {
+    pub fn sort_mappings(&self) -> Vec<&Mapping> {
         let mut ordered_vec: Vec<&Mapping> = self.mappings.iter().collect();
         ordered_vec.sort_by(|a, b| b.get_score().partial_cmp(&a.get_score()).unwrap());
         ordered_vec
@@ -103,9 +172,9 @@ impl Query {
 }
 
 #[derive(Clone)]
-pub(crate) struct Reference {
-    pub(crate) ref_len: usize,
-    pub(crate) ref_name: String,
+pub struct Reference {
+    pub ref_len: usize,
+    pub ref_name: String,
 }
 
 impl Reference {
@@ -116,68 +185,131 @@ impl Reference {
 
 use rayon::prelude::*;
 
+// (references, queries, query_id_2_name) of a SAM/BAM file
+type Alignments = (HashMap<usize, Reference>, HashMap<usize, Query>, HashMap<usize, String>);
+
 /*
 Inputs:
 file_name: directory for SAM/BAM file
 method: how to analyze mapping scores
+pair_mates: if the mappings of both mates of a fragment are combined so the fragment is assigned as one unit
 
 Output:
 (references, ref_id_2_names, queries, query_id_2_name)
 */
-pub(crate) fn read_initial_alignments(file_name: String, method: String) -> (HashMap<usize, Reference>, HashMap<usize, Query>, HashMap<usize, String>){
-    let f = Reader::from_path(&file_name).unwrap();
+pub fn read_initial_alignments(file_name: String, method: String, pair_mates: bool) -> Result<Alignments> {
+    let f = open_alignments(&file_name)?;
     let header = Header::from_template(f.header());
-    let references = analyze_header(header);
+    let references = analyze_header(header, &file_name)?;
+
+    println!("references are done: {}", references.len());
+
+    let (queries, query_id_2_name) = read_queries(f, &file_name, method, pair_mates)?;
+    Ok((references, queries, query_id_2_name))
+}
 
+// reads only the references in the header of a SAM/BAM file
+pub fn read_references(file_name: &str) -> Result<HashMap<usize, Reference>> {
+    let f = open_alignments(file_name)?;
+    let references = analyze_header(Header::from_template(f.header()), file_name)?;
     println!("references are done: {}", references.len());
+    Ok(references)
+}
+
+fn open_alignments(file_name: &str) -> Result<Reader> {
+    Reader::from_path(file_name).map_err(|e| MoraError::Alignment { path: file_name.to_string(), record: "file".to_string(), message: e.to_string() })
+}
 
-    let (queries, query_id_2_name) = analyze_alignments(f, method);
+/*
+    reads the alignments of a SAM/BAM file whose header has the same references as the ones already read,
+    only the target names of the header are compared instead of parsing the header again
+    Output: (queries, query_id_2_name)
+*/
+pub fn read_alignments_with_references(file_name: String, references: &HashMap<usize, Reference>, method: String, pair_mates: bool) -> Result<(HashMap<usize, Query>, HashMap<usize, String>)> {
+    let f = open_alignments(&file_name)?;
+    let target_names = f.header().target_names();
+    if target_names.len() != references.len() {
+        return Err(MoraError::Alignment { path: file_name, record: "header".to_string(),
+                    message: format!("{} references instead of the {} references of the first file", target_names.len(), references.len()) });
+    }
+    for (ref_id, name) in target_names.iter().enumerate() {
+        if references[&ref_id].ref_name.as_bytes() != *name {
+            return Err(MoraError::Alignment { path: file_name, record: format!("@SQ line {}", ref_id + 1),
+                        message: format!("reference {} instead of {} of the first file", String::from_utf8_lossy(name), references[&ref_id].ref_name) });
+        }
+    }
+    read_queries(f, &file_name, method, pair_mates)
+}
+
+// reads the alignments and pairs the mates of each fragment if wanted
+fn read_queries(f: Reader, file_name: &str, method: String, pair_mates: bool) -> Result<(HashMap<usize, Query>, HashMap<usize, String>)> {
+    let (mut queries, query_id_2_name) = analyze_alignments(f, file_name, method, pair_mates)?;
+    if pair_mates {
+        queries.par_iter_mut().for_each(|(_, query)| query.pair_mates());
+        let discordant = queries.values().filter(|query| query.is_discordant()).count();
+        println!("discordant fragments: {}", discordant);
+    }
     println!("reads are done: {}", queries.len());
-    (references, queries, query_id_2_name)
+    Ok((queries, query_id_2_name))
 }
 
-fn analyze_header(header: Header) -> HashMap<usize, Reference> {
+fn analyze_header(header: Header, file_name: &str) -> Result<HashMap<usize, Reference>> {
     let mut references = HashMap::new();
     for (key, records) in header.to_hashmap() {
-        if key != "SQ".to_string() {
+        if key != "SQ" {
             continue;
         }
-        references = records.par_iter().enumerate().fold(|| HashMap::new(), |mut acc, (ref_id, ref_record)| {
-            let ref_name = &ref_record["SN"];
-            let ref_len = ref_record["LN"].parse().unwrap();
-            acc.entry(ref_id).or_insert(Reference::new(ref_len, ref_name.to_string()));
-            acc
-        }).reduce(|| HashMap::new(), |m1, m2| {
-            m2.iter().fold(m1, |mut acc, (key, value)| {
-                acc.entry(key.clone()).or_insert(value.clone());
-                acc
-            })
-        });
-    }
-    references
+        for (ref_id, ref_record) in records.iter().enumerate() {
+            let header_error = |message: String| MoraError::Alignment { path: file_name.to_string(), record: format!("@SQ line {}", ref_id + 1), message };
+            let ref_name = ref_record.get("SN").ok_or_else(|| header_error("no SN field".to_string()))?;
+            let ref_len = ref_record.get("LN").ok_or_else(|| header_error(format!("no LN field for {}", ref_name)))?;
+            let ref_len = ref_len.parse().map_err(|_| header_error(format!("LN of {} is not a length: {}", ref_name, ref_len)))?;
+            references.insert(ref_id, Reference::new(ref_len, ref_name.to_string()));
+        }
+    }
+    Ok(references)
 }
 
 /*
 Inputs:
 f: the thing that reads the SAM file
 method: to determine how to analyze the mapping scores (mainly for bowtie2)
+pair_mates: if the records of a query can be anywhere in the file, otherwise they are expected next to each other
 Output: 
 (queries, names for queries)
 */
-fn analyze_alignments(mut f: Reader, method: String) -> (HashMap<usize, Query>, HashMap<usize, String>) {
-    let mut queries = HashMap::new();
+fn analyze_alignments(mut f: Reader, file_name: &str, method: String, pair_mates: bool) -> Result<(HashMap<usize, Query>, HashMap<usize, String>)> {
+    let mut queries: HashMap<usize, Query> = HashMap::new();
     let mut query_id_2_names = HashMap::new();
+    // the mates of a fragment are not always next to each other (e.g. in a coordinate sorted file), so all the names 
+    // are kept when pairing mates, otherwise only the name of the previous record is compared
+    let mut query_name_2_id = HashMap::new();
+    let mut previous_query: Option<(String, usize)> = None;
     let mut query_id = 0;
-    let mut current_name = "".to_string();
-    let mut current_query = Query::new(0, 0, 0, HashSet::new(), true);
     
-    for r in f.records() {
-        let record = r.unwrap(); 
+    for (record_cnt, r) in f.records().enumerate() {
+        let record_error = |message: String| MoraError::Alignment { path: file_name.to_string(), record: format!("record {}", record_cnt + 1), message };
+        let record = r.map_err(|e| record_error(e.to_string()))?;
         let reference_id = record.tid();
-        let query_name = str::from_utf8(record.qname()).unwrap();
+        let query_name = str::from_utf8(record.qname()).map_err(|_| record_error("query name is not valid UTF-8".to_string()))?;
         let mut score:i32;
         let position = record.pos();
+        let cigar = record.cigar();
+        let end_position = cigar.end_pos();
+        let aligned_bases = cigar.iter().map(|op| match op {
+            Cigar::Match(len) | Cigar::Equal(len) | Cigar::Diff(len) => *len,
+            _ => 0
+        }).sum();
         let paired = record.is_paired();
+        let mate = if !paired {
+            Mate::Single
+        } else if record.is_first_in_template() {
+            Mate::First
+        } else if record.is_last_in_template() {
+            Mate::Second
+        } else {
+            Mate::Single
+        };
         let query_len = record.seq_len() as u32;
         match record.aux(b"AS") {
             Ok(Aux::U8(v))    => score = v as i32,
@@ -185,7 +317,7 @@ fn analyze_alignments(mut f: Reader, method: String) -> (HashMap<usize, Query>,
             Ok(Aux::U16(v))    => score = v as i32,
             Ok(Aux::I16(v))    => score = v as i32,
             Ok(Aux::U32(v))    => score = v as i32,
-            Ok(Aux::I32(v))    => score = v as i32,
+            Ok(Aux::I32(v))    => score = v,
             Ok(Aux::Float(v)) => score = (v * 100.0).round() as i32,
             _                 => score = 0,
         }
@@ -195,25 +327,75 @@ fn analyze_alignments(mut f: Reader, method: String) -> (HashMap<usize, Query>,
         if score <= 0 { // I assume that score = 0 means that it doesn't map
             score = 1
         }
-        let mapping = Mapping::new(reference_id, score as usize, position, paired);
-        if query_name == current_name {
-            current_query.add_mapping(mapping);
+        let mapping = Mapping::new(reference_id, score as usize, position, end_position, aligned_bases, paired, mate);
+        let known_id = if pair_mates {
+            query_name_2_id.get(query_name).copied()
         } else {
-            if query_id != 0 {
+            previous_query.as_ref().filter(|(name, _)| name == query_name).map(|(_, id)| *id)
+        };
+        match known_id {
+            Some(id) => queries.get_mut(&id).unwrap().add_mapping(mapping),
+            None => {
+                query_id += 1;
+                let mut current_query = Query::new(query_id, 0, query_len, HashSet::new(), paired);
+                current_query.add_mapping(mapping);
                 queries.insert(query_id, current_query);
-                query_id_2_names.insert(query_id, current_name.to_string());
+                query_id_2_names.insert(query_id, query_name.to_string());
+                if pair_mates {
+                    query_name_2_id.insert(query_name.to_string(), query_id);
+                } else {
+                    previous_query = Some((query_name.to_string(), query_id));
+                }
             }
-            query_id += 1;
-            current_query = Query::new(query_id, 0, query_len, HashSet::new(), paired);
-            current_name = query_name.to_string();
-            current_query.add_mapping(mapping);
         }
     }
 
-    if query_id != 0 {
-        queries.insert(query_id, current_query);
-        query_id_2_names.insert(query_id, current_name.to_string());
+    Ok((queries, query_id_2_names))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the (reference, score, position, end position) mappings of both mates of a fragment
+    fn fragment(firsts: &[(i32, usize, i64, i64)], seconds: &[(i32, usize, i64, i64)]) -> Query {
+        let mate_mappings = |mappings: &[(i32, usize, i64, i64)], mate: Mate| mappings.iter()
+            .map(|(ref_id, score, position, end_position)| Mapping::new(*ref_id, *score, *position, *end_position, (end_position - position) as u32, true, mate))
+            .collect::<Vec<_>>();
+        let mappings: HashSet<Mapping> = mate_mappings(firsts, Mate::First).into_iter().chain(mate_mappings(seconds, Mate::Second)).collect();
+        Query::new(1, mappings.len(), 100, mappings, true)
+    }
+
+    #[test]
+    fn pair_mates_combines_the_mates_on_shared_references() {
+        let mut query = fragment(&[(0, 50, 100, 200), (0, 30, 900, 1000), (1, 40, 0, 100)], &[(0, 60, 300, 400), (2, 70, 0, 100)]);
+        query.pair_mates();
+
+        assert!(!query.is_discordant());
+        assert_eq!(query.get_cnt(), 1);
+        assert_eq!(query.get_total_score(), 110.0);
+        let mapping = query.mappings.iter().next().unwrap();
+        assert!(mapping.mate == Mate::Fragment);
+        assert_eq!((mapping.get_reference_id(), mapping.get_score()), (0, 110.0));
+        assert_eq!((mapping.get_position(), mapping.get_end_position(), mapping.get_aligned_bases()), (100, 400, 200));
+    }
+
+    #[test]
+    fn pair_mates_marks_fragments_without_a_shared_reference_as_discordant() {
+        let mut query = fragment(&[(0, 50, 0, 100)], &[(1, 50, 0, 100)]);
+        query.pair_mates();
+
+        assert!(query.is_discordant());
+        assert_eq!(query.mappings.len(), 2);
     }
 
-    (queries, query_id_2_names)
+    #[test]
+    fn pair_mates_keeps_fragments_with_one_mapped_mate() {
+        let mut query = fragment(&[(0, 50, 0, 100), (1, 40, 0, 100)], &[]);
+        let mappings = query.mappings.clone();
+        query.pair_mates();
+
+        assert!(!query.is_discordant());
+        assert!(query.mappings == mappings);
+    }
 }
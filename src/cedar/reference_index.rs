@@ -0,0 +1,35 @@
+use std::collections::HashMap;
+use rayon::prelude::*;
+
+use super::readers::{read_references, Reference};
+use crate::error::{MoraError, Result};
+
+/*
+    The references of a SAM/BAM header with their empty coverage bins
+
+    built once from the first file of a batch and shared by all the samples that were mapped against the same references,
+    so the header is not parsed and the bins are not rebuilt for every sample
+*/
+#[derive(Clone)]
+pub struct ReferenceIndex {
+    pub(super) references: HashMap<usize, Reference>,
+    pub(super) coverage_bins: HashMap<usize, Vec<usize>>,
+}
+
+impl ReferenceIndex {
+    pub fn new(references: HashMap<usize, Reference>, segment_size: usize) -> ReferenceIndex {
+        let coverage_bins = references.par_iter().map(|(ref_id, reference)| (*ref_id, vec![0; reference.ref_len / segment_size + 1])).collect();
+        ReferenceIndex { references, coverage_bins }
+    }
+
+    pub fn from_file(file_name: &str, segment_size: usize) -> Result<ReferenceIndex> {
+        if segment_size == 0 {
+            return Err(MoraError::invalid_value("--segment_size", "0", "the bins need at least one base"));
+        }
+        Ok(ReferenceIndex::new(read_references(file_name)?, segment_size))
+    }
+
+    pub fn get_references(&self) -> &HashMap<usize, Reference> {
+        &self.references
+    }
+}
@@ -20,19 +20,19 @@ pub(crate) fn greedy_set_cover(sets: Vec<Vec<u64>>, weights: Vec<Vec<f32>>, uniq
         covers.insert(i);
     }
     
-    while left_over.len() != 0  && covers.len() != 0 {
+    while !left_over.is_empty() && !covers.is_empty() {
         // find the scores of the covers and add the lowest 5% of them to the final cover if it has at least 1 new element
         // this is done to decrease the amount to iterations needed to get a final cover
         // create the bins for the scores of the covers
-        let mut score_bins: HashMap<usize, Vec<usize>> = covers.par_iter().fold(|| HashMap::new(), |mut acc, index| {
+        let mut score_bins: HashMap<usize, Vec<usize>> = covers.par_iter().fold(HashMap::new, |mut acc, index| {
             let score = cover_score(&tmp, &sets[*index], &weights[*index]);
             let bin_num = (score * 2.0).floor() as usize;
             let entry = acc.entry(bin_num).or_insert(Vec::new());
             entry.push(*index);
             acc
-        }).reduce(|| HashMap::new(), |m1, m2| {
+        }).reduce(HashMap::new, |m1, m2| {
             m2.iter().fold(m1, |mut acc, (key, value)| {
-                let entry = acc.entry(key.clone()).or_insert(Vec::new());
+                let entry = acc.entry(*key).or_insert(Vec::new());
                 entry.extend(value);
                 acc
             })
@@ -45,12 +45,12 @@ pub(crate) fn greedy_set_cover(sets: Vec<Vec<u64>>, weights: Vec<Vec<f32>>, uniq
         if loop_len < 1 {
             loop_len = 1;
         }
-        while left_over.len() > 0 && cnt < loop_len {
+        while !left_over.is_empty() && cnt < loop_len {
             let mut bin_finished = true;
-            if score_bins.contains_key(&bin) && score_bins[&bin].len() > 0 {
-                while score_bins[&bin].len() > 0 {
+            if score_bins.contains_key(&bin) && !score_bins[&bin].is_empty() {
+                while !score_bins[&bin].is_empty() {
                     // check conditions to continue
-                    if cnt > loop_len || left_over.len() == 0 {
+                    if cnt > loop_len || left_over.is_empty() {
                         bin_finished = false;   // premature exit of the bin
                         break;
                     }
@@ -64,7 +64,7 @@ pub(crate) fn greedy_set_cover(sets: Vec<Vec<u64>>, weights: Vec<Vec<f32>>, uniq
                     if can_push {
                         final_cover.push(score_bins[&bin][0]);
                     }
-                    let entry = score_bins.entry(bin).or_insert(Vec::new());
+                    let entry = score_bins.entry(bin).or_default();
                     entry.remove(0);
                     cnt += 1;
                 }
@@ -85,14 +85,11 @@ pub(crate) fn greedy_set_cover(sets: Vec<Vec<u64>>, weights: Vec<Vec<f32>>, uniq
 
 // Helper function for calculating the score of a potential cover. The score is calculated as 
 // sum of the weights of each element / # of elements in s that is not in vs. 
-fn cover_score(vs: &HashSet<u64>, s: &Vec<u64>, weights: &Vec<f32>) -> f32{
-    let mut v = 0.0;
-    for i in 0.. s.len() {
-        v += weights[i];
-    }
+fn cover_score(vs: &HashSet<u64>, s: &[u64], weights: &[f32]) -> f32{
+    let v: f32 = weights[..s.len()].iter().sum();
     let mut count = 0.0; // keeps track of the number of elements in s that is not in vs. 
     for element in s {
-        if !vs.contains(&element){
+        if !vs.contains(element){
             count += 1.0;
         }
     }
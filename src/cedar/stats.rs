@@ -22,6 +22,10 @@ impl Stats {
         self.total_read_cnt
     }
     
+    pub(crate) fn get_total_multi_mapped_reads(&self) -> usize {
+        self.total_multi_mapped_reads
+    }
+
     pub(crate) fn get_total_unmapped_reads(&self) -> usize {
         self.total_unmapped_reads
     }
@@ -3,14 +3,14 @@
 Sorts the Vector in terms of the first element in the Tuple
 We are assuming tha the first elements in the tuples are all unique from each other
  */
-pub(crate) fn sort_vec(vec: &Vec<(usize, f32)>) -> Vec<(usize, f32)> {
+pub(crate) fn sort_vec(vec: &[(usize, f32)]) -> Vec<(usize, f32)> {
     let mut vec = vec.to_owned();
     let mut sorted_list = Vec::new();
     while !vec.is_empty() {
         let mut min = vec[0];
-        for i in 0..vec.len() {
-            if vec[i].0 < min.0 {
-                min = vec[i];
+        for item in &vec {
+            if item.0 < min.0 {
+                min = *item;
             }
         }
         sorted_list.push(min);
@@ -0,0 +1,412 @@
+use std::collections::HashMap;
+use std::env;
+use std::io;
+use std::path::Path;
+use serde::{Deserialize, Serialize};
+
+use crate::assignment::SlackModel;
+use crate::error::{create_file, open_file, MoraError, Result};
+
+/*
+    The settings of a run, built with MoraConfig::builder() and shared by the quantification and the assignment
+
+    min_cnt: minimum count for a reference to be valid
+    max_em_iterations: maximum allowed iterations of the EM
+    eps: the EM converges when no count changes by more than eps
+    segment_size: size of the bins the references are split into for the coverage
+    mapping_method: the mapper that wrote the SAM/BAM file, decides how the mapping scores are read (pufferfish, bowtie2, minimap2)
+    pair_mates: keep both mates of a paired fragment together and assign them to the same reference
+    min_score_diff: minimum difference between mapping scores divided by the best mapping score for the secondary assignment
+    final_method: none leaves the reads that cannot be placed unassigned, anything else assigns them randomly based on their mapping scores
+    max_chain_depth: maximum number of moves in an ejection chain that makes room for a left over read
+    slack: how many reads a reference may hold on top of the reads expected from its abundance
+    reestimate_rounds: maximum number of rounds of re-estimating the abundances from the assignment
+*/
+#[derive(Clone, Debug)]
+pub struct MoraConfig {
+    pub(crate) min_cnt: f32,
+    pub(crate) max_em_iterations: usize,
+    pub(crate) eps: f32,
+    pub(crate) segment_size: usize,
+    pub(crate) mapping_method: String,
+    pub(crate) pair_mates: bool,
+    pub(crate) min_score_diff: f32,
+    pub(crate) final_method: String,
+    pub(crate) max_chain_depth: usize,
+    pub(crate) slack: SlackModel,
+    pub(crate) reestimate_rounds: usize,
+}
+
+impl Default for MoraConfig {
+    // the defaults of the command line
+    fn default() -> MoraConfig {
+        MoraConfig { min_cnt: 0.1, max_em_iterations: 300, eps: 0.001, segment_size: 100, mapping_method: "pufferfish".to_string(), pair_mates: false,
+                        min_score_diff: 0.5, final_method: "none".to_string(), max_chain_depth: 1, slack: SlackModel::Absolute(1.0), reestimate_rounds: 0 }
+    }
+}
+
+impl MoraConfig {
+    pub fn builder() -> MoraConfigBuilder {
+        MoraConfigBuilder::default()
+    }
+
+    pub fn get_segment_size(&self) -> usize {
+        self.segment_size
+    }
+
+    pub fn get_slack(&self) -> SlackModel {
+        self.slack
+    }
+
+    // builder starting from these settings, e.g. to override the settings of a config file
+    pub fn into_builder(self) -> MoraConfigBuilder {
+        MoraConfigBuilder { config: self }
+    }
+
+    /*
+        the defaults, overridden by the settings of the config file if one is given,
+        overridden by the MORA_<KEY> environment variables (e.g. MORA_MIN_CNT=0.5)
+    */
+    pub fn load(config_filename: Option<&str>) -> Result<MoraConfig> {
+        Ok(MoraConfig::load_with_run_settings(config_filename)?.0)
+    }
+
+    // same as load, with the settings of the mora binary of the config file and the environment
+    pub fn load_with_run_settings(config_filename: Option<&str>) -> Result<(MoraConfig, RunSettings)> {
+        MoraConfig::load_with_vars(config_filename, &env::vars().collect())
+    }
+
+    // same as load_with_run_settings, with the environment variables given as <name, value>
+    fn load_with_vars(config_filename: Option<&str>, vars: &HashMap<String, String>) -> Result<(MoraConfig, RunSettings)> {
+        let mut loaded = (MoraConfig::default(), RunSettings::default());
+        if let Some(config_filename) = config_filename {
+            println!("Reading settings from {}", config_filename);
+            loaded = ConfigFile::read(config_filename)?.apply(loaded.0, loaded.1)?;
+        }
+        ConfigFile::from_vars(vars)?.apply(loaded.0, loaded.1)
+    }
+
+    // writes the settings as a config file that can be given to --config, YAML for .yaml/.yml and TOML otherwise
+    pub fn write(&self, output_filename: &str) -> Result<()> {
+        self.write_with_run_settings(&RunSettings::default(), output_filename)
+    }
+
+    // same as write, with the settings of the mora binary so that the run can be reproduced with --config
+    pub fn write_with_run_settings(&self, run: &RunSettings, output_filename: &str) -> Result<()> {
+        println!("Writing settings into the file: {}", output_filename);
+        let settings = ConfigFile::new(self, run)?;
+        let written = if is_yaml(output_filename) {
+            serde_yaml::to_writer(create_file(output_filename)?, &settings).map_err(|e| e.to_string())
+        } else {
+            confy::store_path(output_filename, settings).map_err(|e| e.to_string())
+        };
+        written.map_err(|e| MoraError::io(output_filename, io::Error::new(io::ErrorKind::Other, e)))
+    }
+}
+
+fn is_yaml(file_name: &str) -> bool {
+    matches!(Path::new(file_name).extension().and_then(|ext| ext.to_str()), Some("yaml") | Some("yml"))
+}
+
+/*
+    The settings of the mora binary that are not part of MoraConfig, read from and written to the same config file,
+    the options given on the command line override them
+
+    threads: number of threads rayon uses
+    output: path of the assignments (the output directory with a sample sheet)
+    taxonomy: the tax directory or taxonomy database of --tax
+    tax_format: format of the taxonomy (ncbi or gtdb)
+    ranks: comma separated ranks written with the taxonomy
+    tax_ids: if the TaxID of the assigned reference is written
+    ref_name_rules, ref_name_regex: how the reference names are matched to the accessions of the taxonomy
+*/
+#[derive(Clone, Debug, Default)]
+pub struct RunSettings {
+    pub threads: Option<usize>,
+    pub output: Option<String>,
+    pub taxonomy: Option<String>,
+    pub tax_format: Option<String>,
+    pub ranks: Option<String>,
+    pub tax_ids: Option<bool>,
+    pub ref_name_rules: Option<String>,
+    pub ref_name_regex: Option<String>,
+}
+
+// the taxonomy of the Snakemake config.yaml is either a path or False
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum TaxonomyPath {
+    Path(String),
+    Disabled(bool),
+}
+
+fn taxonomy_path<'de, D: serde::Deserializer<'de>>(deserializer: D) -> std::result::Result<Option<String>, D::Error> {
+    match Option::<TaxonomyPath>::deserialize(deserializer)? {
+        Some(TaxonomyPath::Path(path)) => Ok(Some(path)),
+        Some(TaxonomyPath::Disabled(false)) | None => Ok(None),
+        Some(TaxonomyPath::Disabled(true)) => Err(serde::de::Error::custom("TAXONOMY is a path or false")),
+    }
+}
+
+// f32 converted through its shortest decimal representation, so 0.1 is written as 0.1 and not as 0.10000000149011612
+fn to_f64(name: &str, value: f32) -> Result<f64> {
+    value.to_string().parse().map_err(|_| MoraError::invalid_value(name, &value.to_string(), "not a number"))
+}
+
+/*
+    The settings of a config file or of the environment, the settings that are not given keep the value of the layer below
+
+    the keys are the ones of the parameters of the Snakemake config.yaml, so its parameters section can be given directly
+*/
+#[derive(Serialize, Deserialize, Default)]
+struct ConfigFile {
+    #[serde(rename = "MIN_CNT")]
+    min_cnt: Option<f64>,
+    #[serde(rename = "MAX_EM_ITERATIONS")]
+    max_em_iterations: Option<usize>,
+    #[serde(rename = "EPS")]
+    eps: Option<f64>,
+    #[serde(rename = "SEGMENT_SIZE")]
+    segment_size: Option<usize>,
+    #[serde(rename = "MAPPING_MODE")]
+    mapping_method: Option<String>,
+    #[serde(rename = "PAIR_MATES")]
+    pair_mates: Option<bool>,
+    #[serde(rename = "MAX_SCORE_DIFFERENCE")]
+    min_score_diff: Option<f64>,
+    #[serde(rename = "FINAL_ASSIGNMENT_METHOD")]
+    final_method: Option<String>,
+    #[serde(rename = "MAX_CHAIN_DEPTH")]
+    max_chain_depth: Option<usize>,
+    #[serde(rename = "SLACK_MODEL")]
+    slack_model: Option<String>,
+    #[serde(rename = "SLACK")]
+    slack: Option<f64>,
+    #[serde(rename = "REESTIMATE_ROUNDS")]
+    reestimate_rounds: Option<usize>,
+    #[serde(rename = "THREADS", skip_serializing_if = "Option::is_none")]
+    threads: Option<usize>,
+    #[serde(rename = "OUTPUT", skip_serializing_if = "Option::is_none")]
+    output: Option<String>,
+    #[serde(rename = "TAXONOMY", default, deserialize_with = "taxonomy_path", skip_serializing_if = "Option::is_none")]
+    taxonomy: Option<String>,
+    #[serde(rename = "TAX_FORMAT", skip_serializing_if = "Option::is_none")]
+    tax_format: Option<String>,
+    #[serde(rename = "RANKS", skip_serializing_if = "Option::is_none")]
+    ranks: Option<String>,
+    #[serde(rename = "TAX_IDS", skip_serializing_if = "Option::is_none")]
+    tax_ids: Option<bool>,
+    #[serde(rename = "REF_NAME_RULES", skip_serializing_if = "Option::is_none")]
+    ref_name_rules: Option<String>,
+    #[serde(rename = "REF_NAME_REGEX", skip_serializing_if = "Option::is_none")]
+    ref_name_regex: Option<String>,
+}
+
+impl ConfigFile {
+    /*
+        TOML files are read with confy, YAML files are read as the Snakemake config.yaml:
+        the settings are taken from its parameters section if it has one, otherwise from the top level
+        keys that are not settings (e.g. FILES_EXT or TAXONOMY) are ignored
+    */
+    fn read(config_filename: &str) -> Result<ConfigFile> {
+        // confy would create a missing file
+        let f = open_file(config_filename)?;
+        let invalid = |e: String| MoraError::invalid_value("--config", config_filename, e);
+        if !is_yaml(config_filename) {
+            return confy::load_path(config_filename).map_err(|e| invalid(e.to_string()));
+        }
+        let yaml: serde_yaml::Value = serde_yaml::from_reader(f).map_err(|e| invalid(e.to_string()))?;
+        let parameters = match yaml.get("parameters") {
+            Some(parameters) => parameters.clone(),
+            None => yaml,
+        };
+        serde_yaml::from_value(parameters).map_err(|e| invalid(e.to_string()))
+    }
+
+    // the MORA_<KEY> environment variables, numbers and booleans are parsed as TOML values and anything else is a string
+    fn from_vars(vars: &HashMap<String, String>) -> Result<ConfigFile> {
+        let mut table = toml::value::Table::new();
+        for (key, value) in vars {
+            if let Some(setting) = key.strip_prefix("MORA_") {
+                let parsed = toml::from_str::<toml::value::Table>(&format!("value = {}", value)).ok().and_then(|mut t| t.remove("value"));
+                let parsed = parsed.unwrap_or_else(|| toml::Value::String(value.to_string()));
+                // each variable is checked on its own so that the error names it
+                let mut single = toml::value::Table::new();
+                single.insert(setting.to_string(), parsed.clone());
+                toml::Value::Table(single).try_into::<ConfigFile>().map_err(|e| MoraError::invalid_value(key, value, e.to_string()))?;
+                table.insert(setting.to_string(), parsed);
+            }
+        }
+        toml::Value::Table(table).try_into().map_err(|e| MoraError::invalid_value("MORA_ environment variables", "", e.to_string()))
+    }
+
+    fn apply(self, mut config: MoraConfig, mut run: RunSettings) -> Result<(MoraConfig, RunSettings)> {
+        if let Some(min_cnt) = self.min_cnt { config.min_cnt = min_cnt as f32; }
+        if let Some(max_em_iterations) = self.max_em_iterations { config.max_em_iterations = max_em_iterations; }
+        if let Some(eps) = self.eps { config.eps = eps as f32; }
+        if let Some(segment_size) = self.segment_size { config.segment_size = segment_size; }
+        if let Some(mapping_method) = self.mapping_method { config.mapping_method = mapping_method; }
+        if let Some(pair_mates) = self.pair_mates { config.pair_mates = pair_mates; }
+        if let Some(min_score_diff) = self.min_score_diff { config.min_score_diff = min_score_diff as f32; }
+        if let Some(final_method) = self.final_method { config.final_method = final_method; }
+        if let Some(max_chain_depth) = self.max_chain_depth { config.max_chain_depth = max_chain_depth; }
+        if let Some(reestimate_rounds) = self.reestimate_rounds { config.reestimate_rounds = reestimate_rounds; }
+        // the model and its value can be given separately
+        let slack_model = self.slack_model.unwrap_or_else(|| config.slack.get_model().to_string());
+        let slack = self.slack.map(|slack| slack as f32).unwrap_or_else(|| config.slack.get_value());
+        config.slack = SlackModel::new(&slack_model, slack)?;
+
+        if let Some(threads) = self.threads { run.threads = Some(threads); }
+        if let Some(output) = self.output { run.output = Some(output); }
+        if let Some(taxonomy) = self.taxonomy { run.taxonomy = Some(taxonomy); }
+        if let Some(tax_format) = self.tax_format { run.tax_format = Some(tax_format); }
+        if let Some(ranks) = self.ranks { run.ranks = Some(ranks); }
+        if let Some(tax_ids) = self.tax_ids { run.tax_ids = Some(tax_ids); }
+        if let Some(ref_name_rules) = self.ref_name_rules { run.ref_name_rules = Some(ref_name_rules); }
+        if let Some(ref_name_regex) = self.ref_name_regex { run.ref_name_regex = Some(ref_name_regex); }
+        Ok((config, run))
+    }
+
+    // the settings of a run as they are written by --write_config
+    fn new(config: &MoraConfig, run: &RunSettings) -> Result<ConfigFile> {
+        Ok(ConfigFile {
+            min_cnt: Some(to_f64("MIN_CNT", config.min_cnt)?),
+            max_em_iterations: Some(config.max_em_iterations),
+            eps: Some(to_f64("EPS", config.eps)?),
+            segment_size: Some(config.segment_size),
+            mapping_method: Some(config.mapping_method.to_string()),
+            pair_mates: Some(config.pair_mates),
+            min_score_diff: Some(to_f64("MAX_SCORE_DIFFERENCE", config.min_score_diff)?),
+            final_method: Some(config.final_method.to_string()),
+            max_chain_depth: Some(config.max_chain_depth),
+            slack_model: Some(config.slack.get_model().to_string()),
+            slack: Some(to_f64("SLACK", config.slack.get_value())?),
+            reestimate_rounds: Some(config.reestimate_rounds),
+            threads: run.threads,
+            output: run.output.clone(),
+            taxonomy: run.taxonomy.clone(),
+            tax_format: run.tax_format.clone(),
+            ranks: run.ranks.clone(),
+            tax_ids: run.tax_ids,
+            ref_name_rules: run.ref_name_rules.clone(),
+            ref_name_regex: run.ref_name_regex.clone(),
+        })
+    }
+}
+
+// builder for MoraConfig, every setting that is not set keeps its default
+#[derive(Clone, Debug, Default)]
+pub struct MoraConfigBuilder {
+    config: MoraConfig,
+}
+
+impl MoraConfigBuilder {
+    pub fn min_cnt(mut self, min_cnt: f32) -> MoraConfigBuilder {
+        self.config.min_cnt = min_cnt;
+        self
+    }
+
+    pub fn max_em_iterations(mut self, max_em_iterations: usize) -> MoraConfigBuilder {
+        self.config.max_em_iterations = max_em_iterations;
+        self
+    }
+
+    pub fn eps(mut self, eps: f32) -> MoraConfigBuilder {
+        self.config.eps = eps;
+        self
+    }
+
+    pub fn segment_size(mut self, segment_size: usize) -> MoraConfigBuilder {
+        self.config.segment_size = segment_size;
+        self
+    }
+
+    pub fn mapping_method(mut self, mapping_method: &str) -> MoraConfigBuilder {
+        self.config.mapping_method = mapping_method.to_string();
+        self
+    }
+
+    pub fn pair_mates(mut self, pair_mates: bool) -> MoraConfigBuilder {
+        self.config.pair_mates = pair_mates;
+        self
+    }
+
+    pub fn min_score_diff(mut self, min_score_diff: f32) -> MoraConfigBuilder {
+        self.config.min_score_diff = min_score_diff;
+        self
+    }
+
+    pub fn final_method(mut self, final_method: &str) -> MoraConfigBuilder {
+        self.config.final_method = final_method.to_string();
+        self
+    }
+
+    pub fn max_chain_depth(mut self, max_chain_depth: usize) -> MoraConfigBuilder {
+        self.config.max_chain_depth = max_chain_depth;
+        self
+    }
+
+    pub fn slack(mut self, slack: SlackModel) -> MoraConfigBuilder {
+        self.config.slack = slack;
+        self
+    }
+
+    pub fn reestimate_rounds(mut self, reestimate_rounds: usize) -> MoraConfigBuilder {
+        self.config.reestimate_rounds = reestimate_rounds;
+        self
+    }
+
+    pub fn build(self) -> MoraConfig {
+        self.config
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TestDir;
+
+    fn vars(vars: &[(&str, &str)]) -> HashMap<String, String> {
+        vars.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect()
+    }
+
+    #[test]
+    fn file_environment_and_builder_precedence() {
+        let dir = TestDir::new();
+        let config_filename = dir.path("precedence.yaml");
+        std::fs::write(&config_filename, "directories:\n  RESULTS: results\nparameters:\n  MIN_CNT: 0.5\n  SEGMENT_SIZE: 50\n  SLACK_MODEL: relative\n  TAXONOMY: False\n  THREADS: 4\n  FILES_EXT: .fq.gz\n").unwrap();
+        let environment = vars(&[("MORA_SEGMENT_SIZE", "20"), ("MORA_RANKS", "species,genus"), ("SEGMENT_SIZE", "10")]);
+        let (config, run) = MoraConfig::load_with_vars(Some(&config_filename), &environment).unwrap();
+
+        // defaults < config file < environment
+        assert_eq!((config.min_cnt, config.segment_size, config.max_em_iterations), (0.5, 20, 300));
+        assert_eq!((config.slack.get_model(), config.slack.get_value()), ("relative", 1.0));
+        assert_eq!((run.threads, run.taxonomy, run.ranks.as_deref()), (Some(4), None, Some("species,genus")));
+        // < builder (the options given on the command line)
+        let config = config.into_builder().min_cnt(0.9).pair_mates(true).build();
+        assert_eq!((config.min_cnt, config.segment_size, config.pair_mates), (0.9, 20, true));
+
+        // the written settings are read back as they were used
+        let written_filename = dir.path("written.toml");
+        let run = RunSettings { output: Some("out.txt".to_string()), taxonomy: Some("Taxonomy".to_string()), tax_ids: Some(true), ..run };
+        config.write_with_run_settings(&run, &written_filename).unwrap();
+        let (read, read_run) = ConfigFile::read(&written_filename).unwrap().apply(MoraConfig::default(), RunSettings::default()).unwrap();
+        assert_eq!((read.min_cnt, read.segment_size, read.pair_mates, read.slack.get_model()), (0.9, 20, true, "relative"));
+        assert_eq!((read_run.threads, read_run.output.as_deref(), read_run.taxonomy.as_deref(), read_run.tax_ids), (Some(4), Some("out.txt"), Some("Taxonomy"), Some(true)));
+    }
+
+    #[test]
+    fn invalid_environment_variables_are_named() {
+        match MoraConfig::load_with_vars(None, &vars(&[("MORA_MIN_CNT", "many")])) {
+            Err(MoraError::InvalidValue { name, value, .. }) => assert_eq!((name.as_str(), value.as_str()), ("MORA_MIN_CNT", "many")),
+            _ => panic!("MORA_MIN_CNT=many should be rejected")
+        }
+    }
+
+    #[test]
+    fn unknown_slack_model_is_rejected() {
+        let settings = ConfigFile { slack_model: Some("interval".to_string()), ..Default::default() };
+        let applied = settings.apply(MoraConfig::default(), RunSettings::default());
+        assert!(matches!(applied, Err(MoraError::InvalidValue { .. })));
+    }
+}
@@ -0,0 +1,95 @@
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::str::FromStr;
+use serde::Serialize;
+
+/*
+    The errors of a run, each names the file, line or record that caused it
+
+    Io: a file could not be opened, read or written
+    Alignment: the header or a record of a SAM/BAM file cannot be used
+    Parse: a line of a text file (sample sheet, abundances, assignments, taxonomy files, ...) cannot be parsed
+    InvalidValue: an option, a setting of a config file or an environment variable has a value that cannot be used
+    UnknownReference: a reference that is not in the references it is looked up in (known)
+    Taxonomy: the taxonomy is missing a TaxID or a node that is needed
+    Assignment: the reads of a query cannot be assigned
+*/
+#[derive(Debug)]
+pub enum MoraError {
+    Io { path: String, source: io::Error },
+    Alignment { path: String, record: String, message: String },
+    Parse { path: String, line: usize, message: String },
+    InvalidValue { name: String, value: String, message: String },
+    UnknownReference { location: String, reference: String, known: String },
+    Taxonomy { tax_id: String, message: String },
+    Assignment { query: String, message: String },
+}
+
+pub type Result<T> = std::result::Result<T, MoraError>;
+
+impl MoraError {
+    pub fn io(path: &str, source: io::Error) -> MoraError {
+        MoraError::Io { path: path.to_string(), source }
+    }
+
+    pub fn parse(path: &str, line: usize, message: impl Into<String>) -> MoraError {
+        MoraError::Parse { path: path.to_string(), line, message: message.into() }
+    }
+
+    pub fn invalid_value(name: &str, value: &str, message: impl Into<String>) -> MoraError {
+        MoraError::InvalidValue { name: name.to_string(), value: value.to_string(), message: message.into() }
+    }
+}
+
+// opens a file for reading, the error names the file
+pub(crate) fn open_file(path: &str) -> Result<File> {
+    File::open(path).map_err(|e| MoraError::io(path, e))
+}
+
+// creates a file for writing, the error names the file
+pub(crate) fn create_file(path: &str) -> Result<File> {
+    File::create(path).map_err(|e| MoraError::io(path, e))
+}
+
+// writes data into an output file, the error names the file
+pub(crate) fn write_to(output: &mut impl Write, path: &str, data: &[u8]) -> Result<()> {
+    output.write_all(data).map_err(|e| MoraError::io(path, e))
+}
+
+// writes a value as pretty printed JSON into an output file, the error names the file
+pub(crate) fn write_json(value: &impl Serialize, path: &str) -> Result<()> {
+    let mut output = BufWriter::new(create_file(path)?);
+    serde_json::to_writer_pretty(&mut output, value).map_err(|e| MoraError::io(path, io::Error::from(e)))?;
+    write_to(&mut output, path, b"\n")?;
+    output.flush().map_err(|e| MoraError::io(path, e))
+}
+
+// parses a column of a line of a text file, the error names the file, the line and the column
+pub(crate) fn parse_field<T: FromStr>(field: Option<&str>, path: &str, line: usize, name: &str) -> Result<T> {
+    let field = field.ok_or_else(|| MoraError::parse(path, line, format!("no {} column", name)))?;
+    field.trim().parse().map_err(|_| MoraError::parse(path, line, format!("{} is not valid: {}", name, field)))
+}
+
+impl fmt::Display for MoraError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MoraError::Io { path, source } => write!(f, "cannot access {}: {}", path, source),
+            MoraError::Alignment { path, record, message } => write!(f, "{} ({}): {}", path, record, message),
+            MoraError::Parse { path, line, message } => write!(f, "{} line {}: {}", path, line, message),
+            MoraError::InvalidValue { name, value, message } => write!(f, "invalid value '{}' for {}: {}", value, name, message),
+            MoraError::UnknownReference { location, reference, known } => write!(f, "{}: reference {} is not in {}", location, reference, known),
+            MoraError::Taxonomy { tax_id, message } => write!(f, "TaxID {}: {}", tax_id, message),
+            MoraError::Assignment { query, message } => write!(f, "cannot assign query {}: {}", query, message),
+        }
+    }
+}
+
+impl std::error::Error for MoraError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            MoraError::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
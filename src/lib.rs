@@ -0,0 +1,81 @@
+/*
+    MORA as a library: the quantification with Cedar, the assignment of the reads and the taxonomy
+
+    run() does the same as the mora binary without writing any files, the modules give access to the single steps
+    (loading the mappings, the EM, the assignment stages and the taxonomy outputs)
+*/
+use std::time::Instant;
+
+mod error;
+pub use error::{MoraError, Result};
+
+pub mod cedar;
+pub use cedar::{AbundanceRecord, Cedar, EquivalenceClassBuilder, ReferenceIndex};
+
+pub mod assignment;
+pub use assignment::{assign_mappings, AssignmentRecord, AssignmentStatus, SlackModel};
+
+mod config;
+pub use config::{MoraConfig, MoraConfigBuilder, RunSettings};
+
+pub mod summary;
+use summary::RunSummary;
+
+pub mod batch;
+
+pub mod report;
+
+#[cfg(test)]
+mod test_util;
+
+/*
+    The results of a run
+
+    abundances: the estimate of every reference in order of their ID
+    assignments: the final assignment of every query
+    summary: read counts, the EM, the assignment stages and the timings of the run
+*/
+pub struct MoraResults {
+    pub abundances: Vec<AbundanceRecord>,
+    pub assignments: Vec<AssignmentRecord>,
+    pub summary: RunSummary,
+}
+
+// estimates the abundances of the references of a SAM/BAM file and assigns its reads with the settings of config
+pub fn run(mapper_output_name: &str, config: &MoraConfig) -> Result<MoraResults> {
+    let mut cedar = Cedar::new();
+    cedar.run_parallel(mapper_output_name.to_string(), config, None)?;
+    let abundances = cedar.abundance_table();
+    let mut summary = cedar.get_summary();
+
+    let start = Instant::now();
+    let (assignments, assignment_summary) = assign_mappings(cedar, config)?;
+    summary.assignment = Some(assignment_summary);
+    summary.add_timing("assignment", start);
+    Ok(MoraResults { abundances, assignments, summary })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn run_estimates_and_assigns_the_sample() {
+        let results = run(concat!(env!("CARGO_MANIFEST_DIR"), "/sample/test.sam"), &MoraConfig::default()).unwrap();
+
+        let abundances: Vec<(&str, f32, f32)> = results.abundances.iter().map(|record| (record.name.as_str(), record.abundance, record.num_reads)).collect();
+        assert_eq!(abundances, vec![("CHROMOSOME_I", 0.4, 2.0), ("CHROMOSOME_II", 0.2, 1.0), ("CHROMOSOME_III", 0.0, 0.0), ("CHROMOSOME_IV", 0.2, 1.0),
+                                    ("CHROMOSOME_V", 0.2, 1.0)]);
+        assert!(results.abundances.iter().filter(|record| record.num_reads > 0.0).all(|record| record.valid));
+
+        let mut assignments: Vec<(&str, &str)> = results.assignments.iter().map(|record| (record.query.as_str(), record.get_name())).collect();
+        assignments.sort();
+        assert_eq!(assignments, vec![("I", "UNMAPPED"), ("II.14978392", "CHROMOSOME_II"), ("III", "CHROMOSOME_I"), ("IV", "CHROMOSOME_IV"),
+                                     ("V", "CHROMOSOME_I"), ("VI", "CHROMOSOME_V")]);
+        assert!(matches!(results.assignments.iter().find(|record| record.query == "I").unwrap().status, AssignmentStatus::Unmapped));
+
+        assert_eq!((results.summary.reads.total, results.summary.reads.unmapped), (6, 1));
+        let assignment = results.summary.assignment.unwrap();
+        assert_eq!((assignment.assigned, assignment.unmapped), (5, 1));
+    }
+}
@@ -1,120 +1,769 @@
 extern crate clap;
-use clap::{Arg, App};
+use clap::{Arg, App, ArgMatches};
+use std::collections::HashMap;
+use std::path::Path;
+use std::process;
+use std::str::FromStr;
+use std::time::Instant;
 
-mod cedar;
-use cedar::Cedar;
-
-mod assignment;
-use crate::assignment::{assign_mappings, write_output, write_output_with_taxonomy};
+use mora::{Cedar, MoraConfig, MoraError, Result, RunSettings};
+use mora::cedar::{read_abundances, CoverageFormat};
+use mora::batch::run_batch;
+use mora::assignment::{assign_mappings, read_output, records_to_output, write_output, write_output_with_taxonomy, SlackModel};
+use mora::assignment::get_taxonomy::{ProfileSource, ReferenceNames, TaxonomyOptions, TaxonomyReports, TaxonomySource, DEFAULT_NAME_RULES, DEFAULT_RANKS};
+use mora::assignment::get_taxonomy::taxonomy_db::TaxonomyDb;
+use mora::assignment::get_taxonomy::prepare::prepare;
+use mora::report::Report;
+use mora::summary::RunSummary;
 
 fn main() {
-    let commands = App::new("Mora").version("1.0").author("andrewf.zheng@mail.utoronto.ca")
-                        .about("Read Re-Assignment")
-                        .arg(Arg::with_name("SAM File")
-                            .short('s')
-                            .long("sam")
-                            .help("path for sam/bam file")
-                            .required(true)
-                            .takes_value(true)
-                            .display_order(1))
-                        .arg(Arg::with_name("Min Count")
-                            .short('c')
-                            .long("minCnt")
-                            .help("minimum count for a reference to be valid")
-                            .takes_value(true)
-                            .default_value("0.1")
-                            .display_order(3))
-                        .arg(Arg::with_name("Abund output")
-                            .short('a')
-                            .long("abund_out")
-                            .help("path for abundance output")
-                            .takes_value(true)
-                            .display_order(4))
-                        .arg(Arg::with_name("Max EM iterations")
-                            .long("max_em")
-                            .help("maximum allowed iterations of EM")
-                            .takes_value(true)
-                            .default_value("300")
-                            .display_order(3))
-                        .arg(Arg::with_name("segment size")
-                            .long("segment_size")
-                            .help("size to split referenes into")
-                            .takes_value(true)
-                            .default_value("100")
-                            .display_order(3))
-                        .arg(Arg::with_name("Min score diff")
-                            .long("min_score_diff")
-                            .help("minimum difference between mapping scores divided by best mapping score for second step")
-                            .takes_value(true)
-                            .default_value("0.5")
-                            .display_order(3))
-                        .arg(Arg::with_name("Final Method")
-                            .long("final_method")
-                            .help("Assignment method for left over reads, defualt none means leave unassigned otherwise anything not 'none' means assigned based on probability")
-                            .takes_value(true)
-                            .default_value("none")
-                            .display_order(3))
-                        .arg(Arg::with_name("Output")
-                            .short('o')
-                            .long("output")
-                            .help("path for final output of assignments")
-                            .required(true)
-                            .takes_value(true)
-                            .display_order(2))
-                        .arg(Arg::with_name("taxonomy")
-                            .long("tax")
-                            .help("write output with taxonomy details with provided tax directory")
-                            .takes_value(true)
-                            .display_order(4))
-                        .arg(Arg::with_name("Method")
-                            .short('m')
-                            .long("method")
-                            .help("mapping method: (pufferfish, bowtie2, minimap2)")
-                            .takes_value(true)
-                            .display_order(2)
-                            .default_value("pufferfish"))
-                        .arg(Arg::with_name("Threads")
-                            .short('t')
-                            .long("threads")
-                            .help("number of threads for rayon to use")
-                            .takes_value(true)
-                            .display_order(4)
-                            .default_value("3"))
-                        .get_matches();
-
-    // collect values from user inputs
-    let sam_file = commands.value_of("SAM File").unwrap().to_string();
-    let min_cnt: f32 = commands.value_of("Min Count").unwrap().parse().unwrap();
-    let max_iter: usize = commands.value_of("Max EM iterations").unwrap().parse().unwrap();
-    let segment_size: usize = commands.value_of("segment size").unwrap().parse().unwrap();
-    let method: String = commands.value_of("Method").unwrap().to_string();
-    let threads:usize = commands.value_of("Threads").unwrap().parse().unwrap();
-    
-    // setup number of threads
-    rayon::ThreadPoolBuilder::new().num_threads(threads).build_global().unwrap();
+    let commands = cli().get_matches();
+
+    if let Err(e) = run_command(&commands) {
+        eprintln!("Error: {}", e);
+        process::exit(1);
+    }
+}
+
+// the options and subcommands of the mora binary
+fn cli() -> App<'static> {
+    App::new("Mora").version("1.0").author("andrewf.zheng@mail.utoronto.ca")
+        .about("Read Re-Assignment")
+        .subcommand_negates_reqs(true)
+        .subcommand(App::new("quant")
+            .about("estimate the abundances of the references without assigning the reads")
+            .arg(sam_arg()
+                .required_unless_present("Load eq"))
+            .arg(load_eq_arg())
+            .args(settings_args())
+            .args(abundance_args())
+            .arg(summary_arg()))
+        .subcommand(App::new("assign")
+            .about("assign the reads with the abundances of an earlier run, without running the EM again")
+            .arg(sam_arg()
+                .required(true))
+            .arg(abundance_arg()
+                .help("abundance file written by quant with --abund_tsv or -a")
+                .required(true))
+            .arg(Arg::with_name("Output")
+                .short('o')
+                .long("output")
+                .help("path for final output of assignments, OUTPUT of --config if not given")
+                .takes_value(true)
+                .display_order(2))
+            .args(settings_args())
+            .args(taxonomy_args())
+            .arg(summary_arg()))
+        .subcommand(App::new("taxonomy")
+            .about("write an existing assignment file with taxonomy details, or prepare taxonomy files")
+            .args_conflicts_with_subcommands(true)
+            .subcommand_negates_reqs(true)
+            .arg(assignments_arg())
+            .arg(Arg::with_name("Output")
+                .short('o')
+                .long("output")
+                .help("path for the assignments with taxonomy details")
+                .required(true)
+                .takes_value(true)
+                .display_order(2))
+            .arg(abundance_arg()
+                .help("abundance file of the same run (--abund_tsv or -a), needed for the profiles of the estimated abundances"))
+            .args(taxonomy_args())
+            .mut_arg("taxonomy", |arg| arg.required(true))
+            .arg(summary_arg())
+            .subcommand(App::new("build")
+                .about("compile a taxonomy directory into a binary taxonomy database")
+                .arg(Arg::with_name("taxonomy")
+                    .long("tax")
+                    .help("tax directory with accessionsTaxIDs.tab, nodes.dmp, names.dmp and optionally merged.dmp and delnodes.dmp")
+                    .required(true)
+                    .takes_value(true))
+                .arg(Arg::with_name("Output")
+                    .short('o')
+                    .long("output")
+                    .help("path for the taxonomy database")
+                    .required(true)
+                    .takes_value(true)))
+            .subcommand(App::new("prepare")
+                .about("find the TaxIDs of the references in NCBI accession2taxid files")
+                .arg(Arg::with_name("References")
+                    .short('r')
+                    .long("references")
+                    .help("reference FASTA file (plain or gzipped) or SAM/BAM file with the references in its header")
+                    .required(true)
+                    .takes_value(true))
+                .arg(Arg::with_name("Accession2taxid")
+                    .short('a')
+                    .long("accession2taxid")
+                    .help("NCBI accession2taxid files (plain or gzipped)")
+                    .required(true)
+                    .takes_value(true)
+                    .multiple_values(true))
+                .arg(Arg::with_name("Output")
+                    .short('o')
+                    .long("output")
+                    .help("path for accessionsTaxIDs.tab, or for the taxonomy database with --tax")
+                    .required(true)
+                    .takes_value(true))
+                .arg(Arg::with_name("taxonomy")
+                    .long("tax")
+                    .help("tax directory with nodes.dmp, names.dmp and optionally merged.dmp and delnodes.dmp to write a taxonomy database")
+                    .takes_value(true))
+                .arg(Arg::with_name("Threads")
+                    .short('t')
+                    .long("threads")
+                    .help("number of threads for rayon to use")
+                    .takes_value(true)
+                    .default_value("3"))))
+        .subcommand(App::new("report")
+            .about("summarize the assignments and abundances of an earlier run")
+            .arg(assignments_arg())
+            .arg(abundance_arg()
+                .help("abundance file of the same run (--abund_tsv or -a), adds the abundances and the status of the references"))
+            .arg(Arg::with_name("Output")
+                .short('o')
+                .long("output")
+                .help("path for the JSON report")
+                .required(true)
+                .takes_value(true)
+                .display_order(2)))
+        .arg(sam_arg()
+            .required_unless_present_any(["Load eq", "Sample sheet"]))
+        .arg(load_eq_arg())
+        .arg(Arg::with_name("Sample sheet")
+            .long("sample_sheet")
+            .help("tsv file with a sample ID and a sam/bam file per line, all mapped against the same references. Every sample is run with the same settings and gets its abundances, assignments and summary in the output directory")
+            .takes_value(true)
+            .conflicts_with_all(&["SAM File", "Load eq"])
+            .display_order(1))
+        .arg(Arg::with_name("Joint")
+            .long("joint")
+            .help("decide the valid references of the samples in the same group of --sample_sheet (third column, samples without a group are estimated on their own) with one set cover over the union of their equivalence classes, every sample keeps its own EM abundances")
+            .requires("Sample sheet")
+            .conflicts_with_all(&["SAM File", "Load eq"])
+            .display_order(3))
+        .arg(Arg::with_name("Abundance matrix")
+            .long("abund_matrix")
+            .help("path for the abundances of all samples of --sample_sheet (references x samples), default: abundance_matrix.tsv in the output directory")
+            .takes_value(true)
+            .requires("Sample sheet")
+            // the sample sheet conflicts with them, so clap does not require it when they are given
+            .conflicts_with_all(&["SAM File", "Load eq"])
+            .display_order(4))
+        .arg(Arg::with_name("Output")
+            .short('o')
+            .long("output")
+            .help("path for final output of assignments (output directory with --sample_sheet), OUTPUT of --config if not given")
+            .takes_value(true)
+            .display_order(2))
+        .args(settings_args())
+        .args(abundance_args())
+        .args(taxonomy_args())
+        .arg(summary_arg())
+}
+
+// runs the subcommand, or estimates the abundances and assigns the reads in one run without one
+fn run_command(commands: &ArgMatches) -> Result<()> {
+    match commands.subcommand() {
+        Some(("quant", quant_commands)) => {
+            let (config, _) = load_config(quant_commands)?;
+            let (_, summary) = quantify(quant_commands, &config)?;
+            if let Some(summary_file) = quant_commands.value_of("Summary") {
+                summary.write(summary_file.to_string())?;
+            }
+        },
+        Some(("assign", assign_commands)) => {
+            let (config, run) = load_config(assign_commands)?;
+            let mut cedar = Cedar::new();
+            cedar.load_mappings(assign_commands.value_of("SAM File").unwrap().to_string(), &config, None)?;
+            cedar.set_abundances(&read_abundances(assign_commands.value_of("Abundance").unwrap())?)?;
+            let summary = cedar.get_summary();
+            assign_and_write(cedar, summary, assign_commands, &config, &run)?;
+        },
+        Some(("taxonomy", taxonomy_commands)) => taxonomy(taxonomy_commands)?,
+        Some(("report", report_commands)) => {
+            let assignments = read_output(report_commands.value_of("Assignments").unwrap())?;
+            let abundances = report_commands.value_of("Abundance").map(read_abundances).transpose()?;
+            Report::new(&assignments, abundances.as_ref()).write(report_commands.value_of("Output").unwrap().to_string())?;
+        },
+        _ => {
+            let (config, run) = load_config(commands)?;
+            if let Some(sample_sheet) = commands.value_of("Sample sheet") {
+                return run_batch(sample_sheet.to_string(), output_path(&run)?,
+                            commands.value_of("Abundance matrix").map(|path| path.to_string()), &config, commands.is_present("Joint"), taxonomy_settings(&run)?,
+                            &taxonomy_reports(commands, String::new()));
+            }
+            let (cedar, summary) = quantify(commands, &config)?;
+            if commands.is_present("Load eq") {
+                // the reads are not available, so only the abundances can be written
+                if let Some(summary_file) = commands.value_of("Summary") {
+                    summary.write(summary_file.to_string())?;
+                }
+                return Ok(());
+            }
+            assign_and_write(cedar, summary, commands, &config, &run)?;
+        }
+    }
+    Ok(())
+}
+
+// the value of a numerical option, the error names the option
+fn parse_value<T: FromStr>(option: &str, value: &str) -> Result<T> {
+    value.parse().map_err(|_| MoraError::invalid_value(option, value, "expected a number"))
+}
+
+// the value of a true/false option, the error names the option
+fn parse_flag(option: &str, value: &str) -> Result<bool> {
+    value.parse().map_err(|_| MoraError::invalid_value(option, value, "expected true or false"))
+}
+
+// sets up the number of threads rayon uses
+fn set_threads(threads: usize) -> Result<()> {
+    rayon::ThreadPoolBuilder::new().num_threads(threads).build_global()
+        .map_err(|e| MoraError::invalid_value("--threads", &threads.to_string(), e.to_string()))
+}
+
+// the value of an option given on the command line, none if it was not given (even with a default) or the command has no such option
+fn given<'a>(commands: &'a ArgMatches, name: &str) -> Option<&'a str> {
+    if commands.is_valid_arg(name) && commands.occurrences_of(name) > 0 { commands.value_of(name) } else { None }
+}
+
+// the value of an option given on the command line, otherwise the one of the config file, otherwise the default of the option
+fn option_value(commands: &ArgMatches, name: &str, configured: Option<String>) -> Option<String> {
+    given(commands, name).map(|value| value.to_string()).or(configured)
+        .or_else(|| if commands.is_valid_arg(name) { commands.value_of(name).map(|value| value.to_string()) } else { None })
+}
+
+/*
+    the settings of the binary besides MoraConfig (threads, output and taxonomy): the options given on the command line,
+    otherwise the ones of the config file and the environment (configured), otherwise the defaults of the options
+*/
+fn run_settings(commands: &ArgMatches, configured: RunSettings) -> Result<RunSettings> {
+    let threads = option_value(commands, "Threads", configured.threads.map(|threads| threads.to_string()));
+    Ok(RunSettings {
+        threads: threads.map(|threads| parse_value("--threads", &threads)).transpose()?,
+        output: option_value(commands, "Output", configured.output),
+        taxonomy: option_value(commands, "taxonomy", configured.taxonomy),
+        tax_format: option_value(commands, "taxonomy format", configured.tax_format),
+        ranks: option_value(commands, "ranks", configured.ranks),
+        tax_ids: given(commands, "tax ids").map(|tax_ids| parse_flag("--tax_ids", tax_ids)).transpose()?.or(configured.tax_ids),
+        ref_name_rules: option_value(commands, "Reference name rules", configured.ref_name_rules),
+        ref_name_regex: option_value(commands, "Reference name regex", configured.ref_name_regex),
+    })
+}
 
+// the path of the assignments (the output directory with a sample sheet): -o, otherwise OUTPUT of the config file
+fn output_path(run: &RunSettings) -> Result<String> {
+    run.output.clone().ok_or_else(|| MoraError::invalid_value("--output", "", "no path for the assignments, give -o or OUTPUT in the config file"))
+}
+
+/*
+    the settings of MoraConfig and of the binary: config file, environment and command line,
+    sets up the number of threads and writes the settings with --write_config
+*/
+fn load_config(commands: &ArgMatches) -> Result<(MoraConfig, RunSettings)> {
+    // collect values from user inputs: options given on the command line override the config file and the environment
+    let (loaded, configured) = MoraConfig::load_with_run_settings(commands.value_of("Config"))?;
+    let run = run_settings(commands, configured)?;
+    if let Some(threads) = run.threads {
+        set_threads(threads)?;
+    }
+    let slack = match given(commands, "Slack") {
+        Some(slack) => parse_value("--slack", slack)?,
+        None => loaded.get_slack().get_value()
+    };
+    let slack = SlackModel::new(given(commands, "Slack model").unwrap_or(loaded.get_slack().get_model()), slack)?;
+    let mut builder = loaded.into_builder().slack(slack);
+    if let Some(min_cnt) = given(commands, "Min Count") {
+        builder = builder.min_cnt(parse_value("--minCnt", min_cnt)?);
+    }
+    if let Some(max_iter) = given(commands, "Max EM iterations") {
+        builder = builder.max_em_iterations(parse_value("--max_em", max_iter)?);
+    }
+    if let Some(segment_size) = given(commands, "segment size") {
+        builder = builder.segment_size(parse_value("--segment_size", segment_size)?);
+    }
+    if let Some(method) = given(commands, "Method") {
+        builder = builder.mapping_method(method);
+    }
+    if let Some(pair_mates) = given(commands, "Mate consistent") {
+        builder = builder.pair_mates(parse_flag("--mate_consistent", pair_mates)?);
+    }
+    if let Some(score_diff) = given(commands, "Min score diff") {
+        builder = builder.min_score_diff(parse_value("--min_score_diff", score_diff)?);
+    }
+    if let Some(final_method) = given(commands, "Final Method") {
+        builder = builder.final_method(final_method);
+    }
+    if let Some(max_chain_depth) = given(commands, "Max chain depth") {
+        builder = builder.max_chain_depth(parse_value("--max_chain_depth", max_chain_depth)?);
+    }
+    if let Some(reestimate_rounds) = given(commands, "Re-estimation rounds") {
+        builder = builder.reestimate_rounds(parse_value("--reestimate_rounds", reestimate_rounds)?);
+    }
+    let config = builder.build();
+    if let Some(config_output) = commands.value_of("Write config") {
+        config.write_with_run_settings(&run, config_output)?;
+    }
+    Ok((config, run))
+}
+
+// runs the EM on the sam/bam file, or on the equivalence classes of --load_eq, and writes the abundance outputs
+fn quantify(commands: &ArgMatches, config: &MoraConfig) -> Result<(Cedar, RunSummary)> {
     let mut cedar = Cedar::new(); 
-    cedar.run_parallel(sam_file, max_iter, 0.001, min_cnt, segment_size, method);
+    if let Some(eq_file) = commands.value_of("Load eq") {
+        cedar.run_from_equivalence_classes(eq_file.to_string(), config)?;
+    } else {
+        cedar.run_parallel(commands.value_of("SAM File").unwrap().to_string(), config, None)?;
+    }
 
-    if commands.is_present("Abund output") {
-        cedar.serialize_simple(commands.value_of("Abund output").unwrap().to_string());
+    let start = Instant::now();
+    if let Some(eq_file) = commands.value_of("Dump eq") {
+        cedar.dump_equivalence_classes(eq_file.to_string())?;
+    }
+    if let Some(abund_output) = commands.value_of("Abund output") {
+        cedar.serialize_simple(abund_output.to_string())?;
+    }
+    if let Some(quant_sf) = commands.value_of("Quant sf") {
+        cedar.write_quant_sf(quant_sf.to_string())?;
+    }
+    if let Some(abund_tsv) = commands.value_of("Abund tsv") {
+        cedar.write_abundance_tsv(abund_tsv.to_string())?;
+    }
+    if let Some(coverage_output) = commands.value_of("Coverage output") {
+        let min_reads: usize = parse_value("--coverage_min_reads", commands.value_of("Coverage min reads").unwrap())?;
+        cedar.write_coverage(coverage_output.to_string(), CoverageFormat::new(commands.value_of("Coverage format").unwrap()), config.get_segment_size(), min_reads)?;
     }
+    let mut summary = cedar.get_summary();
+    summary.add_timing("abundance_outputs", start);
+    Ok((cedar, summary))
+}
 
-    let final_method: String = commands.value_of("Final Method").unwrap().to_string();
-    let score_diff: f32 = commands.value_of("Min score diff").unwrap().parse().unwrap();
-    let output_filename = commands.value_of("Output").unwrap();
+// assigns the reads of a cedar whose abundances are known and writes the assignments, with taxonomy details if --tax is given
+fn assign_and_write(cedar: Cedar, mut summary: RunSummary, commands: &ArgMatches, config: &MoraConfig, run: &RunSettings) -> Result<()> {
+    let sam_file = commands.value_of("SAM File").unwrap();
+    let sample_id = sample_id(sam_file);
+    let output_filename = output_path(run)?;
 
-    let output = assign_mappings(cedar, score_diff, final_method);
+    let reference_abundance = cedar.get_reference_abundances();
+    let start = Instant::now();
+    let (records, assignment_summary) = assign_mappings(cedar, config)?;
+    let output = records_to_output(&records);
+    summary.assignment = Some(assignment_summary);
+    summary.add_timing("assignment", start);
 
     println!("\nWriting results to {}", &output_filename);
-    if commands.is_present("taxonomy") {
-        let tax_dir = commands.value_of("taxonomy").unwrap().to_string();
-        println!("tax directory = {}", &tax_dir);
-        write_output_with_taxonomy(output_filename.to_string(), output, 
-                                        tax_dir.to_string() + "/accessionsTaxIDs.tab",
-                                        tax_dir.to_string() + "/nodes.dmp",
-                                        tax_dir.to_string() + "/names.dmp");
+    let start = Instant::now();
+    if let Some((source, options)) = taxonomy_settings(run)? {
+        let taxonomy_summary = write_output_with_taxonomy(output_filename.to_string(), output, reference_abundance, source, options, taxonomy_reports(commands, sample_id))?;
+        summary.taxonomy = Some(taxonomy_summary);
     } else {
-        write_output(output_filename.to_string(), output);
+        write_output(output_filename.to_string(), output)?;
+    }
+    summary.add_timing("output", start);
+
+    if let Some(summary_file) = commands.value_of("Summary") {
+        summary.write(summary_file.to_string())?;
+    }
+    Ok(())
+}
+
+// where the taxonomy of --tax is read from and how the lineages are written, none without --tax
+fn taxonomy_settings(run: &RunSettings) -> Result<Option<(TaxonomySource, TaxonomyOptions)>> {
+    let tax_dir = match &run.taxonomy {
+        Some(tax_dir) => tax_dir,
+        None => return Ok(None)
+    };
+    println!("tax directory = {}", tax_dir);
+    let reference_names = ReferenceNames::new(run.ref_name_rules.as_deref().unwrap_or(DEFAULT_NAME_RULES), run.ref_name_regex.as_deref())?;
+    Ok(Some((TaxonomySource::from_path(tax_dir, run.tax_format.as_deref().unwrap_or("ncbi"))?,
+        TaxonomyOptions::new(run.ranks.as_deref().unwrap_or(DEFAULT_RANKS), run.tax_ids.unwrap_or(false), reference_names))))
+}
+
+// the sample ID of the reports: the file name of the input without its extension
+fn sample_id(file: &str) -> String {
+    Path::new(file).file_stem().map_or_else(|| file.to_string(), |stem| stem.to_string_lossy().to_string())
+}
+
+fn taxonomy_reports(commands: &ArgMatches, sample_id: String) -> TaxonomyReports {
+    TaxonomyReports {
+        sample_id,
+        kraken_report: commands.value_of("Kraken report").map(|path| path.to_string()),
+        metaphlan_profile: commands.value_of("MetaPhlAn profile").map(|path| path.to_string()),
+        cami_profile: commands.value_of("CAMI profile").map(|path| path.to_string()),
+        cami_binning: commands.value_of("CAMI binning").map(|path| path.to_string()),
+        krona_text: commands.value_of("Krona text").map(|path| path.to_string()),
+        sunburst_html: commands.value_of("Sunburst html").map(|path| path.to_string()),
+        krona_source: ProfileSource::new(commands.value_of("Krona source").unwrap()),
+        rank_counts: commands.value_of("Rank counts").map(|path| path.to_string()),
+        rank_counts_source: ProfileSource::new(commands.value_of("Rank counts source").unwrap()),
+    }
+}
+
+// mora taxonomy: writes an existing assignment file with taxonomy details, or builds the taxonomy files with build and prepare
+fn taxonomy(commands: &ArgMatches) -> Result<()> {
+    match commands.subcommand() {
+        Some(("build", build_commands)) => {
+            TaxonomyDb::build(build_commands.value_of("taxonomy").unwrap().to_string(), build_commands.value_of("Output").unwrap().to_string())
+        },
+        Some(("prepare", prepare_commands)) => {
+            set_threads(parse_value("--threads", prepare_commands.value_of("Threads").unwrap())?)?;
+            prepare(prepare_commands.value_of("References").unwrap().to_string(),
+                    prepare_commands.values_of("Accession2taxid").unwrap().map(|file| file.to_string()).collect(),
+                    prepare_commands.value_of("Output").unwrap().to_string(),
+                    prepare_commands.value_of("taxonomy").map(|dir| dir.to_string()))
+        },
+        _ => {
+            let assignments_file = commands.value_of("Assignments").unwrap();
+            let sample_id = sample_id(assignments_file);
+            let output = read_output(assignments_file)?;
+            // <reference name, estimated abundance>, empty if no abundance file is given
+            let reference_abundance: HashMap<String, f32> = commands.value_of("Abundance").map(read_abundances).transpose()?.unwrap_or_default()
+                .into_iter().map(|(r_name, (abundance, _))| (r_name, abundance)).collect();
+
+            let start = Instant::now();
+            let (source, options) = taxonomy_settings(&run_settings(commands, RunSettings::default())?)?
+                .ok_or_else(|| MoraError::invalid_value("--tax", "", "the taxonomy is needed to write the taxonomy outputs"))?;
+            let taxonomy = write_output_with_taxonomy(commands.value_of("Output").unwrap().to_string(), output, reference_abundance,
+                                                        source, options, taxonomy_reports(commands, sample_id))?;
+            let mut summary = RunSummary { taxonomy: Some(taxonomy), ..Default::default() };
+            summary.add_timing("output", start);
+            if let Some(summary_file) = commands.value_of("Summary") {
+                summary.write(summary_file.to_string())?;
+            }
+            Ok(())
+        }
+    }
+}
+
+// path of a sam/bam file
+fn sam_arg() -> Arg<'static> {
+    Arg::with_name("SAM File")
+        .short('s')
+        .long("sam")
+        .help("path for sam/bam file")
+        .takes_value(true)
+        .display_order(1)
+}
+
+fn load_eq_arg() -> Arg<'static> {
+    Arg::with_name("Load eq")
+        .long("load_eq")
+        .alias("load-eq")
+        .help("rerun the EM on equivalence classes written by --dump_eq instead of a sam/bam file, only the abundances are written")
+        .takes_value(true)
+        .conflicts_with_all(&["SAM File", "Coverage output"])
+        .display_order(1)
+}
+
+fn summary_arg() -> Arg<'static> {
+    Arg::with_name("Summary")
+        .long("summary")
+        .help("path for a JSON summary of the run: read counts, EM, assignment stages, missing accessions and timings")
+        .takes_value(true)
+        .display_order(4)
+}
+
+// the assignments written by an earlier run
+fn assignments_arg() -> Arg<'static> {
+    Arg::with_name("Assignments")
+        .short('i')
+        .long("input")
+        .help("assignment file of an earlier run")
+        .required(true)
+        .takes_value(true)
+        .display_order(1)
+}
+
+// the abundances written by an earlier run
+fn abundance_arg() -> Arg<'static> {
+    Arg::with_name("Abundance")
+        .long("abundance")
+        .takes_value(true)
+        .display_order(1)
+}
+
+// the settings of MoraConfig and the number of threads
+fn settings_args() -> Vec<Arg<'static>> {
+    vec![
+        Arg::with_name("Config")
+            .long("config")
+            .help("TOML or YAML file with the settings (e.g. the Snakemake config.yaml), overridden by MORA_<KEY> environment variables and by the options given here")
+            .takes_value(true)
+            .display_order(2),
+        Arg::with_name("Write config")
+            .long("write_config")
+            .help("path to write the settings used in this run (with the threads, the output and the taxonomy settings) as a config file for --config, YAML for .yaml/.yml and TOML otherwise")
+            .takes_value(true)
+            .display_order(4),
+        Arg::with_name("Min Count")
+            .short('c')
+            .long("minCnt")
+            .help("minimum count for a reference to be valid")
+            .takes_value(true)
+            .default_value("0.1")
+            .display_order(3),
+        Arg::with_name("Max EM iterations")
+            .long("max_em")
+            .help("maximum allowed iterations of EM")
+            .takes_value(true)
+            .default_value("300")
+            .display_order(3),
+        Arg::with_name("segment size")
+            .long("segment_size")
+            .help("size to split referenes into")
+            .takes_value(true)
+            .default_value("100")
+            .display_order(3),
+        Arg::with_name("Method")
+            .short('m')
+            .long("method")
+            .help("mapping method: (pufferfish, bowtie2, minimap2)")
+            .takes_value(true)
+            .display_order(2)
+            .default_value("pufferfish"),
+        Arg::with_name("Mate consistent")
+            .long("mate_consistent")
+            .help("keep both mates of a paired fragment together and assign them to the same reference, --mate_consistent=false overrides PAIR_MATES of --config")
+            .takes_value(true)
+            .require_equals(true)
+            .min_values(0)
+            .default_missing_value("true")
+            .possible_values(["true", "false"])
+            .display_order(3),
+        Arg::with_name("Min score diff")
+            .long("min_score_diff")
+            .help("minimum difference between mapping scores divided by best mapping score for second step")
+            .takes_value(true)
+            .default_value("0.5")
+            .display_order(3),
+        Arg::with_name("Final Method")
+            .long("final_method")
+            .help("Assignment method for left over reads, defualt none means leave unassigned otherwise anything not 'none' means assigned based on probability")
+            .takes_value(true)
+            .default_value("none")
+            .display_order(3),
+        Arg::with_name("Max chain depth")
+            .long("max_chain_depth")
+            .help("maximum number of incumbent reads moved in a chain to make room for a left over read")
+            .takes_value(true)
+            .default_value("1")
+            .display_order(3),
+        Arg::with_name("Slack model")
+            .long("slack_model")
+            .help("how many reads a reference can hold above its abundance: absolute (reads), relative (fraction of expected reads) or binomial (binomial standard deviations of the expected read count)")
+            .takes_value(true)
+            .possible_values(["absolute", "relative", "binomial"])
+            .default_value("absolute")
+            .display_order(3),
+        Arg::with_name("Slack")
+            .long("slack")
+            .help("value used by the slack model")
+            .takes_value(true)
+            .default_value("1")
+            .display_order(3),
+        Arg::with_name("Re-estimation rounds")
+            .long("reestimate_rounds")
+            .help("maximum number of rounds of re-estimating the abundances from the assignment and re-assigning the reads")
+            .takes_value(true)
+            .default_value("0")
+            .display_order(3),
+        Arg::with_name("Threads")
+            .short('t')
+            .long("threads")
+            .help("number of threads for rayon to use")
+            .takes_value(true)
+            .display_order(4)
+            .default_value("3"),
+    ]
+}
+
+// the abundance outputs written after the EM
+fn abundance_args() -> Vec<Arg<'static>> {
+    vec![
+        Arg::with_name("Abund output")
+            .short('a')
+            .long("abund_out")
+            .help("path for abundance output")
+            .takes_value(true)
+            .display_order(4),
+        Arg::with_name("Quant sf")
+            .long("quant_sf")
+            .help("path for abundance output in salmon's quant.sf format")
+            .takes_value(true)
+            .display_order(4),
+        Arg::with_name("Abund tsv")
+            .long("abund_tsv")
+            .help("path for abundance output with the quant.sf columns, coverage breadth, unique reads and valid/removed status")
+            .takes_value(true)
+            .display_order(4),
+        Arg::with_name("Coverage output")
+            .long("coverage_out")
+            .help("path for the per-bin coverage profiles of the references")
+            .takes_value(true)
+            .display_order(4),
+        Arg::with_name("Coverage format")
+            .long("coverage_format")
+            .help("format of the coverage profiles: bedgraph (one line per bin) or tsv (one line per reference)")
+            .takes_value(true)
+            .possible_values(["bedgraph", "tsv"])
+            .default_value("bedgraph")
+            .display_order(4),
+        Arg::with_name("Coverage min reads")
+            .long("coverage_min_reads")
+            .help("minimum number of reads for the coverage profile of a reference to be written")
+            .takes_value(true)
+            .default_value("1")
+            .display_order(4),
+        Arg::with_name("Dump eq")
+            .long("dump_eq")
+            .alias("dump-eq")
+            .help("path for the equivalence classes in salmon's eq_classes format, the reference table is written to <path>.refs.tsv")
+            .takes_value(true)
+            .display_order(4),
+    ]
+}
+
+// --tax and the outputs written with the taxonomy
+fn taxonomy_args() -> Vec<Arg<'static>> {
+    vec![
+        Arg::with_name("taxonomy")
+            .long("tax")
+            .help("write output with taxonomy details with provided tax directory or taxonomy database")
+            .takes_value(true)
+            .display_order(4),
+        Arg::with_name("taxonomy format")
+            .long("tax_format")
+            .help("format of the taxonomy given to --tax: ncbi (dump files or taxonomy database) or gtdb (GTDB taxonomy tsv files)")
+            .takes_value(true)
+            .possible_values(["ncbi", "gtdb"])
+            .default_value("ncbi")
+            .display_order(4),
+        Arg::with_name("ranks")
+            .long("ranks")
+            .help("comma separated ranks written with --tax, e.g. strain,species,genus,family,order,class,phylum,domain,realm")
+            .takes_value(true)
+            .default_value(DEFAULT_RANKS)
+            .display_order(4),
+        Arg::with_name("tax ids")
+            .long("tax_ids")
+            .help("write the TaxID of the assigned reference with --tax, --tax_ids=false overrides TAX_IDS of --config")
+            .takes_value(true)
+            .require_equals(true)
+            .min_values(0)
+            .default_missing_value("true")
+            .possible_values(["true", "false"])
+            .display_order(4),
+        Arg::with_name("Reference name rules")
+            .long("ref_name_rules")
+            .help("comma separated rules tried in order to match reference names to accessions with --tax: verbatim, first_token, strip_version, regex, kraken_taxid (NCBI TaxIDs, not used with --tax_format gtdb)")
+            .takes_value(true)
+            .default_value(DEFAULT_NAME_RULES)
+            .display_order(4),
+        Arg::with_name("Reference name regex")
+            .long("ref_name_regex")
+            .help("regular expression of the regex rule, the first capture group is the accession")
+            .takes_value(true)
+            .display_order(4),
+        Arg::with_name("Kraken report")
+            .long("kraken_report")
+            .help("path for a Kraken2-style report of the assigned reads rolled up the taxonomy")
+            .takes_value(true)
+            .requires("taxonomy")
+            .display_order(4),
+        Arg::with_name("MetaPhlAn profile")
+            .long("metaphlan_profile")
+            .help("path for a MetaPhlAn-style profile of the estimated abundances rolled up the taxonomy")
+            .takes_value(true)
+            .requires("taxonomy")
+            .display_order(4),
+        Arg::with_name("CAMI profile")
+            .long("cami_profile")
+            .help("path for a CAMI taxonomic profile of the estimated abundances (for OPAL)")
+            .takes_value(true)
+            .requires("taxonomy")
+            .display_order(4),
+        Arg::with_name("CAMI binning")
+            .long("cami_binning")
+            .help("path for a CAMI binning of the assigned reads (for AMBER)")
+            .takes_value(true)
+            .requires("taxonomy")
+            .display_order(4),
+        Arg::with_name("Krona text")
+            .long("krona_text")
+            .help("path for the Krona text input (value followed by the lineage)")
+            .takes_value(true)
+            .requires("taxonomy")
+            .display_order(4),
+        Arg::with_name("Sunburst html")
+            .long("sunburst_html")
+            .help("path for a self-contained HTML page with a static sunburst chart (SVG, not Krona)")
+            .takes_value(true)
+            .requires("taxonomy")
+            .display_order(4),
+        Arg::with_name("Krona source")
+            .long("krona_source")
+            .help("values shown by --krona_text and --sunburst_html: reads (per-read lineages) or abundance (estimated abundances)")
+            .takes_value(true)
+            .possible_values(["reads", "abundance"])
+            .default_value("reads")
+            .display_order(4),
+        Arg::with_name("Rank counts")
+            .long("rank_counts")
+            .help("path for a table of the reads counted per taxon at every rank of --ranks")
+            .takes_value(true)
+            .requires("taxonomy")
+            .display_order(4),
+        Arg::with_name("Rank counts source")
+            .long("rank_counts_source")
+            .help("what the rank counts are computed from: reads (per-read lineages) or abundance (estimated abundances)")
+            .takes_value(true)
+            .possible_values(["reads", "abundance"])
+            .default_value("reads")
+            .display_order(4),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> clap::Result<ArgMatches> {
+        cli().try_get_matches_from(std::iter::once("mora").chain(args.iter().copied()))
+    }
+
+    #[test]
+    fn subcommands_and_their_options() {
+        let commands = parse(&["quant", "-s", "reads.sam", "--abund_tsv", "abundance.tsv", "--minCnt", "0.5"]).unwrap();
+        let (name, quant) = commands.subcommand().unwrap();
+        assert_eq!((name, quant.value_of("SAM File"), quant.value_of("Abund tsv")), ("quant", Some("reads.sam"), Some("abundance.tsv")));
+        assert_eq!((given(quant, "Min Count"), given(quant, "Max EM iterations"), quant.value_of("Max EM iterations")), (Some("0.5"), None, Some("300")));
+
+        let commands = parse(&["taxonomy", "build", "--tax", "tax", "-o", "taxonomy.db"]).unwrap();
+        let (_, taxonomy) = commands.subcommand().unwrap();
+        assert_eq!(taxonomy.subcommand_matches("build").unwrap().value_of("Output"), Some("taxonomy.db"));
+
+        let commands = parse(&["--sample_sheet", "samples.tsv", "--joint", "-o", "out"]).unwrap();
+        assert!(commands.subcommand().is_none());
+        assert!(commands.is_present("Joint"));
+    }
+
+    #[test]
+    fn missing_and_conflicting_options_are_rejected() {
+        for args in [vec!["assign", "-s", "reads.sam"], vec!["taxonomy", "-i", "assignments.txt", "-o", "out.txt"], vec!["report", "-o", "report.json"],
+                     vec!["-s", "reads.sam", "--joint"], vec!["-s", "reads.sam", "--load_eq", "eq.txt"], vec!["-s", "reads.sam", "--kraken_report", "kraken.txt"],
+                     vec!["-s", "reads.sam", "--slack_model", "interval"]] {
+            assert!(parse(&args).is_err(), "{:?}", args);
+        }
+    }
+
+    #[test]
+    fn run_settings_of_the_command_line() {
+        let commands = parse(&["-s", "reads.sam", "--ranks", "species,genus", "--tax_ids"]).unwrap();
+        let configured = RunSettings { output: Some("configured.txt".to_string()), ranks: Some("genus".to_string()), tax_ids: Some(false), ..Default::default() };
+        let run = run_settings(&commands, configured).unwrap();
+        // given options override the config file, the defaults of the options do not
+        assert_eq!((run.output.as_deref(), run.ranks.as_deref(), run.tax_ids, run.threads), (Some("configured.txt"), Some("species,genus"), Some(true), Some(3)));
+        assert_eq!(sample_id("data/sample.sorted.bam"), "sample.sorted");
     }
 }
@@ -0,0 +1,101 @@
+use std::collections::HashMap;
+use serde::Serialize;
+
+use crate::assignment::AssignmentStatus;
+use crate::error::{write_json, Result};
+
+/*
+    Summary of the outputs of an earlier run, written as JSON by mora report
+
+    reads: the queries of the assignment file by their final status
+    references_with_reads: number of references with at least one assigned read
+    valid_references: number of references kept by the set cover, none without an abundance file
+    references: the references with assigned reads and, with an abundance file, the valid references, by decreasing number of reads
+*/
+#[derive(Serialize, Default)]
+pub struct Report {
+    pub reads: ReportReads,
+    pub references_with_reads: usize,
+    pub valid_references: Option<usize>,
+    pub references: Vec<ReferenceReport>,
+}
+
+#[derive(Serialize, Default)]
+pub struct ReportReads {
+    pub total: usize,
+    pub assigned: usize,
+    pub unmapped: usize,
+    pub unassigned: usize,
+    pub reference_filtered: usize,
+    pub discordant: usize,
+}
+
+// assigned_fraction: fraction of the assigned reads, abundance and valid: from the abundance file if one was given
+#[derive(Serialize)]
+pub struct ReferenceReport {
+    pub name: String,
+    pub assigned_reads: usize,
+    pub assigned_fraction: f32,
+    pub abundance: Option<f32>,
+    pub valid: Option<bool>,
+}
+
+impl Report {
+    /*
+        assignments: <query name, reference name or assignment status> as read by read_output
+        abundances: <reference name, (abundance, valid)> as read by read_abundances
+    */
+    pub fn new(assignments: &HashMap<String, String>, abundances: Option<&HashMap<String, (f32, bool)>>) -> Report {
+        let mut report = Report::default();
+        let mut assigned_reads: HashMap<&str, usize> = HashMap::new();
+        for r_name in assignments.values() {
+            report.reads.total += 1;
+            match AssignmentStatus::from_label(r_name) {
+                Some(AssignmentStatus::Unmapped) => report.reads.unmapped += 1,
+                Some(AssignmentStatus::Unassigned) => report.reads.unassigned += 1,
+                Some(AssignmentStatus::ReferenceFiltered) => report.reads.reference_filtered += 1,
+                Some(AssignmentStatus::Discordant) => report.reads.discordant += 1,
+                _ => {
+                    report.reads.assigned += 1;
+                    *assigned_reads.entry(r_name).or_insert(0) += 1;
+                }
+            }
+        }
+        report.references_with_reads = assigned_reads.len();
+
+        if let Some(abundances) = abundances {
+            report.valid_references = Some(abundances.values().filter(|(_, valid)| *valid).count());
+            for (r_name, (_, valid)) in abundances {
+                if *valid {
+                    assigned_reads.entry(r_name).or_insert(0);
+                }
+            }
+        }
+        for (r_name, reads) in assigned_reads {
+            let estimate = abundances.and_then(|abundances| abundances.get(r_name));
+            report.references.push(ReferenceReport {
+                name: r_name.to_string(),
+                assigned_reads: reads,
+                assigned_fraction: if report.reads.assigned > 0 { reads as f32 / report.reads.assigned as f32 } else { 0.0 },
+                abundance: estimate.map(|(abundance, _)| *abundance),
+                valid: estimate.map(|(_, valid)| *valid),
+            });
+        }
+        report.references.sort_by(|a, b| b.assigned_reads.cmp(&a.assigned_reads).then_with(|| a.name.cmp(&b.name)));
+
+        println!("Report summary:");
+        println!("# of reads: {}", report.reads.total);
+        println!("# of assigned reads: {}", report.reads.assigned);
+        println!("# of unmapped reads: {}", report.reads.unmapped);
+        println!("# of unassigned reads: {}", report.reads.unassigned);
+        println!("# of reference filtered reads: {}", report.reads.reference_filtered);
+        println!("# of discordant fragments: {}", report.reads.discordant);
+        println!("# of references with assigned reads: {}", report.references_with_reads);
+        report
+    }
+
+    pub fn write(&self, output_filename: String) -> Result<()> {
+        println!("Writing report into the file: {}", &output_filename);
+        write_json(self, &output_filename)
+    }
+}
@@ -0,0 +1,134 @@
+use std::time::Instant;
+use serde::Serialize;
+
+use crate::error::{write_json, Result};
+
+/*
+    Machine-readable summary of a run, written as JSON with --summary
+
+    reads: read counts of the mapping file
+    em: the equivalence classes and the EM with the set cover
+    assignment: the number of reads handled by each assignment stage, none if the reads were not assigned
+    taxonomy: the accessions without a lineage, none if no taxonomy was given
+    timings: wall clock time of each phase in the order they ran
+*/
+#[derive(Serialize, Clone, Default)]
+pub struct RunSummary {
+    pub reads: ReadSummary,
+    pub em: EmSummary,
+    pub assignment: Option<AssignmentSummary>,
+    pub taxonomy: Option<TaxonomySummary>,
+    pub timings: Vec<PhaseTiming>,
+}
+
+#[derive(Serialize, Clone, Default)]
+pub struct ReadSummary {
+    pub total: usize,
+    pub multi_mapped: usize,
+    pub unmapped: usize,
+}
+
+/*
+    references: number of references in the header of the mapping file
+    valid_references_before_set_cover: references with at least one mapped read when the EM starts
+    valid_references_after_set_cover: references that are still valid when the EM stops
+    joint_set_cover: if the valid references were decided by the set cover over the samples of a joint batch
+*/
+#[derive(Serialize, Clone, Default)]
+pub struct EmSummary {
+    pub equivalence_classes: usize,
+    pub iterations: usize,
+    pub converged: bool,
+    pub references: usize,
+    pub valid_references_before_set_cover: usize,
+    pub valid_references_after_set_cover: usize,
+    pub joint_set_cover: bool,
+}
+
+/*
+    reads placed by each stage of the final assignment:
+    unique: reads mapping to a single reference
+    secondary: reads whose best mapping is a lot better than the others
+    abundance: reads placed in order of their mapping scores while their reference has space
+    moved: left over reads placed by moving other reads with ejection chains
+    leftover: reads that could not be placed, assigned randomly or left unassigned depending on --method
+
+    followed by the final status of the reads and the number of re-estimation rounds that were run
+*/
+#[derive(Serialize, Clone, Default)]
+pub struct AssignmentSummary {
+    pub unique: usize,
+    pub secondary: usize,
+    pub abundance: usize,
+    pub moved: usize,
+    pub leftover: usize,
+    pub assigned: usize,
+    pub unassigned: usize,
+    pub unmapped: usize,
+    pub reference_filtered: usize,
+    pub discordant: usize,
+    pub reestimation_rounds: usize,
+}
+
+// accessions: number of references looked up, missing_accessions: references without a TaxID or lineage
+#[derive(Serialize, Clone, Default)]
+pub struct TaxonomySummary {
+    pub accessions: usize,
+    pub missing_accessions: Vec<String>,
+}
+
+#[derive(Serialize, Clone)]
+pub struct PhaseTiming {
+    pub phase: String,
+    pub seconds: f64,
+}
+
+impl RunSummary {
+    // records the time since start for a phase
+    pub fn add_timing(&mut self, phase: &str, start: Instant) {
+        self.timings.push(PhaseTiming { phase: phase.to_string(), seconds: start.elapsed().as_secs_f64() });
+    }
+
+    pub fn write(&self, output_filename: String) -> Result<()> {
+        println!("Writing run summary into the file: {}", &output_filename);
+        write_json(self, &output_filename)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TestDir;
+
+    #[test]
+    fn summary_json_has_the_stage_counts_and_missing_accessions() {
+        let summary = RunSummary {
+            assignment: Some(AssignmentSummary { unique: 5, secondary: 2, abundance: 3, moved: 1, leftover: 1, assigned: 11, unmapped: 4, reestimation_rounds: 2, ..Default::default() }),
+            taxonomy: Some(TaxonomySummary { accessions: 3, missing_accessions: vec!["NC_000001.1".to_string()] }),
+            ..Default::default()
+        };
+        let dir = TestDir::new();
+        let output = dir.path("summary.json");
+        summary.write(output.clone()).unwrap();
+
+        let json: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(&output).unwrap()).unwrap();
+        let assignment = &json["assignment"];
+        for (stage, reads) in [("unique", 5), ("secondary", 2), ("abundance", 3), ("moved", 1), ("leftover", 1), ("assigned", 11), ("unassigned", 0),
+                               ("unmapped", 4), ("reestimation_rounds", 2)] {
+            assert_eq!(assignment[stage], reads, "{}", stage);
+        }
+        assert_eq!(json["taxonomy"]["accessions"], 3);
+        assert_eq!(json["taxonomy"]["missing_accessions"], serde_json::json!(["NC_000001.1"]));
+    }
+
+    #[test]
+    fn summary_json_without_assignment_or_taxonomy() {
+        let dir = TestDir::new();
+        let output = dir.path("summary.json");
+        RunSummary::default().write(output.clone()).unwrap();
+
+        let json: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(&output).unwrap()).unwrap();
+        assert!(json["assignment"].is_null());
+        assert!(json["taxonomy"].is_null());
+    }
+}
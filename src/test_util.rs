@@ -0,0 +1,22 @@
+// helpers shared by the tests of the modules
+use tempfile::TempDir;
+
+/*
+    A directory for the files a test writes, removed with its files when it is dropped, also when the test fails
+ */
+pub(crate) struct TestDir(TempDir);
+
+impl TestDir {
+    pub(crate) fn new() -> TestDir {
+        TestDir(tempfile::Builder::new().prefix("mora_").tempdir().unwrap())
+    }
+
+    pub(crate) fn get_path(&self) -> String {
+        self.0.path().to_string_lossy().to_string()
+    }
+
+    // path of a file in the directory, as the readers and writers take it
+    pub(crate) fn path(&self, name: &str) -> String {
+        self.0.path().join(name).to_string_lossy().to_string()
+    }
+}
@@ -0,0 +1,63 @@
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+// runs the mora binary in dir and checks that it succeeds
+fn mora(dir: &Path, args: &[&str]) {
+    let output = Command::new(env!("CARGO_BIN_EXE_mora")).current_dir(dir).args(args).output().unwrap();
+    assert!(output.status.success(), "mora {:?}: {}", args, String::from_utf8_lossy(&output.stderr));
+}
+
+fn sorted_lines(path: &Path) -> Vec<String> {
+    let mut lines: Vec<String> = fs::read_to_string(path).unwrap().lines().map(|line| line.to_string()).collect();
+    lines.sort();
+    lines
+}
+
+// C. elegans with the chromosomes of sample/test.sam as its accessions
+fn write_taxonomy(dir: &Path) {
+    let tax_dir = dir.join("tax");
+    fs::create_dir(&tax_dir).unwrap();
+    fs::write(tax_dir.join("nodes.dmp"), "1\t|\t1\t|\tno rank\t|\n2759\t|\t1\t|\tsuperkingdom\t|\n6239\t|\t2759\t|\tspecies\t|\n").unwrap();
+    fs::write(tax_dir.join("names.dmp"), "1\t|\troot\t|\t\t|\tscientific name\t|\n2759\t|\tEukaryota\t|\t\t|\tscientific name\t|\n\
+                                          6239\t|\tCaenorhabditis elegans\t|\t\t|\tscientific name\t|\n").unwrap();
+    let accessions: String = ["I", "II", "III", "IV", "V"].iter().map(|chromosome| format!("CHROMOSOME_{}\t6239\n", chromosome)).collect();
+    fs::write(tax_dir.join("accessionsTaxIDs.tab"), accessions).unwrap();
+}
+
+#[test]
+fn quant_then_assign_matches_a_single_run() {
+    let dir = tempfile::Builder::new().prefix("mora_").tempdir().unwrap();
+    let sam = Path::new(env!("CARGO_MANIFEST_DIR")).join("sample/test.sam");
+    let sam = sam.to_str().unwrap();
+
+    mora(dir.path(), &["-s", sam, "-o", "single_run.txt"]);
+    mora(dir.path(), &["quant", "-s", sam, "--abund_tsv", "abundance.tsv"]);
+    mora(dir.path(), &["assign", "-s", sam, "--abundance", "abundance.tsv", "-o", "assigned.txt"]);
+    assert_eq!(sorted_lines(&dir.path().join("assigned.txt")), sorted_lines(&dir.path().join("single_run.txt")));
+
+    // the abundances of the quant run are read back by report
+    mora(dir.path(), &["report", "-i", "assigned.txt", "--abundance", "abundance.tsv", "-o", "report.json"]);
+    let report: serde_json::Value = serde_json::from_str(&fs::read_to_string(dir.path().join("report.json")).unwrap()).unwrap();
+    assert_eq!((report["reads"]["total"].as_u64(), report["reads"]["assigned"].as_u64(), report["reads"]["unmapped"].as_u64()), (Some(6), Some(5), Some(1)));
+    assert_eq!(report["references"][0]["name"], "CHROMOSOME_I");
+    assert_eq!(report["references"][0]["assigned_reads"], 2);
+}
+
+#[test]
+fn taxonomy_of_existing_assignments() {
+    let dir = tempfile::Builder::new().prefix("mora_").tempdir().unwrap();
+    let sam = Path::new(env!("CARGO_MANIFEST_DIR")).join("sample/test.sam");
+    write_taxonomy(dir.path());
+
+    mora(dir.path(), &["-s", sam.to_str().unwrap(), "-o", "assignments.txt"]);
+    mora(dir.path(), &["taxonomy", "-i", "assignments.txt", "-o", "taxonomy.txt", "--tax", "tax", "--ranks", "species,superkingdom",
+                       "--kraken_report", "kraken.txt", "--summary", "summary.json"]);
+
+    let assigned = fs::read_to_string(dir.path().join("taxonomy.txt")).unwrap();
+    assert_eq!(assigned.lines().filter(|line| line.contains("Caenorhabditis elegans") && line.contains("Eukaryota")).count(), 5);
+    let kraken = fs::read_to_string(dir.path().join("kraken.txt")).unwrap();
+    assert!(kraken.lines().any(|line| line.ends_with("Caenorhabditis elegans") && line.contains("\tS\t6239\t")), "{}", kraken);
+    let summary: serde_json::Value = serde_json::from_str(&fs::read_to_string(dir.path().join("summary.json")).unwrap()).unwrap();
+    assert_eq!(summary["taxonomy"]["missing_accessions"], serde_json::json!([]));
+}